@@ -0,0 +1,307 @@
+//! Backend built on FTDI's proprietary D2XX driver.
+
+use crate::{BitMode, DeviceInfo, DeviceType, Error, VCP};
+use ftdi_vcp_sys::{
+    get_device_info_list, FT_Close, FT_GetBitMode, FT_GetComPortNumber, FT_GetLatencyTimer,
+    FT_Open, FT_OpenEx, FT_Purge, FT_Read, FT_ResetDevice, FT_SetBitMode, FT_SetLatencyTimer,
+    FT_Write, DWORD, FT_DEVICE_LIST_INFO_NODE, FT_FLAGS_HISPEED, FT_FLAGS_OPENED, FT_HANDLE,
+    FT_OPEN_BY_DESCRIPTION, FT_OPEN_BY_LOCATION, FT_OPEN_BY_SERIAL_NUMBER, FT_STATUS, LONG,
+    LPDWORD, LPVOID, PVOID, UCHAR,
+};
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::io::{Read, Write};
+use std::mem::MaybeUninit;
+
+pub type Handle = FT_HANDLE;
+
+impl From<FT_STATUS> for Error {
+    fn from(src: FT_STATUS) -> Self {
+        match src {
+            0 => Error::NoError,
+            1 => Error::InvalidHandle,
+            2 => Error::DeviceNotFound,
+            3 => Error::DeviceNotOpened,
+            4 => Error::IoError,
+            5 => Error::InsufficientResources,
+            6 => Error::InvalidParameter,
+            7 => Error::InvalidBaudRate,
+            8 => Error::DeviceNotOpenedForErase,
+            9 => Error::DeviceNotOpenedForWrite,
+            10 => Error::FailedToWriteDevice,
+            11 => Error::EepromReadFailed,
+            12 => Error::EepromWriteFailed,
+            13 => Error::EepromEraseFailed,
+            14 => Error::EepromNotPresent,
+            15 => Error::EepromNotProgrammed,
+            16 => Error::InvalidArgs,
+            17 => Error::NotSupported,
+            18 => Error::OtherError,
+            19 => Error::DeviceListNotReady,
+            x => Error::UnknownError(x),
+        }
+    }
+}
+
+/// Convert a fixed-size, NUL-terminated C string buffer.
+fn c_string(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+fn device_info(node: &FT_DEVICE_LIST_INFO_NODE) -> DeviceInfo {
+    DeviceInfo {
+        device_type: DeviceType::from(node.Type as u32),
+        vid: (node.ID >> 16) as u16,
+        pid: node.ID as u16,
+        location: node.LocId as u32,
+        serial_number: c_string(&node.SerialNumber),
+        description: c_string(&node.Description),
+        opened: node.Flags & FT_FLAGS_OPENED != 0,
+        high_speed: node.Flags & FT_FLAGS_HISPEED != 0,
+    }
+}
+
+pub fn list_devices() -> Result<Vec<DeviceInfo>, Error> {
+    let nodes = get_device_info_list().map_err(|e| Error::from(e as FT_STATUS))?;
+    Ok(nodes.iter().map(device_info).collect())
+}
+
+impl VCP {
+    /// Finish opening a device by reading back its current bit mode.
+    fn from_handle(handle: FT_HANDLE) -> Result<VCP, Error> {
+        let mut bit_mode = MaybeUninit::<UCHAR>::uninit();
+        let result = Error::from(unsafe { FT_GetBitMode(handle, bit_mode.as_mut_ptr()) });
+        if result != Error::NoError {
+            unsafe { FT_Close(handle) };
+            return Err(result);
+        }
+        let bit_mode = BitMode::from(unsafe { bit_mode.assume_init() });
+        Ok(VCP { handle, bit_mode })
+    }
+
+    fn open_ex(arg: PVOID, flags: DWORD) -> Result<VCP, Error> {
+        let mut handle = MaybeUninit::<FT_HANDLE>::uninit();
+        let result = Error::from(unsafe { FT_OpenEx(arg, flags, handle.as_mut_ptr()) });
+        if result != Error::NoError {
+            return Err(result);
+        }
+        VCP::from_handle(unsafe { handle.assume_init() })
+    }
+
+    pub fn new_from_name(name: &str) -> Result<VCP, Error> {
+        let c_str = CString::new(name).or(Err(Error::StringContainsNullByte))?;
+        VCP::open_ex(c_str.as_ptr() as PVOID, FT_OPEN_BY_DESCRIPTION)
+    }
+
+    pub fn open_by_serial(serial: &str) -> Result<VCP, Error> {
+        let c_str = CString::new(serial).or(Err(Error::StringContainsNullByte))?;
+        VCP::open_ex(c_str.as_ptr() as PVOID, FT_OPEN_BY_SERIAL_NUMBER)
+    }
+
+    pub fn open_by_location(location: u32) -> Result<VCP, Error> {
+        VCP::open_ex(location as usize as PVOID, FT_OPEN_BY_LOCATION)
+    }
+
+    pub fn open_by_index(index: usize) -> Result<VCP, Error> {
+        let index = index.try_into().or(Err(Error::InvalidArgs))?;
+        let mut handle = MaybeUninit::<FT_HANDLE>::uninit();
+        let result = Error::from(unsafe { FT_Open(index, handle.as_mut_ptr()) });
+        if result != Error::NoError {
+            return Err(result);
+        }
+        VCP::from_handle(unsafe { handle.assume_init() })
+    }
+
+    pub fn com_port(&self) -> Result<usize, Error> {
+        let mut com_port_number = MaybeUninit::<LONG>::uninit();
+        let result =
+            Error::from(unsafe { FT_GetComPortNumber(self.handle, com_port_number.as_mut_ptr()) });
+        if result != Error::NoError {
+            Err(result)
+        } else {
+            let com_port_number = unsafe { com_port_number.assume_init() };
+            if let Ok(e) = com_port_number.try_into() {
+                Ok(e)
+            } else {
+                Err(Error::NoComPortAssigned)
+            }
+        }
+    }
+
+    /// Set the given signals to "OUTPUT".  All other signals will be "INPUT".
+    pub fn set_bitmode(&mut self, outputs: u8, bitmode: BitMode) -> Result<(), Error> {
+        let result = Error::from(unsafe { FT_SetBitMode(self.handle, outputs, bitmode.to_u8()) });
+        if result != Error::NoError {
+            Err(result)
+        } else {
+            self.bit_mode = bitmode;
+            Ok(())
+        }
+    }
+
+    pub fn reset(&mut self) -> Result<(), Error> {
+        let result = Error::from(unsafe { FT_ResetDevice(self.handle) });
+        if result != Error::NoError {
+            Err(result)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn purge(&mut self) -> Result<(), Error> {
+        let result = Error::from(unsafe {
+            FT_Purge(
+                self.handle,
+                ftdi_vcp_sys::FT_PURGE_RX | ftdi_vcp_sys::FT_PURGE_TX,
+            )
+        });
+        if result != Error::NoError {
+            Err(result)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn latency_timer(&mut self) -> Result<u8, Error> {
+        let mut latency = MaybeUninit::<UCHAR>::uninit();
+        let result = Error::from(unsafe { FT_GetLatencyTimer(self.handle, latency.as_mut_ptr()) });
+        if result != Error::NoError {
+            Err(result)
+        } else {
+            Ok(unsafe { latency.assume_init() })
+        }
+    }
+
+    pub fn set_latency_timer(&mut self, latency: u8) -> Result<(), Error> {
+        let result = Error::from(unsafe { FT_SetLatencyTimer(self.handle, latency) });
+        if result != Error::NoError {
+            Err(result)
+        } else {
+            Ok(())
+        }
+    }
+
+    // pub fn write(&mut self, out_buffer: &[u8]) -> Result<usize, Error> {
+    //     let mut bytes_written = MaybeUninit::<DWORD>::uninit();
+    //     let result = Error::from(unsafe {
+    //         FT_Write(
+    //             self.handle,
+    //             out_buffer.as_ptr() as LPVOID,
+    //             out_buffer
+    //                 .len()
+    //                 .try_into()
+    //                 .expect("couldn't convert buffer length to DWORD"),
+    //             bytes_written.as_mut_ptr() as LPDWORD,
+    //         )
+    //     });
+    //     if result != Error::NoError {
+    //         Err(result)
+    //     } else {
+    //         Ok(unsafe { bytes_written.assume_init() }
+    //             .try_into()
+    //             .expect("invalid number of bytes written"))
+    //     }
+    // }
+}
+
+impl Write for VCP {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut bytes_written = MaybeUninit::<DWORD>::uninit();
+        let result = Error::from(unsafe {
+            FT_Write(
+                self.handle,
+                buf.as_ptr() as LPVOID,
+                buf.len()
+                    .try_into()
+                    .expect("couldn't convert buffer length to DWORD"),
+                bytes_written.as_mut_ptr() as LPDWORD,
+            )
+        });
+        if result != Error::NoError {
+            Err(std::io::Error::new(std::io::ErrorKind::Other, result))
+        } else {
+            let bytes_written = unsafe { bytes_written.assume_init() };
+            // println!(
+            //     "Wrote {} bytes (wanted to write {})",
+            //     bytes_written,
+            //     buf.len()
+            // );
+            Ok(bytes_written
+                .try_into()
+                .expect("invalid number of bytes written"))
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for VCP {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let mut bytes_read = MaybeUninit::<DWORD>::uninit();
+        let result = Error::from(unsafe {
+            FT_Read(
+                self.handle,
+                buf.as_mut_ptr() as LPVOID,
+                buf.len()
+                    .try_into()
+                    .expect("couldn't convert buffer length to DWORD"),
+                bytes_read.as_mut_ptr() as LPDWORD,
+            )
+        });
+        if result != Error::NoError {
+            Err(std::io::Error::new(std::io::ErrorKind::Other, result))
+        } else {
+            let bytes_read = unsafe { bytes_read.assume_init() };
+            // println!("Read {} bytes (wanted to read {})", bytes_read, buf.len());
+            Ok(bytes_read
+                .try_into()
+                .expect("invalid number of bytes written"))
+        }
+    }
+}
+
+impl Drop for VCP {
+    fn drop(&mut self) {
+        // There's nothing useful to do if closing fails, and panicking
+        // here would abort a program that is already unwinding.
+        let _ = unsafe { FT_Close(self.handle) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_list_nodes() {
+        let mut node = FT_DEVICE_LIST_INFO_NODE {
+            Flags: FT_FLAGS_HISPEED,
+            Type: 6,
+            ID: 0x0403_6010,
+            LocId: 0x321,
+            SerialNumber: [0; 16],
+            Description: [0; 64],
+            ftHandle: std::ptr::null_mut(),
+        };
+        node.SerialNumber[..9].copy_from_slice(b"FT4ABC12B");
+        node.Description[..18].copy_from_slice(b"iCEBreaker V1.0e B");
+        let info = device_info(&node);
+        assert_eq!(info.device_type, DeviceType::Ft2232H);
+        assert_eq!((info.vid, info.pid, info.location), (0x0403, 0x6010, 0x321));
+        assert_eq!(info.serial_number, "FT4ABC12B");
+        assert_eq!(info.description, "iCEBreaker V1.0e B");
+        assert!(info.high_speed && !info.opened);
+
+        // A description that fills the whole buffer has no terminator
+        assert_eq!(c_string(b"full"), "full");
+    }
+
+    #[test]
+    fn status_codes() {
+        assert_eq!(Error::from(0), Error::NoError);
+        assert_eq!(Error::from(2), Error::DeviceNotFound);
+        assert_eq!(Error::from(99), Error::UnknownError(99));
+    }
+}
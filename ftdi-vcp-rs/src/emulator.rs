@@ -0,0 +1,854 @@
+//! An emulated FT2232H MPSSE engine.  Bytes written to an `Emulator` are
+//! parsed exactly as the real chip would parse them, recorded as a list of
+//! `Event`s, and any SPI traffic is routed to a virtual `Peripheral`.  Bytes
+//! the chip would send back to the host can be read out again, so anything
+//! that drives a `VCP` through `Read` and `Write` can be tested without
+//! hardware.
+
+use crate::mpsse::{Command, MC_DATA_BITS, MC_DATA_IN, MC_DATA_LSB, MC_DATA_OUT, MC_DATA_TMS};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Response to an opcode the MPSSE engine doesn't understand.
+pub const BAD_COMMAND: u8 = 0xFA;
+
+/// GPIOL1 (ADBUS5), the line the wait commands watch
+const GPIOL1: u16 = 1 << 5;
+
+/// How long a read waits for a stalled wait command, like the read timeout
+/// of the real driver.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A virtual device attached to the emulated MPSSE pins.  Pin values are
+/// given as a 16-bit word, with ADBUS in the low byte and ACBUS in the high
+/// byte.
+pub trait Peripheral {
+    /// Called whenever the level of any pin changes.
+    fn set_pins(&mut self, _pins: u16) {}
+
+    /// Return the pins this device drives as `(mask, value)`.  Input pins
+    /// that nothing drives read as high.
+    fn drive_pins(&mut self) -> (u16, u16) {
+        (0, 0)
+    }
+
+    /// Clock `bits` bits out of the top of `mosi`, MSB first.  The bits
+    /// clocked in are returned in the bottom of the result, the same way the
+    /// MPSSE shift register collects them.
+    fn clock(&mut self, mosi: u8, bits: u8) -> u8;
+}
+
+/// Nothing attached: MISO floats high.
+impl Peripheral for () {
+    fn clock(&mut self, _mosi: u8, _bits: u8) -> u8 {
+        0xff
+    }
+}
+
+/// Several devices on the same bus.  Each one is expected to ignore SPI
+/// traffic while it is not selected and leave MISO high.
+impl Peripheral for Vec<Box<dyn Peripheral>> {
+    fn set_pins(&mut self, pins: u16) {
+        for peripheral in self.iter_mut() {
+            peripheral.set_pins(pins);
+        }
+    }
+
+    fn drive_pins(&mut self) -> (u16, u16) {
+        self.iter_mut().fold((0, 0), |(mask, value), p| {
+            let (m, v) = p.drive_pins();
+            (mask | m, value | (v & m))
+        })
+    }
+
+    fn clock(&mut self, mosi: u8, bits: u8) -> u8 {
+        self.iter_mut()
+            .fold(0xff, |miso, p| miso & p.clock(mosi, bits))
+    }
+}
+
+/// Everything the emulated MPSSE engine was asked to do, in order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    SetLow {
+        value: u8,
+        direction: u8,
+    },
+    SetHigh {
+        value: u8,
+        direction: u8,
+    },
+    ReadLow(u8),
+    ReadHigh(u8),
+
+    /// A data shifting command.  `command` holds the raw opcode so the clock
+    /// edges and bit order can be checked.  In bit mode `mosi` and `miso`
+    /// each hold a single byte.
+    Shift {
+        command: u8,
+        bits: usize,
+        mosi: Vec<u8>,
+        miso: Vec<u8>,
+    },
+
+    /// Bits clocked out on TMS
+    Tms {
+        command: u8,
+        bits: usize,
+        data: u8,
+    },
+
+    ClockDivisor(u16),
+    Divide5(bool),
+    Loopback(bool),
+    ThreePhaseClock(bool),
+    AdaptiveClock(bool),
+
+    /// Clocks with no data transfer
+    Clocks(usize),
+
+    /// Waited for GPIOL1 (ADBUS5) to reach the given level.  Everything
+    /// after the wait is held up until the peripheral drives the line there,
+    /// and the event is recorded once it has.
+    Wait {
+        high: bool,
+    },
+
+    /// Clock until GPIOL1 reaches the given level, up to `count` bytes if
+    /// counting bytes.
+    ClockUntil {
+        high: bool,
+        count: Option<usize>,
+    },
+
+    Tristate {
+        low: u8,
+        high: u8,
+    },
+    Flush,
+    BadCommand(u8),
+}
+
+pub struct Emulator<P: Peripheral> {
+    peripheral: P,
+
+    /// Bytes written by the host that don't yet form a complete command
+    input: Vec<u8>,
+
+    /// Bytes waiting to be read by the host
+    output: VecDeque<u8>,
+
+    /// Whether a wait command is holding up the input
+    waiting: bool,
+    read_timeout: Duration,
+
+    events: Vec<Event>,
+
+    low_value: u8,
+    low_direction: u8,
+    high_value: u8,
+    high_direction: u8,
+
+    divisor: u16,
+    divide_by_5: bool,
+    loopback: bool,
+    three_phase: bool,
+    adaptive: bool,
+}
+
+impl<P: Peripheral> Emulator<P> {
+    pub fn new(peripheral: P) -> Emulator<P> {
+        Emulator {
+            peripheral,
+            input: vec![],
+            output: VecDeque::new(),
+            waiting: false,
+            read_timeout: READ_TIMEOUT,
+            events: vec![],
+            low_value: 0,
+            low_direction: 0,
+            high_value: 0,
+            high_direction: 0,
+            divisor: 0,
+            // The FT2232H powers up dividing its clock by 5 for
+            // compatibility with the FT2232D.
+            divide_by_5: true,
+            loopback: false,
+            three_phase: false,
+            adaptive: false,
+        }
+    }
+
+    pub fn peripheral(&self) -> &P {
+        &self.peripheral
+    }
+
+    pub fn peripheral_mut(&mut self) -> &mut P {
+        &mut self.peripheral
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// The SPI clock frequency that the current settings would produce.
+    pub fn frequency(&self) -> u32 {
+        let base = if self.divide_by_5 {
+            12_000_000
+        } else {
+            60_000_000
+        };
+        let frequency = base / ((1 + self.divisor as u32) * 2);
+        if self.three_phase {
+            frequency * 2 / 3
+        } else {
+            frequency
+        }
+    }
+
+    pub fn loopback(&self) -> bool {
+        self.loopback
+    }
+
+    /// Change how long a read waits for GPIOL1 before giving up and
+    /// returning nothing.
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = timeout;
+    }
+
+    /// The current level of every pin, taking into account what the host is
+    /// driving and what the peripheral is driving.
+    pub fn pins(&mut self) -> u16 {
+        let direction = (self.high_direction as u16) << 8 | self.low_direction as u16;
+        let driven = (self.high_value as u16) << 8 | self.low_value as u16;
+        let (mask, value) = self.peripheral.drive_pins();
+        let inputs = (value & mask) | !mask;
+        (driven & direction) | (inputs & !direction)
+    }
+
+    fn update_pins(&mut self) {
+        let pins = self.pins();
+        self.peripheral.set_pins(pins);
+    }
+
+    /// Parse and execute as many complete commands as are buffered.
+    fn process(&mut self) {
+        let input = std::mem::take(&mut self.input);
+        let mut offset = 0;
+        while let Some(consumed) = self.step(&input[offset..]) {
+            offset += consumed;
+        }
+        self.input = input[offset..].to_vec();
+    }
+
+    /// Execute the command at the start of `input`, returning how many bytes
+    /// it used, or `None` if the command isn't complete yet.
+    fn step(&mut self, input: &[u8]) -> Option<usize> {
+        use Command::*;
+        let opcode = *input.first()?;
+        if opcode & 0x80 == 0 {
+            return self.shift(input);
+        }
+
+        let need = |n: usize| if input.len() >= n { Some(()) } else { None };
+        let command = match Command::from_u8(opcode) {
+            Some(c) => c,
+            None => {
+                self.bad_command(opcode);
+                return Some(1);
+            }
+        };
+        match command {
+            MC_SETB_LOW => {
+                need(3)?;
+                self.low_value = input[1];
+                self.low_direction = input[2];
+                self.events.push(Event::SetLow {
+                    value: input[1],
+                    direction: input[2],
+                });
+                self.update_pins();
+                Some(3)
+            }
+            MC_SETB_HIGH => {
+                need(3)?;
+                self.high_value = input[1];
+                self.high_direction = input[2];
+                self.events.push(Event::SetHigh {
+                    value: input[1],
+                    direction: input[2],
+                });
+                self.update_pins();
+                Some(3)
+            }
+            MC_READB_LOW => {
+                let value = self.pins() as u8;
+                self.output.push_back(value);
+                self.events.push(Event::ReadLow(value));
+                Some(1)
+            }
+            MC_READB_HIGH => {
+                let value = (self.pins() >> 8) as u8;
+                self.output.push_back(value);
+                self.events.push(Event::ReadHigh(value));
+                Some(1)
+            }
+            MC_LOOPBACK_EN | MC_LOOPBACK_DIS => {
+                self.loopback = opcode == MC_LOOPBACK_EN.to_u8();
+                self.events.push(Event::Loopback(self.loopback));
+                Some(1)
+            }
+            MC_SET_CLK_DIV => {
+                need(3)?;
+                self.divisor = input[1] as u16 | (input[2] as u16) << 8;
+                self.events.push(Event::ClockDivisor(self.divisor));
+                Some(3)
+            }
+            MC_FLUSH => {
+                self.events.push(Event::Flush);
+                Some(1)
+            }
+            MC_WAIT_H | MC_WAIT_L => {
+                let high = opcode == MC_WAIT_H.to_u8();
+                self.waiting = (self.pins() & GPIOL1 != 0) != high;
+                if self.waiting {
+                    return None;
+                }
+                self.events.push(Event::Wait { high });
+                Some(1)
+            }
+            MC_TCK_X5 | MC_TCK_D5 => {
+                self.divide_by_5 = opcode == MC_TCK_D5.to_u8();
+                self.events.push(Event::Divide5(self.divide_by_5));
+                Some(1)
+            }
+            MC_EN_3PH_CLK | MC_DIS_3PH_CLK => {
+                self.three_phase = opcode == MC_EN_3PH_CLK.to_u8();
+                self.events.push(Event::ThreePhaseClock(self.three_phase));
+                Some(1)
+            }
+            MC_EN_ADPT_CLK | MC_DIS_ADPT_CLK => {
+                self.adaptive = opcode == MC_EN_ADPT_CLK.to_u8();
+                self.events.push(Event::AdaptiveClock(self.adaptive));
+                Some(1)
+            }
+            MC_CLK_N => {
+                need(2)?;
+                self.events.push(Event::Clocks(input[1] as usize + 1));
+                Some(2)
+            }
+            MC_CLK_N8 => {
+                need(3)?;
+                let bytes = input[1] as usize | (input[2] as usize) << 8;
+                self.events.push(Event::Clocks((bytes + 1) * 8));
+                Some(3)
+            }
+            MC_CLK_TO_H | MC_CLK_TO_L => {
+                let high = opcode == MC_CLK_TO_H.to_u8();
+                self.events.push(Event::ClockUntil { high, count: None });
+                Some(1)
+            }
+            MC_CLK8_TO_H | MC_CLK8_TO_L => {
+                need(3)?;
+                let high = opcode == MC_CLK8_TO_H.to_u8();
+                let count = input[1] as usize | (input[2] as usize) << 8;
+                self.events.push(Event::ClockUntil {
+                    high,
+                    count: Some(count + 1),
+                });
+                Some(3)
+            }
+            MC_TRI => {
+                need(3)?;
+                self.events.push(Event::Tristate {
+                    low: input[1],
+                    high: input[2],
+                });
+                Some(3)
+            }
+            // Host bus emulation commands are not valid in MPSSE mode.
+            MC_CPU_RS | MC_CPU_RE | MC_CPU_WS | MC_CPU_WE => {
+                self.bad_command(opcode);
+                Some(1)
+            }
+        }
+    }
+
+    fn bad_command(&mut self, opcode: u8) {
+        self.output.push_back(BAD_COMMAND);
+        self.output.push_back(opcode);
+        self.events.push(Event::BadCommand(opcode));
+    }
+
+    /// Decode a data shifting command (one with the top bit clear).
+    fn shift(&mut self, input: &[u8]) -> Option<usize> {
+        let command = input[0];
+        let read = command & MC_DATA_IN != 0;
+        let write = command & MC_DATA_OUT != 0;
+        let lsb = command & MC_DATA_LSB != 0;
+
+        if command & MC_DATA_TMS != 0 {
+            // TMS commands are always bit mode and always carry a data byte.
+            if input.len() < 3 {
+                return None;
+            }
+            let bits = (input[1] & 7) as usize + 1;
+            self.events.push(Event::Tms {
+                command,
+                bits,
+                data: input[2],
+            });
+            if read {
+                self.output.push_back(0xff);
+            }
+            return Some(3);
+        }
+
+        if !read && !write {
+            self.bad_command(command);
+            return Some(1);
+        }
+
+        if command & MC_DATA_BITS != 0 {
+            let len = if write { 3 } else { 2 };
+            if input.len() < len {
+                return None;
+            }
+            let bits = (input[1] & 7) as usize + 1;
+            let mosi = if write { input[2] } else { 0 };
+            let miso = self.clock(mosi, bits as u8, lsb);
+            if read {
+                self.output.push_back(miso);
+            }
+            self.events.push(Event::Shift {
+                command,
+                bits,
+                mosi: vec![mosi],
+                miso: vec![miso],
+            });
+            return Some(len);
+        }
+
+        if input.len() < 3 {
+            return None;
+        }
+        let count = (input[1] as usize | (input[2] as usize) << 8) + 1;
+        let len = if write { 3 + count } else { 3 };
+        if input.len() < len {
+            return None;
+        }
+        let mosi = if write {
+            input[3..len].to_vec()
+        } else {
+            vec![0; count]
+        };
+        let miso: Vec<u8> = mosi.iter().map(|b| self.clock(*b, 8, lsb)).collect();
+        if read {
+            self.output.extend(&miso);
+        }
+        self.events.push(Event::Shift {
+            command,
+            bits: count * 8,
+            mosi,
+            miso,
+        });
+        Some(len)
+    }
+
+    /// Clock up to 8 bits through the peripheral, taking bit order and
+    /// loopback into account.
+    fn clock(&mut self, mosi: u8, bits: u8, lsb: bool) -> u8 {
+        let mask = ((1u16 << bits) - 1) as u8;
+        let out = if lsb { mosi.reverse_bits() } else { mosi };
+        let mut miso = self.peripheral.clock(out, bits) & mask;
+        if self.loopback {
+            miso = (out as u16 >> (8 - bits)) as u8;
+        }
+        if lsb {
+            miso.reverse_bits()
+        } else {
+            miso
+        }
+    }
+}
+
+impl<P: Peripheral> Write for Emulator<P> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.input.extend_from_slice(buf);
+        self.process();
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<P: Peripheral> Read for Emulator<P> {
+    /// Returns whatever the emulated chip has queued up, first giving a
+    /// stalled wait command until the read timeout to finish.  Reading when
+    /// nothing is queued returns 0 bytes, which `read_exact` treats as an
+    /// error.
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let deadline = Instant::now() + self.read_timeout;
+        while self.output.is_empty() && self.waiting && Instant::now() < deadline {
+            sleep(Duration::from_micros(100));
+            self.process();
+        }
+
+        let count = buf.len().min(self.output.len());
+        for (dest, src) in buf.iter_mut().zip(self.output.drain(..count)) {
+            *dest = src;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpsse;
+
+    /// A one-byte shift register that is only selected while ADBUS4 is low.
+    #[derive(Default)]
+    struct ShiftRegister {
+        selected: bool,
+        value: u8,
+        bytes: Vec<u8>,
+    }
+
+    impl Peripheral for ShiftRegister {
+        fn set_pins(&mut self, pins: u16) {
+            self.selected = pins & 0x10 == 0;
+        }
+
+        fn drive_pins(&mut self) -> (u16, u16) {
+            (0x40, 0x00)
+        }
+
+        fn clock(&mut self, mosi: u8, bits: u8) -> u8 {
+            if !self.selected || bits != 8 {
+                return 0xff;
+            }
+            self.bytes.push(mosi);
+            std::mem::replace(&mut self.value, mosi)
+        }
+    }
+
+    #[test]
+    fn gpio() {
+        let mut emulator = Emulator::new(ShiftRegister::default());
+        mpsse::set_gpio_low(&mut emulator, 0x10, 0x93).unwrap();
+        assert_eq!(
+            emulator.take_events(),
+            vec![Event::SetLow {
+                value: 0x10,
+                direction: 0x93
+            }]
+        );
+
+        // Outputs read back as driven, the peripheral pulls ADBUS6 low and
+        // everything else floats high.
+        assert_eq!(mpsse::read_gpio_low(&mut emulator).unwrap(), 0x3C);
+        assert_eq!(mpsse::read_gpio_high(&mut emulator).unwrap(), 0xff);
+    }
+
+    #[test]
+    fn spi_routed_to_peripheral() {
+        let mut emulator = Emulator::new(ShiftRegister::default());
+        mpsse::set_gpio_low(&mut emulator, 0x00, 0x93).unwrap();
+
+        let mut data = [1, 2, 3];
+        mpsse::xfer_spi(&mut emulator, &mut data).unwrap();
+        assert_eq!(data, [0, 1, 2]);
+        mpsse::send_spi(&mut emulator, &[4, 5]).unwrap();
+        assert_eq!(emulator.peripheral().bytes, vec![1, 2, 3, 4, 5]);
+
+        let events = emulator.take_events();
+        assert_eq!(
+            events[1],
+            Event::Shift {
+                command: 0x31,
+                bits: 24,
+                mosi: vec![1, 2, 3],
+                miso: vec![0, 1, 2],
+            }
+        );
+        assert_eq!(
+            events[2],
+            Event::Shift {
+                command: 0x11,
+                bits: 16,
+                mosi: vec![4, 5],
+                miso: vec![3, 4],
+            }
+        );
+
+        // Deselected, MISO floats high and nothing reaches the peripheral.
+        mpsse::set_gpio_low(&mut emulator, 0x10, 0x93).unwrap();
+        let mut data = [6];
+        mpsse::xfer_spi(&mut emulator, &mut data).unwrap();
+        assert_eq!(data, [0xff]);
+        assert_eq!(emulator.peripheral().bytes.len(), 5);
+    }
+
+    #[test]
+    fn loopback() {
+        let mut emulator = Emulator::new(());
+        emulator
+            .write_all(&[Command::MC_LOOPBACK_EN.to_u8()])
+            .unwrap();
+        let mut data = [0xDE, 0xAD, 0xBE, 0xEF];
+        mpsse::xfer_spi(&mut emulator, &mut data).unwrap();
+        assert_eq!(data, [0xDE, 0xAD, 0xBE, 0xEF]);
+
+        assert_eq!(mpsse::xfer_spi_bits(&mut emulator, 0xA0, 3).unwrap(), 0b101);
+        assert_eq!(
+            mpsse::xfer_spi_bits(&mut emulator, 0xFF, 0),
+            Err(crate::Error::InvalidArgs)
+        );
+        assert_eq!(
+            mpsse::xfer_spi_bits(&mut emulator, 0xFF, 9),
+            Err(crate::Error::InvalidArgs)
+        );
+
+        // LSB first sends the bottom bits of the byte.
+        emulator
+            .write_all(&[
+                MC_DATA_IN | MC_DATA_OUT | MC_DATA_LSB | MC_DATA_BITS,
+                2,
+                0x05,
+            ])
+            .unwrap();
+        let mut result = [0];
+        emulator.read_exact(&mut result).unwrap();
+        assert_eq!(result[0], 0xA0);
+    }
+
+    #[test]
+    fn clock_divisor() {
+        let mut emulator = Emulator::new(());
+        assert_eq!(emulator.frequency(), 6_000_000);
+
+        emulator
+            .write_all(&[
+                Command::MC_TCK_X5.to_u8(),
+                Command::MC_SET_CLK_DIV.to_u8(),
+                0x02,
+                0x00,
+            ])
+            .unwrap();
+        assert_eq!(emulator.frequency(), 10_000_000);
+
+        emulator
+            .write_all(&[
+                Command::MC_TCK_D5.to_u8(),
+                Command::MC_SET_CLK_DIV.to_u8(),
+                119,
+                0x00,
+            ])
+            .unwrap();
+        assert_eq!(emulator.frequency(), 50_000);
+        assert_eq!(
+            emulator.take_events(),
+            vec![
+                Event::Divide5(false),
+                Event::ClockDivisor(2),
+                Event::Divide5(true),
+                Event::ClockDivisor(119),
+            ]
+        );
+    }
+
+    #[test]
+    fn spi_frequency() {
+        let mut emulator = Emulator::new(());
+        for (requested, actual) in [
+            (6_000_000, 6_000_000),
+            (50_000, 50_000),
+            (30_000_000, 30_000_000),
+            (100_000_000, 30_000_000),
+            (10_000_000, 10_000_000),
+            (7_000_000, 6_000_000),
+            (1_000, 1_000),
+            (10, 91),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                mpsse::set_spi_frequency(&mut emulator, *requested).unwrap(),
+                *actual
+            );
+            assert_eq!(emulator.frequency(), *actual);
+        }
+        assert_eq!(mpsse::clock_divisor(0), Err(crate::Error::InvalidArgs));
+
+        // The defaults swot has always used stay on the /5 prescaler
+        assert_eq!(mpsse::clock_divisor(6_000_000), Ok((true, 0, 6_000_000)));
+        assert_eq!(mpsse::clock_divisor(50_000), Ok((true, 119, 50_000)));
+    }
+
+    #[test]
+    fn commands_split_across_writes() {
+        let mut emulator = Emulator::new(());
+        emulator.write_all(&[0x80, 0x12]).unwrap();
+        assert!(emulator.events().is_empty());
+        emulator.write_all(&[0x34, 0x11, 0x01]).unwrap();
+        assert_eq!(emulator.events().len(), 1);
+        emulator.write_all(&[0x00, 0xAA]).unwrap();
+        assert_eq!(emulator.events().len(), 1);
+        emulator.write_all(&[0xBB, 0x87]).unwrap();
+        assert_eq!(
+            emulator.take_events(),
+            vec![
+                Event::SetLow {
+                    value: 0x12,
+                    direction: 0x34
+                },
+                Event::Shift {
+                    command: 0x11,
+                    bits: 16,
+                    mosi: vec![0xAA, 0xBB],
+                    miso: vec![0xff, 0xff],
+                },
+                Event::Flush,
+            ]
+        );
+    }
+
+    #[test]
+    fn long_transfers() {
+        let mut emulator = Emulator::new(());
+        emulator
+            .write_all(&[Command::MC_LOOPBACK_EN.to_u8()])
+            .unwrap();
+        emulator.take_events();
+
+        let mut data = (0..0x12345).map(|i| i as u8).collect::<Vec<_>>();
+        let expected = data.clone();
+        mpsse::xfer_spi(&mut emulator, &mut data).unwrap();
+        assert_eq!(data, expected);
+        let events = emulator.take_events();
+        assert_eq!(events.len(), 0x12345_usize.div_ceil(mpsse::READ_CHUNK));
+        assert!(events.iter().all(|e| match e {
+            Event::Shift { bits, .. } => *bits <= mpsse::READ_CHUNK * 8,
+            _ => false,
+        }));
+
+        // Sends don't need to wait for a reply, so only split where the
+        // length field runs out
+        mpsse::send_spi(&mut emulator, &data).unwrap();
+        let bits = emulator
+            .take_events()
+            .iter()
+            .map(|e| match e {
+                Event::Shift { bits, .. } => *bits,
+                _ => 0,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(bits, vec![0x10000 * 8, 0x2345 * 8]);
+    }
+
+    #[test]
+    fn queue() {
+        let mut emulator = Emulator::new(ShiftRegister::default());
+        let mut queue = mpsse::Queue::new();
+        queue.set_gpio_low(0x00, 0x93);
+        queue.send_spi(&[1, 2]);
+        let xfer = queue.xfer_spi(&[3, 4]);
+        queue.set_gpio_low(0x10, 0x93);
+        let gpio = queue.read_gpio_low();
+        assert_eq!((xfer, gpio, queue.reply_len()), (0, 2, 3));
+
+        let reply = mpsse::submit(&mut emulator, &queue).unwrap();
+        assert_eq!(reply, vec![2, 3, 0x3C]);
+        let events = emulator.take_events();
+        assert_eq!(events.len(), 6);
+        assert_eq!(events[5], Event::Flush);
+
+        // Replies that could overflow the chip's buffer are refused
+        let mut queue = mpsse::Queue::new();
+        queue.xfer_spi(&vec![0; mpsse::READ_CHUNK]);
+        assert!(mpsse::submit(&mut emulator, &queue).is_ok());
+        queue.read_gpio_low();
+        emulator.take_events();
+        assert_eq!(
+            mpsse::submit(&mut emulator, &queue),
+            Err(crate::Error::InvalidArgs)
+        );
+        assert!(emulator.events().is_empty());
+
+        // Long transfers are split into commands the chip can take
+        let mut queue = mpsse::Queue::new();
+        queue.send_spi(&vec![0x55; 0x10001]);
+        let encoded = queue.encode();
+        assert_eq!(&encoded[..3], &[0x11, 0xff, 0xff]);
+        assert_eq!(&encoded[0x10003..0x10006], &[0x11, 0x00, 0x00]);
+        assert_eq!(encoded.len(), 0x10001 + 7);
+    }
+
+    /// A device that drives GPIOL1 to whatever level it is given.
+    struct ReadyLine(bool);
+
+    impl Peripheral for ReadyLine {
+        fn drive_pins(&mut self) -> (u16, u16) {
+            (GPIOL1, if self.0 { GPIOL1 } else { 0 })
+        }
+
+        fn clock(&mut self, _mosi: u8, _bits: u8) -> u8 {
+            0xff
+        }
+    }
+
+    #[test]
+    fn waits_hold_up_later_commands() {
+        let mut emulator = Emulator::new(ReadyLine(false));
+        emulator.set_read_timeout(Duration::from_millis(10));
+        emulator
+            .write_all(&[
+                Command::MC_WAIT_H.to_u8(),
+                Command::MC_READB_LOW.to_u8(),
+                Command::MC_FLUSH.to_u8(),
+            ])
+            .unwrap();
+        assert!(emulator.events().is_empty());
+        let mut result = [0; 1];
+        assert!(emulator.read_exact(&mut result).is_err());
+
+        emulator.peripheral_mut().0 = true;
+        emulator.read_exact(&mut result).unwrap();
+        assert_eq!(result[0] & 0x20, 0x20);
+        assert_eq!(
+            emulator.take_events(),
+            vec![
+                Event::Wait { high: true },
+                Event::ReadLow(result[0]),
+                Event::Flush
+            ]
+        );
+
+        // A line already at the level doesn't hold anything up
+        emulator
+            .write_all(&[Command::MC_WAIT_H.to_u8(), Command::MC_FLUSH.to_u8()])
+            .unwrap();
+        assert_eq!(
+            emulator.take_events(),
+            vec![Event::Wait { high: true }, Event::Flush]
+        );
+    }
+
+    #[test]
+    fn bad_command() {
+        let mut emulator = Emulator::new(());
+        emulator.write_all(&[0xAB]).unwrap();
+        let mut result = [0; 2];
+        emulator.read_exact(&mut result).unwrap();
+        assert_eq!(result, [BAD_COMMAND, 0xAB]);
+        assert_eq!(emulator.events(), &[Event::BadCommand(0xAB)]);
+    }
+}
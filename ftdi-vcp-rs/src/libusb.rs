@@ -0,0 +1,527 @@
+//! Native backend that talks to the FTDI chip directly over libusb, using the
+//! same vendor control requests and bulk endpoints as libftdi.  This lets the
+//! crate run on platforms where the D2XX driver is not available.
+
+use crate::{BitMode, DeviceInfo, DeviceType, Error, VCP};
+use rusb::{Context, Device, DeviceHandle, Direction, Speed, TransferType, UsbContext, Version};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+const FTDI_VID: u16 = 0x0403;
+
+/// FT232R, FT2232C/D/H, FT4232H, FT232H and FT-X
+const FTDI_PIDS: &[u16] = &[0x6001, 0x6010, 0x6011, 0x6014, 0x6015];
+
+/* Vendor requests */
+const SIO_RESET_REQUEST: u8 = 0x00;
+const SIO_SET_LATENCY_TIMER_REQUEST: u8 = 0x09;
+const SIO_GET_LATENCY_TIMER_REQUEST: u8 = 0x0A;
+const SIO_SET_BITMODE_REQUEST: u8 = 0x0B;
+const SIO_READ_PINS_REQUEST: u8 = 0x0C;
+
+/* Values for SIO_RESET_REQUEST */
+const SIO_RESET_SIO: u16 = 0;
+const SIO_RESET_PURGE_RX: u16 = 1;
+const SIO_RESET_PURGE_TX: u16 = 2;
+
+/* bmRequestType for vendor requests to the device */
+const FTDI_DEVICE_OUT_REQTYPE: u8 = 0x40;
+const FTDI_DEVICE_IN_REQTYPE: u8 = 0xC0;
+
+/// Every bulk IN packet starts with two modem status bytes that are not
+/// part of the data stream.
+const MODEM_STATUS_LEN: usize = 2;
+
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Handle {
+    device: DeviceHandle<Context>,
+    interface: u8,
+    in_ep: u8,
+    out_ep: u8,
+    packet_size: usize,
+    rx: VecDeque<u8>,
+}
+
+impl std::fmt::Debug for Handle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let device = self.device.device();
+        f.debug_struct("Handle")
+            .field("bus", &device.bus_number())
+            .field("address", &device.address())
+            .field("interface", &self.interface)
+            .finish()
+    }
+}
+
+impl From<rusb::Error> for Error {
+    fn from(src: rusb::Error) -> Self {
+        match src {
+            rusb::Error::Io => Error::IoError,
+            rusb::Error::InvalidParam => Error::InvalidParameter,
+            rusb::Error::Access | rusb::Error::Busy => Error::DeviceNotOpened,
+            rusb::Error::NoDevice | rusb::Error::NotFound => Error::DeviceNotFound,
+            rusb::Error::Timeout | rusb::Error::Overflow | rusb::Error::Pipe => Error::IoError,
+            rusb::Error::NoMem => Error::InsufficientResources,
+            rusb::Error::NotSupported => Error::NotSupported,
+            _ => Error::OtherError,
+        }
+    }
+}
+
+fn io_error(e: rusb::Error) -> std::io::Error {
+    let kind = match e {
+        rusb::Error::Timeout => std::io::ErrorKind::TimedOut,
+        _ => std::io::ErrorKind::Other,
+    };
+    std::io::Error::new(kind, Error::from(e))
+}
+
+/// How `VCP` picks which interface to open, in the terms `list_devices`
+/// reports them.
+enum Selector<'a> {
+    Description(&'a str),
+    Serial(&'a str),
+    Location(u32),
+
+    /// Position in the list returned by `list_devices()`
+    Index(usize),
+}
+
+impl Selector<'_> {
+    fn matches(&self, index: usize, info: &DeviceInfo) -> bool {
+        match *self {
+            Selector::Description(description) => info.description == description,
+            Selector::Serial(serial) => info.serial_number == serial,
+            Selector::Location(location) => info.location == location,
+            Selector::Index(i) => index == i,
+        }
+    }
+}
+
+/// One channel of an attached FTDI chip, found while enumerating the bus.
+struct Channel {
+    device: Device<Context>,
+    interface: u8,
+    info: DeviceInfo,
+}
+
+/// Work out the chip from the revision in `bcdDevice`, the same way libftdi
+/// does.
+fn device_type(bcd_device: u16, serial_number_index: Option<u8>) -> DeviceType {
+    match bcd_device {
+        // Early BM parts with no serial number report 0x200
+        0x0200 if serial_number_index.is_none() => DeviceType::FtBM,
+        0x0200 => DeviceType::FtAM,
+        0x0400 => DeviceType::FtBM,
+        0x0500 => DeviceType::Ft2232C,
+        0x0600 => DeviceType::Ft232R,
+        0x0700 => DeviceType::Ft2232H,
+        0x0800 => DeviceType::Ft4232H,
+        0x0900 => DeviceType::Ft232H,
+        0x1000 => DeviceType::FtXSeries,
+        x => DeviceType::Unknown(x as u32),
+    }
+}
+
+/// Turn a version rusb has decoded back into the BCD it was sent as, so
+/// that an FT-X part's 0x1000 comes back as 0x1000 rather than 0x0A00.
+fn bcd(version: Version) -> u16 {
+    let major = version.major() as u16;
+    (major / 10) << 12
+        | (major % 10) << 8
+        | (version.minor() as u16) << 4
+        | version.sub_minor() as u16
+}
+
+/// Build a location ID in the spirit of D2XX: the bus number followed by one
+/// nibble per hub port, then the channel number starting from 1.
+fn location(bus: u8, ports: &[u8], interface: u8) -> u32 {
+    let path = ports
+        .iter()
+        .fold(bus as u32, |loc, port| loc << 4 | (*port as u32 & 0xf));
+    path << 4 | (interface as u32 + 1)
+}
+
+/// The description and serial number of one channel.  D2XX appends the
+/// channel letter to those of multi-channel parts.
+fn channel_names(product: &str, serial: &str, interface_count: u8, number: u8) -> (String, String) {
+    if interface_count > 1 {
+        let letter = (b'A' + number) as char;
+        (
+            format!("{} {}", product, letter),
+            format!("{}{}", serial, letter),
+        )
+    } else {
+        (product.to_owned(), serial.to_owned())
+    }
+}
+
+/// Whether some other program has already claimed `interface`.  An interface
+/// bound to a kernel driver is available, since we are able to detach it.
+fn claimed_elsewhere(handle: &DeviceHandle<Context>, interface: u8) -> bool {
+    if let Ok(true) = handle.kernel_driver_active(interface) {
+        return false;
+    }
+    match handle.claim_interface(interface) {
+        Ok(()) => {
+            let _ = handle.release_interface(interface);
+            false
+        }
+        Err(rusb::Error::Busy) => true,
+        Err(_) => false,
+    }
+}
+
+fn channels(context: &Context) -> Result<Vec<Channel>, Error> {
+    let mut channels = vec![];
+    for device in context.devices()?.iter() {
+        let descriptor = device.device_descriptor()?;
+        if descriptor.vendor_id() != FTDI_VID || !FTDI_PIDS.contains(&descriptor.product_id()) {
+            continue;
+        }
+
+        // Devices we aren't allowed to open are still listed, but without
+        // their description or serial number.
+        let handle = device.open().ok();
+        let (product, serial) = match &handle {
+            Some(h) => (
+                h.read_product_string_ascii(&descriptor).unwrap_or_default(),
+                h.read_serial_number_string_ascii(&descriptor)
+                    .unwrap_or_default(),
+            ),
+            None => (String::new(), String::new()),
+        };
+        let high_speed = matches!(
+            device.speed(),
+            Speed::High | Speed::Super | Speed::SuperPlus
+        );
+        let bcd_device = bcd(descriptor.device_version());
+
+        let ports = device.port_numbers().unwrap_or_default();
+        let config = device.active_config_descriptor()?;
+        let interface_count = config.num_interfaces();
+        for interface in config.interfaces() {
+            let number = interface.number();
+            let (description, serial_number) =
+                channel_names(&product, &serial, interface_count, number);
+
+            let opened = match &handle {
+                Some(h) => claimed_elsewhere(h, number),
+                None => false,
+            };
+
+            channels.push(Channel {
+                device: device.clone(),
+                interface: number,
+                info: DeviceInfo {
+                    device_type: device_type(bcd_device, descriptor.serial_number_string_index()),
+                    vid: descriptor.vendor_id(),
+                    pid: descriptor.product_id(),
+                    location: location(device.bus_number(), &ports, number),
+                    serial_number,
+                    description,
+                    opened,
+                    high_speed,
+                },
+            });
+        }
+    }
+    Ok(channels)
+}
+
+pub fn list_devices() -> Result<Vec<DeviceInfo>, Error> {
+    let context = Context::new()?;
+    Ok(channels(&context)?.into_iter().map(|c| c.info).collect())
+}
+
+impl Handle {
+    /// Open the first FTDI interface that `selector` matches, detach any
+    /// kernel driver and claim it.
+    fn open(selector: Selector) -> Result<Handle, Error> {
+        let context = Context::new()?;
+        let (_, channel) = channels(&context)?
+            .into_iter()
+            .enumerate()
+            .find(|(index, c)| selector.matches(*index, &c.info))
+            .ok_or(Error::DeviceNotFound)?;
+
+        let device_handle = channel.device.open()?;
+        let config = channel.device.active_config_descriptor()?;
+        let interface = config
+            .interfaces()
+            .find(|i| i.number() == channel.interface)
+            .ok_or(Error::DeviceNotFound)?;
+        let setting = interface.descriptors().next().ok_or(Error::NotSupported)?;
+
+        let mut in_ep = None;
+        let mut out_ep = None;
+        let mut packet_size = 64;
+        for endpoint in setting.endpoint_descriptors() {
+            if endpoint.transfer_type() != TransferType::Bulk {
+                continue;
+            }
+            match endpoint.direction() {
+                Direction::In => {
+                    in_ep = Some(endpoint.address());
+                    packet_size = endpoint.max_packet_size() as usize;
+                }
+                Direction::Out => out_ep = Some(endpoint.address()),
+            }
+        }
+        let (in_ep, out_ep) = match (in_ep, out_ep) {
+            (Some(i), Some(o)) => (i, o),
+            _ => return Err(Error::NotSupported),
+        };
+
+        // Not every platform supports detaching the kernel driver.
+        let _ = device_handle.set_auto_detach_kernel_driver(true);
+        device_handle.claim_interface(channel.interface)?;
+
+        Ok(Handle {
+            device: device_handle,
+            interface: channel.interface,
+            in_ep,
+            out_ep,
+            packet_size,
+            rx: VecDeque::new(),
+        })
+    }
+
+    /// FTDI numbers its channels starting at 1 in the `wIndex` field.
+    fn index(&self) -> u16 {
+        self.interface as u16 + 1
+    }
+
+    fn control_out(&self, request: u8, value: u16) -> Result<(), Error> {
+        self.device.write_control(
+            FTDI_DEVICE_OUT_REQTYPE,
+            request,
+            value,
+            self.index(),
+            &[],
+            CONTROL_TIMEOUT,
+        )?;
+        Ok(())
+    }
+
+    fn control_in(&self, request: u8, value: u16) -> Result<u8, Error> {
+        let mut result = [0; 1];
+        let len = self.device.read_control(
+            FTDI_DEVICE_IN_REQTYPE,
+            request,
+            value,
+            self.index(),
+            &mut result,
+            CONTROL_TIMEOUT,
+        )?;
+        if len != result.len() {
+            return Err(Error::IoError);
+        }
+        Ok(result[0])
+    }
+
+    /// Perform a single bulk read and append the payload of every packet,
+    /// minus its modem status header, to the receive queue.
+    fn fill_rx(&mut self, timeout: Duration) -> std::io::Result<()> {
+        let mut buffer = vec![0; self.packet_size * 8];
+        let len = match self.device.read_bulk(self.in_ep, &mut buffer, timeout) {
+            Ok(len) => len,
+            Err(rusb::Error::Timeout) => return Ok(()),
+            Err(e) => return Err(io_error(e)),
+        };
+        for packet in buffer[..len].chunks(self.packet_size) {
+            if packet.len() > MODEM_STATUS_LEN {
+                self.rx.extend(&packet[MODEM_STATUS_LEN..]);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VCP {
+    fn open_matching(selector: Selector) -> Result<VCP, Error> {
+        let handle = Handle::open(selector)?;
+        let bit_mode = BitMode::from(handle.control_in(SIO_READ_PINS_REQUEST, 0)?);
+        Ok(VCP { handle, bit_mode })
+    }
+
+    pub fn new_from_name(name: &str) -> Result<VCP, Error> {
+        if name.contains('\0') {
+            return Err(Error::StringContainsNullByte);
+        }
+        VCP::open_matching(Selector::Description(name))
+    }
+
+    pub fn open_by_serial(serial: &str) -> Result<VCP, Error> {
+        if serial.contains('\0') {
+            return Err(Error::StringContainsNullByte);
+        }
+        VCP::open_matching(Selector::Serial(serial))
+    }
+
+    pub fn open_by_location(location: u32) -> Result<VCP, Error> {
+        VCP::open_matching(Selector::Location(location))
+    }
+
+    pub fn open_by_index(index: usize) -> Result<VCP, Error> {
+        VCP::open_matching(Selector::Index(index))
+    }
+
+    /// There are no COM ports outside of Windows.
+    pub fn com_port(&self) -> Result<usize, Error> {
+        Err(Error::NoComPortAssigned)
+    }
+
+    /// Set the given signals to "OUTPUT".  All other signals will be "INPUT".
+    pub fn set_bitmode(&mut self, outputs: u8, bitmode: BitMode) -> Result<(), Error> {
+        self.handle.control_out(
+            SIO_SET_BITMODE_REQUEST,
+            (bitmode.to_u8() as u16) << 8 | outputs as u16,
+        )?;
+        self.bit_mode = bitmode;
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.handle.control_out(SIO_RESET_REQUEST, SIO_RESET_SIO)?;
+        self.handle.rx.clear();
+        Ok(())
+    }
+
+    pub fn purge(&mut self) -> Result<(), Error> {
+        self.handle
+            .control_out(SIO_RESET_REQUEST, SIO_RESET_PURGE_RX)?;
+        self.handle
+            .control_out(SIO_RESET_REQUEST, SIO_RESET_PURGE_TX)?;
+        self.handle.rx.clear();
+        Ok(())
+    }
+
+    pub fn latency_timer(&mut self) -> Result<u8, Error> {
+        self.handle.control_in(SIO_GET_LATENCY_TIMER_REQUEST, 0)
+    }
+
+    pub fn set_latency_timer(&mut self, latency: u8) -> Result<(), Error> {
+        self.handle
+            .control_out(SIO_SET_LATENCY_TIMER_REQUEST, latency as u16)
+    }
+}
+
+impl Write for VCP {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.handle
+            .device
+            .write_bulk(self.handle.out_ep, buf, WRITE_TIMEOUT)
+            .map_err(io_error)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for VCP {
+    /// Like `FT_Read`, block until the whole buffer has been filled or the
+    /// read times out.
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let deadline = Instant::now() + READ_TIMEOUT;
+        while self.handle.rx.len() < buf.len() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            self.handle.fill_rx(deadline - now)?;
+        }
+
+        let count = buf.len().min(self.handle.rx.len());
+        if count == 0 && !buf.is_empty() {
+            return Err(io_error(rusb::Error::Timeout));
+        }
+        for (dest, src) in buf.iter_mut().zip(self.handle.rx.drain(..count)) {
+            *dest = src;
+        }
+        Ok(count)
+    }
+}
+
+impl Drop for VCP {
+    fn drop(&mut self) {
+        // Releasing the interface reattaches the kernel driver, if any.
+        let _ = self.handle.device.release_interface(self.handle.interface);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The channels of two iCEBreakers, as `channels` would list them
+    fn icebreakers() -> Vec<DeviceInfo> {
+        let mut devices = vec![];
+        for (serial, bus) in [("FT4ABC12", 1), ("FT4XYZ34", 3)].iter() {
+            for number in 0..2 {
+                let (description, serial_number) =
+                    channel_names("iCEBreaker V1.0e", serial, 2, number);
+                devices.push(DeviceInfo {
+                    device_type: device_type(0x0700, Some(3)),
+                    vid: FTDI_VID,
+                    pid: 0x6010,
+                    location: location(*bus, &[2], number),
+                    serial_number,
+                    description,
+                    opened: false,
+                    high_speed: true,
+                });
+            }
+        }
+        devices
+    }
+
+    fn find(devices: &[DeviceInfo], selector: Selector) -> Option<usize> {
+        devices
+            .iter()
+            .enumerate()
+            .find(|(index, info)| selector.matches(*index, info))
+            .map(|(index, _)| index)
+    }
+
+    #[test]
+    fn channels_are_named_like_d2xx() {
+        let devices = icebreakers();
+        assert_eq!(devices[1].description, "iCEBreaker V1.0e B");
+        assert_eq!(devices[1].serial_number, "FT4ABC12B");
+        assert_eq!(devices[3].location, 0x322);
+        assert_eq!(devices[0].device_type, DeviceType::Ft2232H);
+        assert_eq!(
+            channel_names("FT232R USB UART", "A50285BI", 1, 0),
+            ("FT232R USB UART".to_owned(), "A50285BI".to_owned())
+        );
+        assert_eq!(location(1, &[1, 4, 2], 0), 0x11421);
+        assert_eq!(device_type(0x0200, None), DeviceType::FtBM);
+        assert_eq!(device_type(0x0200, Some(3)), DeviceType::FtAM);
+        assert_eq!(device_type(0x1234, Some(3)), DeviceType::Unknown(0x1234));
+        for raw in [0x0200, 0x0700, 0x0900, 0x1000].iter() {
+            assert_eq!(bcd(Version::from_bcd(*raw)), *raw);
+        }
+        assert_eq!(
+            device_type(bcd(Version::from_bcd(0x1000)), Some(3)),
+            DeviceType::FtXSeries
+        );
+    }
+
+    #[test]
+    fn selectors_pick_the_first_match() {
+        let devices = icebreakers();
+        let description = Selector::Description("iCEBreaker V1.0e A");
+        assert_eq!(find(&devices, description), Some(0));
+        assert_eq!(find(&devices, Selector::Serial("FT4XYZ34B")), Some(3));
+        assert_eq!(find(&devices, Selector::Serial("FT4XYZ34")), None);
+        assert_eq!(find(&devices, Selector::Location(0x321)), Some(2));
+        assert_eq!(find(&devices, Selector::Location(0x121)), Some(0));
+        assert_eq!(find(&devices, Selector::Index(3)), Some(3));
+        assert_eq!(find(&devices, Selector::Index(4)), None);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::Error;
+use std::io::{Read, Write};
 
 // /* Transfer Command bits */
 
@@ -30,16 +32,13 @@
 //  *   |`-------- TMS mode enable
 //  *   `--------- Special command mode enable. See mpsse_cmd enum.
 //  */
-// pub enum CommandBits {
-
-//     #define MC_DATA_TMS  (0x40) /* When set use TMS mode */
-//     #define MC_DATA_IN   (0x20) /* When set read data (Data IN) */
-//     #define MC_DATA_OUT  (0x10) /* When set write data (Data OUT) */
-//     #define MC_DATA_LSB  (0x08) /* When set input/output data LSB first. */
-//  #define MC_DATA_ICN  (0x04) /* When set receive data on negative clock edge */
-//  #define MC_DATA_BITS (0x02) /* When set count bits not bytes */
-//  #define MC_DATA_OCN  (0x01) /* When set update data on negative clock edge */
-// }
+pub const MC_DATA_TMS: u8 = 0x40; /* When set use TMS mode */
+pub const MC_DATA_IN: u8 = 0x20; /* When set read data (Data IN) */
+pub const MC_DATA_OUT: u8 = 0x10; /* When set write data (Data OUT) */
+pub const MC_DATA_LSB: u8 = 0x08; /* When set input/output data LSB first. */
+pub const MC_DATA_ICN: u8 = 0x04; /* When set receive data on negative clock edge */
+pub const MC_DATA_BITS: u8 = 0x02; /* When set count bits not bytes */
+pub const MC_DATA_OCN: u8 = 0x01; /* When set update data on negative clock edge */
 
  /* MPSSE engine command definitions */
  #[allow(non_camel_case_types)]
@@ -164,4 +163,372 @@ impl Command {
             MC_CPU_WE => 0x93, /* CPUMode write extended address */
         }
     }
+
+    pub fn from_u8(src: u8) -> Option<Command> {
+        use Command::*;
+        Some(match src {
+            0x80 => MC_SETB_LOW,
+            0x81 => MC_READB_LOW,
+            0x82 => MC_SETB_HIGH,
+            0x83 => MC_READB_HIGH,
+            0x84 => MC_LOOPBACK_EN,
+            0x85 => MC_LOOPBACK_DIS,
+            0x86 => MC_SET_CLK_DIV,
+            0x87 => MC_FLUSH,
+            0x88 => MC_WAIT_H,
+            0x89 => MC_WAIT_L,
+            0x8A => MC_TCK_X5,
+            0x8B => MC_TCK_D5,
+            0x8C => MC_EN_3PH_CLK,
+            0x8D => MC_DIS_3PH_CLK,
+            0x8E => MC_CLK_N,
+            0x8F => MC_CLK_N8,
+            0x94 => MC_CLK_TO_H,
+            0x95 => MC_CLK_TO_L,
+            0x96 => MC_EN_ADPT_CLK,
+            0x97 => MC_DIS_ADPT_CLK,
+            0x9C => MC_CLK8_TO_H,
+            0x9D => MC_CLK8_TO_L,
+            0x9E => MC_TRI,
+            0x90 => MC_CPU_RS,
+            0x91 => MC_CPU_RE,
+            0x92 => MC_CPU_WS,
+            0x93 => MC_CPU_WE,
+            _ => return None,
+        })
+    }
+}
+
+/* The functions below encode MPSSE operations onto any byte stream, so the
+ * same code can drive a real device or an emulator. */
+
+/// The fastest SPI clock that doesn't exceed `hz`, as a choice of the /5
+/// prescaler, a clock divisor and the frequency they give.  The /5
+/// prescaler (12 MHz base clock) is preferred when it does as well as the
+/// 60 MHz base clock, since it also works on the original FT2232D.
+/// Requests outside the range the engine can produce get the nearest
+/// frequency it can.
+pub fn clock_divisor(hz: u32) -> Result<(bool, u16, u32), Error> {
+    if hz == 0 {
+        return Err(Error::InvalidArgs);
+    }
+
+    // The clock is base / ((1 + divisor) * 2)
+    let settings = |base: u64| {
+        let hz = hz as u64;
+        let divisor = (base / 2).div_ceil(hz).max(1) - 1;
+        let divisor = divisor.min(0xffff);
+        (divisor as u16, (base / ((1 + divisor) * 2)) as u32)
+    };
+    let (slow_divisor, slow_hz) = settings(12_000_000);
+    let (fast_divisor, fast_hz) = settings(60_000_000);
+
+    let divide_by_5 = match (slow_hz <= hz, fast_hz <= hz) {
+        (true, true) => slow_hz >= fast_hz,
+        // Below the slowest clock the engine can make, take the slowest
+        (slow_ok, fast_ok) => slow_ok || !fast_ok,
+    };
+    if divide_by_5 {
+        Ok((true, slow_divisor, slow_hz))
+    } else {
+        Ok((false, fast_divisor, fast_hz))
+    }
+}
+
+/// Set the SPI clock as close to `hz` as possible without going over,
+/// returning the frequency actually used.
+pub fn set_spi_frequency<T: Read + Write>(port: &mut T, hz: u32) -> Result<u32, Error> {
+    let (divide_by_5, divisor, actual) = clock_divisor(hz)?;
+    let prescaler = if divide_by_5 {
+        Command::MC_TCK_D5
+    } else {
+        Command::MC_TCK_X5
+    };
+    port.write_all(&[
+        prescaler.to_u8(),
+        Command::MC_SET_CLK_DIV.to_u8(),
+        divisor as u8,
+        (divisor >> 8) as u8,
+    ])
+    .map_err(|_| Error::IoError)?;
+    Ok(actual)
+}
+
+pub fn set_gpio_low<T: Read + Write>(port: &mut T, value: u8, direction: u8) -> Result<(), Error> {
+    port.write_all(&[Command::MC_SETB_LOW.to_u8(), value, direction])
+        .map_err(|_| Error::IoError)
+}
+
+pub fn set_gpio_high<T: Read + Write>(port: &mut T, value: u8, direction: u8) -> Result<(), Error> {
+    port.write_all(&[Command::MC_SETB_HIGH.to_u8(), value, direction])
+        .map_err(|_| Error::IoError)
+}
+
+pub fn read_gpio_low<T: Read + Write>(port: &mut T) -> Result<u8, Error> {
+    port.write_all(&[Command::MC_READB_LOW.to_u8()])
+        .map_err(|_| Error::IoError)?;
+    let mut result = [0; 1];
+    port.read_exact(&mut result).map_err(|_| Error::IoError)?;
+    Ok(result[0])
+}
+
+pub fn read_gpio_high<T: Read + Write>(port: &mut T) -> Result<u8, Error> {
+    port.write_all(&[Command::MC_READB_HIGH.to_u8()])
+        .map_err(|_| Error::IoError)?;
+    let mut result = [0; 1];
+    port.read_exact(&mut result).map_err(|_| Error::IoError)?;
+    Ok(result[0])
+}
+
+/// Clock out the top `bits` bits of `data`, MSB first, and return the bits
+/// that were clocked in.
+pub fn xfer_spi_bits<T: Read + Write>(port: &mut T, data: u8, bits: usize) -> Result<u8, Error> {
+    // The length byte holds bits - 1, and only up to a byte can be shifted
+    if !(1..=8).contains(&bits) {
+        return Err(Error::InvalidArgs);
+    }
+
+    let buffer = &[
+        /* Input and output, update data on negative edge read on positive, bits. */
+        MC_DATA_IN | MC_DATA_OUT | MC_DATA_OCN | MC_DATA_BITS,
+        bits as u8 - 1,
+        data,
+    ];
+    port.write_all(buffer).map_err(|_| Error::IoError)?;
+
+    let mut return_val = [0; 1];
+    port.read_exact(&mut return_val).map_err(|_| Error::IoError)?;
+    Ok(return_val[0])
+}
+
+/// The most data one byte shifting command can move
+pub const MAX_SHIFT: usize = 0x10000;
+
+/// How much data to clock in before reading it back.  The chip stops
+/// shifting once its buffer for the host is full (4 KiB on the FT2232H),
+/// and anything still to be written then backs up behind it, so the
+/// reply is collected in pieces no bigger than that.
+pub const READ_CHUNK: usize = 4096;
+
+/// Clock `data` out, replacing it with the bytes that were clocked in.
+/// Any length is fine; long transfers are split up with each piece read
+/// back before the next is sent.
+pub fn xfer_spi<T: Read + Write>(port: &mut T, data: &mut [u8]) -> Result<(), Error> {
+    let mut buffer = Vec::with_capacity(READ_CHUNK + 3);
+    for chunk in data.chunks_mut(READ_CHUNK) {
+        buffer.clear();
+        /* Input and output, update data on negative edge read on positive. */
+        encode_shift(&mut buffer, MC_DATA_IN | MC_DATA_OUT | MC_DATA_OCN, chunk);
+        port.write_all(&buffer).map_err(|_| Error::IoError)?;
+        port.read_exact(chunk).map_err(|_| Error::IoError)?;
+    }
+    Ok(())
+}
+
+/// How many `READ_CHUNK` requests `read_spi` keeps queued up ahead of the
+/// one it is reading back
+pub const READS_IN_FLIGHT: usize = 4;
+
+/// Clock `data.len()` bytes in without driving MOSI, for reading a
+/// stream such as the contents of a flash.  Requests for the next few
+/// chunks are sent before each one is read back, so the chip carries on
+/// clocking while the host collects the last reply.  A request that
+/// finds the chip's buffer full just waits for the host to catch up.
+pub fn read_spi<T: Read + Write>(port: &mut T, data: &mut [u8]) -> Result<(), Error> {
+    fn request<T: Write>(port: &mut T, len: usize) -> Result<(), Error> {
+        let len = len - 1;
+        /* Input only, read on positive edge, then send it straight back. */
+        port.write_all(&[
+            MC_DATA_IN,
+            len as u8,
+            (len >> 8) as u8,
+            Command::MC_FLUSH.to_u8(),
+        ])
+        .map_err(|_| Error::IoError)
+    }
+
+    let lens = data.chunks(READ_CHUNK).map(<[u8]>::len).collect::<Vec<_>>();
+    for len in lens.iter().take(READS_IN_FLIGHT) {
+        request(port, *len)?;
+    }
+    for (idx, chunk) in data.chunks_mut(READ_CHUNK).enumerate() {
+        if let Some(len) = lens.get(idx + READS_IN_FLIGHT) {
+            request(port, *len)?;
+        }
+        port.read_exact(chunk).map_err(|_| Error::IoError)?;
+    }
+    Ok(())
+}
+
+/// Clock `data` out, discarding whatever comes back.  Any length is fine.
+pub fn send_spi<T: Read + Write>(port: &mut T, data: &[u8]) -> Result<(), Error> {
+    let mut buffer = Vec::with_capacity(data.len().min(MAX_SHIFT) + 3);
+    for chunk in data.chunks(MAX_SHIFT) {
+        buffer.clear();
+        /* Output only, update data on negative edge. */
+        encode_shift(&mut buffer, MC_DATA_OUT | MC_DATA_OCN, chunk);
+        port.write_all(&buffer).map_err(|_| Error::IoError)?;
+    }
+    Ok(())
+}
+
+/// One step of a `Queue`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    SetGpioLow {
+        value: u8,
+        direction: u8,
+    },
+    SetGpioHigh {
+        value: u8,
+        direction: u8,
+    },
+    ReadGpioLow,
+    ReadGpioHigh,
+    /// Clock bytes out, discarding what comes back
+    Send(Vec<u8>),
+    /// Clock bytes out, keeping what comes back
+    Xfer(Vec<u8>),
+    /// Clock out the top `bits` bits of `data`
+    XferBits {
+        data: u8,
+        bits: usize,
+    },
+    /// Hold everything after this until GPIOL1 (ADBUS5) reaches a level
+    WaitGpiol1 {
+        high: bool,
+    },
+}
+
+/// A run of GPIO and SPI operations to be sent to the chip together.
+/// Each call that reads something returns where its bytes will be in the
+/// reply, so the caller can pick them out once the queue has run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Queue {
+    ops: Vec<Op>,
+    reply_len: usize,
+}
+
+impl Queue {
+    pub fn new() -> Queue {
+        Queue::default()
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// The number of bytes the queue reads back
+    pub fn reply_len(&self) -> usize {
+        self.reply_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn set_gpio_low(&mut self, value: u8, direction: u8) {
+        self.ops.push(Op::SetGpioLow { value, direction });
+    }
+
+    pub fn set_gpio_high(&mut self, value: u8, direction: u8) {
+        self.ops.push(Op::SetGpioHigh { value, direction });
+    }
+
+    pub fn read_gpio_low(&mut self) -> usize {
+        self.push_read(Op::ReadGpioLow, 1)
+    }
+
+    pub fn read_gpio_high(&mut self) -> usize {
+        self.push_read(Op::ReadGpioHigh, 1)
+    }
+
+    pub fn send_spi(&mut self, data: &[u8]) {
+        if !data.is_empty() {
+            self.ops.push(Op::Send(data.to_vec()));
+        }
+    }
+
+    pub fn xfer_spi(&mut self, data: &[u8]) -> usize {
+        self.push_read(Op::Xfer(data.to_vec()), data.len())
+    }
+
+    pub fn xfer_spi_bits(&mut self, data: u8, bits: usize) -> usize {
+        debug_assert!((1..=8).contains(&bits), "can't shift {} bits", bits);
+        self.push_read(Op::XferBits { data, bits }, 1)
+    }
+
+    /// Make the chip wait for GPIOL1 to reach the given level before
+    /// going on with the rest of the queue.
+    pub fn wait_gpiol1(&mut self, high: bool) {
+        self.ops.push(Op::WaitGpiol1 { high });
+    }
+
+    fn push_read(&mut self, op: Op, len: usize) -> usize {
+        let offset = self.reply_len;
+        if len > 0 {
+            self.ops.push(op);
+            self.reply_len += len;
+        }
+        offset
+    }
+
+    /// The MPSSE commands for the whole queue, ending with a flush so the
+    /// chip sends the reply straight back.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = vec![];
+        for op in &self.ops {
+            match op {
+                Op::SetGpioLow { value, direction } => {
+                    buffer.extend_from_slice(&[Command::MC_SETB_LOW.to_u8(), *value, *direction])
+                }
+                Op::SetGpioHigh { value, direction } => {
+                    buffer.extend_from_slice(&[Command::MC_SETB_HIGH.to_u8(), *value, *direction])
+                }
+                Op::ReadGpioLow => buffer.push(Command::MC_READB_LOW.to_u8()),
+                Op::ReadGpioHigh => buffer.push(Command::MC_READB_HIGH.to_u8()),
+                Op::Send(data) => encode_shift(&mut buffer, MC_DATA_OUT | MC_DATA_OCN, data),
+                Op::Xfer(data) => {
+                    encode_shift(&mut buffer, MC_DATA_IN | MC_DATA_OUT | MC_DATA_OCN, data)
+                }
+                Op::XferBits { data, bits } => buffer.extend_from_slice(&[
+                    MC_DATA_IN | MC_DATA_OUT | MC_DATA_OCN | MC_DATA_BITS,
+                    *bits as u8 - 1,
+                    *data,
+                ]),
+                Op::WaitGpiol1 { high: true } => buffer.push(Command::MC_WAIT_H.to_u8()),
+                Op::WaitGpiol1 { high: false } => buffer.push(Command::MC_WAIT_L.to_u8()),
+            }
+        }
+        buffer.push(Command::MC_FLUSH.to_u8());
+        buffer
+    }
+}
+
+/// Append byte shifting commands moving `data`, as many as it takes.
+/// The length is sent as one less than the byte count, LSB first.
+fn encode_shift(buffer: &mut Vec<u8>, command: u8, data: &[u8]) {
+    for chunk in data.chunks(MAX_SHIFT) {
+        let len = chunk.len() - 1;
+        buffer.extend_from_slice(&[command, len as u8, (len >> 8) as u8]);
+        buffer.extend_from_slice(chunk);
+    }
+}
+
+/// Run everything in `queue` with a single write, then collect the reply
+/// with a single read.  Nothing is read until it has all been sent, so a
+/// queue whose reply is bigger than `READ_CHUNK` could fill the chip's
+/// buffer and stall it; those are refused with `InvalidArgs`.
+pub fn submit<T: Read + Write>(port: &mut T, queue: &Queue) -> Result<Vec<u8>, Error> {
+    if queue.reply_len() > READ_CHUNK {
+        return Err(Error::InvalidArgs);
+    }
+    if queue.is_empty() {
+        return Ok(vec![]);
+    }
+    port.write_all(&queue.encode())
+        .map_err(|_| Error::IoError)?;
+    let mut reply = vec![0; queue.reply_len()];
+    port.read_exact(&mut reply).map_err(|_| Error::IoError)?;
+    Ok(reply)
 }
@@ -14,13 +14,13 @@ pub type FT_STATUS = ULONG;
 #[repr(C)]
 #[derive(Clone)]
 pub struct FT_DEVICE_LIST_INFO_NODE {
-    Flags: ULONG,
-    Type: ULONG,
-    ID: ULONG,
-    LocId: DWORD,
-    SerialNumber: [u8; 16],
-    Description: [u8; 64],
-    ftHandle: FT_HANDLE,
+    pub Flags: ULONG,
+    pub Type: ULONG,
+    pub ID: ULONG,
+    pub LocId: DWORD,
+    pub SerialNumber: [u8; 16],
+    pub Description: [u8; 64],
+    pub ftHandle: FT_HANDLE,
 }
 
 #[repr(C)]
@@ -48,11 +48,11 @@ pub enum FtStatus {
     FT_DEVICE_LIST_NOT_READY = 19,
 }
 
-// // Device information flags
-// enum FT_FLAGS {
-//     FT_FLAGS_OPENED = 1,
-//     FT_FLAGS_HISPEED = 2,
-// }
+//
+// Device information flags
+//
+pub const FT_FLAGS_OPENED: ULONG = 1;
+pub const FT_FLAGS_HISPEED: ULONG = 2;
 
 //
 // Purge rx and tx buffers
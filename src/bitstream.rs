@@ -0,0 +1,475 @@
+//! Parsing of iCE40 bitstreams, so that files can be checked before they
+//! are sent to the FPGA or written where it boots from.
+//!
+//! A bitstream is an optional comment block (`FF 00`, NUL-separated
+//! strings, `00 FF`), the preamble `7E AA 99 7E`, then a series of
+//! commands.  The high nibble of each command byte is the opcode and the
+//! low nibble the number of big-endian payload bytes that follow.
+
+use std::fmt;
+
+pub const PREAMBLE: [u8; 4] = [0x7E, 0xAA, 0x99, 0x7E];
+
+/* Opcodes */
+const CMD_SPECIAL: u8 = 0x0;
+const CMD_BANK_NUMBER: u8 = 0x1;
+const CMD_CRC_CHECK: u8 = 0x2;
+const CMD_BOOT_ADDRESS: u8 = 0x4;
+const CMD_OSCILLATOR: u8 = 0x5;
+const CMD_BANK_WIDTH: u8 = 0x6;
+const CMD_BANK_HEIGHT: u8 = 0x7;
+const CMD_BANK_OFFSET: u8 = 0x8;
+const CMD_FEATURES: u8 = 0x9;
+
+/* Payloads of CMD_SPECIAL */
+const CRAM_DATA: u32 = 0x01;
+const BRAM_DATA: u32 = 0x03;
+const RESET_CRC: u32 = 0x05;
+const WAKEUP: u32 = 0x06;
+const REBOOT: u32 = 0x08;
+
+/// CMD_FEATURES bit that lets the design use SB_WARMBOOT
+const FEATURE_WARMBOOT: u32 = 1 << 5;
+
+/// How far into a file the preamble may be before it isn't a bitstream
+const MAX_COMMENT: usize = 4096;
+
+/// The iCE40 dies, told apart by the size of their CRAM banks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fpga {
+    Hx1k,
+    Up5k,
+    Hx8k,
+}
+
+impl Fpga {
+    /// CRAM bank width and height, in bits
+    fn cram_size(self) -> (usize, usize) {
+        match self {
+            Fpga::Hx1k => (332, 144),
+            Fpga::Up5k => (692, 256),
+            Fpga::Hx8k => (872, 272),
+        }
+    }
+
+    fn from_cram_size(size: (usize, usize)) -> Option<Fpga> {
+        [Fpga::Hx1k, Fpga::Up5k, Fpga::Hx8k]
+            .iter()
+            .copied()
+            .find(|fpga| fpga.cram_size() == size)
+    }
+
+    /// Accepts part names such as `up5k`, `hx8k` or `iCE40LP1K`.
+    pub fn from_name(name: &str) -> Option<Fpga> {
+        let name = name.to_lowercase();
+        let name = name.trim_start_matches("ice40").trim_start_matches('-');
+        match name {
+            "1k" | "hx1k" | "lp1k" => Some(Fpga::Hx1k),
+            "5k" | "up5k" => Some(Fpga::Up5k),
+            "8k" | "hx8k" | "lp8k" => Some(Fpga::Hx8k),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Fpga::Hx1k => "iCE40 HX1K/LP1K",
+            Fpga::Up5k => "iCE40 UP5K",
+            Fpga::Hx8k => "iCE40 HX8K/LP8K",
+        }
+    }
+}
+
+/// Why a file couldn't be parsed as a bitstream
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    /// There's no preamble near the start, so this isn't a bitstream
+    NoPreamble,
+
+    /// The file ends part way through a command
+    Truncated { offset: usize },
+
+    /// The file ends without a wakeup or reboot command
+    NoWakeup,
+
+    /// A command that isn't part of the format
+    UnknownCommand { offset: usize, command: u8 },
+
+    /// A data block came before the bank it belongs to was described
+    NoBankSize { offset: usize },
+
+    /// A CRC check command didn't match the data before it
+    Crc { offset: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::NoPreamble => write!(f, "not an iCE40 bitstream, there is no preamble"),
+            ParseError::Truncated { offset } => {
+                write!(f, "bitstream is cut short at 0x{:X}", offset)
+            }
+            ParseError::NoWakeup => write!(f, "bitstream has no wakeup command"),
+            ParseError::UnknownCommand { offset, command } => {
+                write!(f, "unknown command 0x{:02X} at 0x{:X}", command, offset)
+            }
+            ParseError::NoBankSize { offset } => {
+                write!(f, "data at 0x{:X} comes before the bank size", offset)
+            }
+            ParseError::Crc { offset } => write!(f, "CRC check at 0x{:X} failed", offset),
+        }
+    }
+}
+
+/// What was learned from a bitstream's header and commands.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bitstream {
+    /// Strings from the comment block, such as the tool that made it
+    pub comments: Vec<String>,
+
+    /// The part the CRAM banks are sized for, if it's one we know
+    pub fpga: Option<Fpga>,
+
+    /// CRAM bank width and height in bits, from which `fpga` was found
+    pub cram_size: Option<(usize, usize)>,
+
+    /// Bytes up to and including the wakeup or reboot command
+    pub len: usize,
+
+    pub cram_bytes: usize,
+    pub bram_bytes: usize,
+
+    /// How many CRC check commands there were, all of which passed
+    pub crc_checks: usize,
+
+    /// Whether the design may use SB_WARMBOOT to load another image
+    pub warmboot: bool,
+
+    /// Set by the commands in a multiboot header, which tell the FPGA
+    /// to load the image at this flash address
+    pub boot_address: Option<usize>,
+}
+
+/// CRC-16/CCITT, as the iCE40 computes over the commands.
+fn crc16(mut crc: u16, byte: u8) -> u16 {
+    crc ^= (byte as u16) << 8;
+    for _ in 0..8 {
+        crc = if crc & 0x8000 != 0 {
+            (crc << 1) ^ 0x1021
+        } else {
+            crc << 1
+        };
+    }
+    crc
+}
+
+impl Bitstream {
+    /// Find where the preamble is, reading the comment block before it.
+    fn header(data: &[u8]) -> Result<(Vec<String>, usize), ParseError> {
+        let mut comments = vec![];
+        let mut pos = 0;
+        if data.starts_with(&[0xFF, 0x00]) {
+            let end = data[2..]
+                .windows(2)
+                .take(MAX_COMMENT)
+                .position(|w| w == [0x00, 0xFF])
+                .ok_or(ParseError::NoPreamble)?;
+            comments = data[2..end + 2]
+                .split(|b| *b == 0)
+                .filter(|s| !s.is_empty())
+                .map(|s| String::from_utf8_lossy(s).into_owned())
+                .collect();
+            pos = end + 4;
+        }
+        // Padding is allowed ahead of the preamble
+        while pos < data.len().min(MAX_COMMENT) && data[pos] == 0xFF {
+            pos += 1;
+        }
+        if !data[pos..].starts_with(&PREAMBLE) {
+            return Err(ParseError::NoPreamble);
+        }
+        Ok((comments, pos + PREAMBLE.len()))
+    }
+
+    /// Work through the commands up to the wakeup or reboot, checking
+    /// each CRC along the way.
+    pub fn parse(data: &[u8]) -> Result<Bitstream, ParseError> {
+        let (comments, mut pos) = Bitstream::header(data)?;
+        let mut bitstream = Bitstream {
+            comments,
+            ..Bitstream::default()
+        };
+
+        let mut crc = 0xFFFF;
+        let mut width = None;
+        let mut height = None;
+        loop {
+            let offset = pos;
+            let command = *data.get(pos).ok_or(ParseError::NoWakeup)?;
+            let payload_len = (command & 0x0F) as usize;
+            let payload = data
+                .get(pos + 1..pos + 1 + payload_len)
+                .ok_or(ParseError::Truncated { offset })?;
+            for byte in &data[pos..pos + 1 + payload_len] {
+                crc = crc16(crc, *byte);
+            }
+            pos += 1 + payload_len;
+            let value = payload
+                .iter()
+                .fold(0u32, |value, byte| value << 8 | *byte as u32);
+
+            match command >> 4 {
+                // A lone zero byte is padding, as follows each data block
+                CMD_SPECIAL if payload_len == 0 => (),
+                CMD_SPECIAL => match value {
+                    CRAM_DATA | BRAM_DATA => {
+                        let size = match (width, height) {
+                            (Some(width), Some(height)) => width * height / 8,
+                            _ => return Err(ParseError::NoBankSize { offset }),
+                        };
+                        let block = data
+                            .get(pos..pos + size)
+                            .ok_or(ParseError::Truncated { offset })?;
+                        for byte in block {
+                            crc = crc16(crc, *byte);
+                        }
+                        pos += size;
+                        if value == CRAM_DATA {
+                            bitstream.cram_bytes += size;
+                            bitstream.cram_size = bitstream.cram_size.or(width.zip(height));
+                        } else {
+                            bitstream.bram_bytes += size;
+                        }
+                    }
+                    RESET_CRC => crc = 0xFFFF,
+                    WAKEUP | REBOOT => break,
+                    _ => return Err(ParseError::UnknownCommand { offset, command }),
+                },
+                // Feeding in the expected CRC leaves zero if it matched
+                CMD_CRC_CHECK => {
+                    if crc != 0 {
+                        return Err(ParseError::Crc { offset });
+                    }
+                    bitstream.crc_checks += 1;
+                }
+                // The first payload byte is the SPI read opcode to use
+                CMD_BOOT_ADDRESS => bitstream.boot_address = Some((value & 0xFF_FFFF) as usize),
+                CMD_BANK_WIDTH => width = Some(value as usize + 1),
+                CMD_BANK_HEIGHT => height = Some(value as usize),
+                CMD_BANK_NUMBER | CMD_OSCILLATOR | CMD_BANK_OFFSET => (),
+                CMD_FEATURES => bitstream.warmboot = value & FEATURE_WARMBOOT != 0,
+                _ => return Err(ParseError::UnknownCommand { offset, command }),
+            }
+        }
+
+        bitstream.len = pos;
+        bitstream.fpga = bitstream.cram_size.and_then(Fpga::from_cram_size);
+        Ok(bitstream)
+    }
+
+    /// A multiboot header carries no configuration, just a boot address.
+    pub fn is_multiboot_header(&self) -> bool {
+        self.boot_address.is_some() && self.cram_bytes == 0
+    }
+}
+
+impl fmt::Display for Bitstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(address) = self.boot_address.filter(|_| self.is_multiboot_header()) {
+            return write!(f, "multiboot header, booting image at 0x{:06X}", address);
+        }
+        match (self.fpga, self.cram_size) {
+            (Some(fpga), _) => write!(f, "{} bitstream", fpga.name())?,
+            (None, Some((width, height))) => write!(
+                f,
+                "bitstream for an unknown part ({}x{} CRAM)",
+                width, height
+            )?,
+            (None, None) => write!(f, "bitstream with no CRAM data")?,
+        }
+        write!(
+            f,
+            ", {} bytes ({} CRAM, {} BRAM), {} CRC checks",
+            self.len, self.cram_bytes, self.bram_bytes, self.crc_checks
+        )?;
+        if self.warmboot {
+            write!(f, ", warmboot enabled")?;
+        }
+        for comment in &self.comments {
+            write!(f, "\n  {}", comment)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Assembles bitstreams, keeping track of the CRC like icepack does.
+    pub struct Builder {
+        pub data: Vec<u8>,
+        crc: u16,
+    }
+
+    impl Builder {
+        pub fn new(comment: &str) -> Builder {
+            let mut data = vec![0xFF, 0x00];
+            data.extend_from_slice(comment.as_bytes());
+            data.extend_from_slice(&[0x00, 0x00, 0xFF]);
+            data.extend_from_slice(&PREAMBLE);
+            Builder { data, crc: 0xFFFF }
+        }
+
+        pub fn bytes(&mut self, bytes: &[u8]) -> &mut Builder {
+            for byte in bytes {
+                self.crc = crc16(self.crc, *byte);
+            }
+            self.data.extend_from_slice(bytes);
+            self
+        }
+
+        pub fn reset_crc(&mut self) -> &mut Builder {
+            self.bytes(&[0x01, 0x05]);
+            self.crc = 0xFFFF;
+            self
+        }
+
+        pub fn crc_check(&mut self) -> &mut Builder {
+            self.bytes(&[0x22]);
+            let crc = self.crc;
+            self.bytes(&crc.to_be_bytes())
+        }
+
+        /// One bank of CRAM in the layout of `fpga`
+        pub fn cram(&mut self, fpga: Fpga, bank: u8) -> &mut Builder {
+            let (width, height) = fpga.cram_size();
+            let size = width * height / 8;
+            self.bytes(&[0x11, bank])
+                .bytes(&[0x62])
+                .bytes(&((width - 1) as u16).to_be_bytes())
+                .bytes(&[0x72])
+                .bytes(&(height as u16).to_be_bytes())
+                .bytes(&[0x82, 0x00, 0x00])
+                .bytes(&[0x01, 0x01])
+                .bytes(&vec![0xA5; size])
+                .bytes(&[0x00, 0x00])
+        }
+
+        pub fn wakeup(&mut self) -> Vec<u8> {
+            self.bytes(&[0x01, 0x06]);
+            self.data.clone()
+        }
+    }
+
+    /// A bitstream laid out like the ones icepack writes.
+    pub fn bitstream(fpga: Fpga) -> Vec<u8> {
+        let mut builder = Builder::new("Lattice iCE40 test");
+        builder
+            .reset_crc()
+            .bytes(&[0x51, 0x00])
+            .bytes(&[0x92, 0x00, 0x20]);
+        for bank in 0..4 {
+            builder.cram(fpga, bank);
+        }
+        builder.crc_check().wakeup()
+    }
+
+    #[test]
+    fn parse_bitstream() {
+        let data = bitstream(Fpga::Up5k);
+        let bitstream = Bitstream::parse(&data).unwrap();
+        assert_eq!(bitstream.fpga, Some(Fpga::Up5k));
+        assert_eq!(bitstream.comments, vec!["Lattice iCE40 test".to_owned()]);
+        assert_eq!(bitstream.len, data.len());
+        assert_eq!(bitstream.cram_bytes, 4 * 692 * 256 / 8);
+        assert_eq!(bitstream.crc_checks, 1);
+        assert!(bitstream.warmboot);
+        assert!(!bitstream.is_multiboot_header());
+
+        let mut corrupt = data.clone();
+        corrupt[100] ^= 1;
+        assert!(matches!(
+            Bitstream::parse(&corrupt),
+            Err(ParseError::Crc { .. })
+        ));
+        assert!(matches!(
+            Bitstream::parse(&data[..1000]),
+            Err(ParseError::Truncated { .. })
+        ));
+        assert_eq!(
+            Bitstream::parse(b"int main() { return 0; }"),
+            Err(ParseError::NoPreamble)
+        );
+        assert_eq!(Bitstream::parse(&[0xFF; 64]), Err(ParseError::NoPreamble));
+    }
+
+    /// The opening of real bitstreams for each die: the comment block, the
+    /// global settings and the commands that start CRAM bank 0. The 1k one
+    /// is from iCEcube2, the others from nextpnr and icepack.
+    const FIXTURES: [(Fpga, &[u8], &str); 3] = [
+        (
+            Fpga::Hx1k,
+            b"\xFF\x00Lattice\x00iCEcube2 2014.08.26723\x00Part: iCE40HX1K-TQ144\x00\
+              Date: Nov 7 2014 17:49:10\x00\x00\xFF\x7E\xAA\x99\x7E\x51\x00\x01\x05\
+              \x92\x00\x20\x62\x01\x4B\x72\x00\x90\x82\x00\x00\x11\x00\x01\x01",
+            "Lattice",
+        ),
+        (
+            Fpga::Up5k,
+            b"\xFF\x00from next-pnr\x00\x00\xFF\x7E\xAA\x99\x7E\x51\x00\x01\x05\
+              \x92\x00\x20\x62\x02\xB3\x72\x01\x00\x82\x00\x00\x11\x00\x01\x01",
+            "from next-pnr",
+        ),
+        (
+            Fpga::Hx8k,
+            b"\xFF\x00from next-pnr\x00\x00\xFF\x7E\xAA\x99\x7E\x51\x00\x01\x05\
+              \x92\x00\x20\x62\x03\x67\x72\x01\x10\x82\x00\x00\x11\x00\x01\x01",
+            "from next-pnr",
+        ),
+    ];
+
+    #[test]
+    fn real_headers() {
+        for (fpga, head, comment) in FIXTURES.iter() {
+            // Everything up to the first block of CRAM data decodes
+            assert_eq!(
+                Bitstream::parse(head),
+                Err(ParseError::Truncated {
+                    offset: head.len() - 2
+                })
+            );
+
+            // Fill in the rest so the settings can be checked as a whole
+            let reset = head.windows(2).rposition(|w| w == [0x01, 0x05]).unwrap();
+            let (width, height) = fpga.cram_size();
+            let mut builder = Builder {
+                data: head[..reset].to_vec(),
+                crc: 0xFFFF,
+            };
+            builder
+                .reset_crc()
+                .bytes(&head[reset + 2..])
+                .bytes(&vec![0; width * height / 8])
+                .bytes(&[0x00, 0x00]);
+            for bank in 1..4 {
+                builder.cram(*fpga, bank);
+            }
+            let data = builder.crc_check().wakeup();
+
+            let bitstream = Bitstream::parse(&data).unwrap();
+            assert_eq!(bitstream.fpga, Some(*fpga));
+            assert_eq!(bitstream.cram_size, Some((width, height)));
+            assert_eq!(bitstream.comments[0], *comment);
+            assert!(bitstream.warmboot);
+        }
+    }
+
+    #[test]
+    fn fpga_names() {
+        assert_eq!(Fpga::from_name("up5k"), Some(Fpga::Up5k));
+        assert_eq!(Fpga::from_name("iCE40HX8K"), Some(Fpga::Hx8k));
+        assert_eq!(Fpga::from_name("iCE40-LP1K"), Some(Fpga::Hx1k));
+        assert_eq!(Fpga::from_name("ecp5"), None);
+    }
+}
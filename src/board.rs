@@ -0,0 +1,477 @@
+//! Board profiles describe how an FTDI part is wired to the FPGA and its
+//! flash: which interface to open, which GPIO lines drive chip select and
+//! CRESET, and which one reads CDONE.  A few common boards are built in,
+//! and others can be described in a TOML file:
+//!
+//! ```toml
+//! name = "My board"
+//! description = "Dual RS232-HS"   # USB description of the FTDI part
+//! channel = "B"                   # for parts with more than one interface
+//! frequency = "10M"
+//! flash_size = "4M"
+//! fpga = "hx8k"
+//!
+//! [pins]
+//! cs = "ADBUS4"
+//! creset = "ACBUS7"
+//! cdone = "ACBUS6"
+//! ready = "ADBUS5"                # optional, high while the flash is idle
+//! inverted = ["creset"]           # lines that pass through an inverter
+//! ```
+
+use crate::bitstream::Fpga;
+use crate::cli::{parse_frequency, parse_size, DEFAULT_FREQUENCY};
+use crate::error::Error;
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+
+/// The two bytes of GPIO an MPSSE engine has
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bank {
+    /// ADBUS, which also carries the SPI lines
+    Low,
+    /// ACBUS
+    High,
+}
+
+/// One GPIO line.  Chip select and CRESET are normally active low and
+/// CDONE high when configured; `inverted` flips that for boards that put
+/// a transistor or inverter in the way.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pin {
+    pub bank: Bank,
+    pub bit: u8,
+    pub inverted: bool,
+}
+
+impl Pin {
+    const fn low(bit: u8) -> Pin {
+        Pin {
+            bank: Bank::Low,
+            bit,
+            inverted: false,
+        }
+    }
+
+    const fn high(bit: u8) -> Pin {
+        Pin {
+            bank: Bank::High,
+            bit,
+            inverted: false,
+        }
+    }
+
+    pub fn mask(self) -> u8 {
+        1 << self.bit
+    }
+
+    /// Accepts `ADBUS0`-`ADBUS7` and `ACBUS0`-`ACBUS7`, or `AD4`-style
+    /// short names.
+    pub fn parse(name: &str) -> Option<Pin> {
+        let name = name.trim().to_uppercase();
+        let (bank, bit) = if let Some(bit) = name.strip_prefix("ADBUS") {
+            (Bank::Low, bit)
+        } else if let Some(bit) = name.strip_prefix("ACBUS") {
+            (Bank::High, bit)
+        } else if let Some(bit) = name.strip_prefix("AD") {
+            (Bank::Low, bit)
+        } else if let Some(bit) = name.strip_prefix("AC") {
+            (Bank::High, bit)
+        } else {
+            return None;
+        };
+        match bit.parse::<u8>() {
+            Ok(bit) if bit < 8 => Some(Pin {
+                bank,
+                bit,
+                inverted: false,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bank = match self.bank {
+            Bank::Low => "ADBUS",
+            Bank::High => "ACBUS",
+        };
+        write!(f, "{}{}", bank, self.bit)?;
+        if self.inverted {
+            write!(f, " (inverted)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The lines used to talk to the FPGA and its flash, besides SPI itself
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pins {
+    pub cs: Pin,
+    pub creset: Pin,
+    pub cdone: Pin,
+
+    /// A line that is high once the flash has finished programming or
+    /// erasing, which needs a part with a dedicated RY/BY# output wired to
+    /// it.  The MPSSE engine can only wait on GPIOL1, so this is always
+    /// ADBUS5.
+    pub ready: Option<Pin>,
+}
+
+/// The one line an MPSSE engine can wait on
+pub const GPIOL1: Pin = Pin::low(5);
+
+/// The wiring iceprog expects, used by the iCEBreaker and iCEstick
+impl Default for Pins {
+    fn default() -> Self {
+        Pins {
+            cs: Pin::low(4),
+            creset: Pin::low(7),
+            cdone: Pin::low(6),
+            ready: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoardProfile {
+    pub name: String,
+
+    /// USB description of the FTDI part, without the channel suffix
+    pub description: String,
+
+    /// Which interface of a multi-channel part to use, such as `A`
+    pub channel: Option<char>,
+
+    pub pins: Pins,
+
+    /// SPI clock to use unless `--freq` or `--slow` says otherwise
+    pub frequency: u32,
+
+    /// Size of the flash, for parts that can't be identified
+    pub flash_size: Option<usize>,
+
+    /// The FPGA fitted, which bitstreams are checked against
+    pub fpga: Option<Fpga>,
+}
+
+const MB: usize = 1024 * 1024;
+
+/* Names of the built-in profiles, for `--help` and error messages */
+pub const BUILTIN: &[&str] = &["icebreaker", "icestick", "upduino", "tinyfpga", "ft2232h"];
+
+/// What a TOML board file may contain
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BoardFile {
+    name: Option<String>,
+    description: String,
+    channel: Option<char>,
+    frequency: Option<String>,
+    flash_size: Option<String>,
+    fpga: Option<String>,
+    pins: PinsFile,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PinsFile {
+    cs: String,
+    creset: String,
+    cdone: String,
+    ready: Option<String>,
+    #[serde(default)]
+    inverted: Vec<String>,
+}
+
+impl BoardProfile {
+    fn new(name: &str, description: &str, channel: Option<char>, pins: Pins) -> BoardProfile {
+        BoardProfile {
+            name: name.to_owned(),
+            description: description.to_owned(),
+            channel,
+            pins,
+            frequency: DEFAULT_FREQUENCY,
+            flash_size: None,
+            fpga: None,
+        }
+    }
+
+    /// Look up one of the profiles in `BUILTIN`.
+    pub fn builtin(name: &str) -> Option<BoardProfile> {
+        let board = match name.to_lowercase().as_str() {
+            "icebreaker" => BoardProfile {
+                flash_size: Some(16 * MB),
+                fpga: Some(Fpga::Up5k),
+                ..BoardProfile::new("iCEBreaker", "iCEBreaker V1.0e", Some('A'), Pins::default())
+            },
+            "icestick" => BoardProfile {
+                flash_size: Some(4 * MB),
+                fpga: Some(Fpga::Hx1k),
+                ..BoardProfile::new("iCEstick", "Dual RS232-HS", Some('A'), Pins::default())
+            },
+            "upduino" => BoardProfile {
+                flash_size: Some(4 * MB),
+                fpga: Some(Fpga::Up5k),
+                ..BoardProfile::new("UPduino", "Single RS232-HS", None, Pins::default())
+            },
+            // An FT232H breakout wired to a TinyFPGA-style programming
+            // header, with chip select on the usual MPSSE line
+            "tinyfpga" => BoardProfile {
+                flash_size: Some(MB),
+                fpga: Some(Fpga::Hx8k),
+                ..BoardProfile::new(
+                    "TinyFPGA adapter",
+                    "Single RS232-HS",
+                    None,
+                    Pins {
+                        cs: Pin::low(3),
+                        creset: Pin::low(4),
+                        cdone: Pin::low(5),
+                        ready: None,
+                    },
+                )
+            },
+            // Interface A of a bare FT2232H module, leaving ADBUS4-7 free
+            "ft2232h" => BoardProfile::new(
+                "FT2232H breakout",
+                "Dual RS232-HS",
+                Some('A'),
+                Pins {
+                    cs: Pin::low(3),
+                    creset: Pin::high(0),
+                    cdone: Pin::high(1),
+                    ready: None,
+                },
+            ),
+            _ => return None,
+        };
+        Some(board)
+    }
+
+    pub fn from_toml(text: &str) -> Result<BoardProfile, String> {
+        let file: BoardFile = toml::from_str(text).map_err(|e| e.to_string())?;
+        let pin = |name: &str, value: &str| {
+            let mut pin =
+                Pin::parse(value).ok_or(format!("{} pin \"{}\" is unknown", name, value))?;
+            pin.inverted = file.pins.inverted.iter().any(|inverted| inverted == name);
+            Ok::<_, String>(pin)
+        };
+        if let Some(name) = file
+            .pins
+            .inverted
+            .iter()
+            .find(|name| !["cs", "creset", "cdone", "ready"].contains(&name.as_str()))
+        {
+            return Err(format!("can't invert unknown pin \"{}\"", name));
+        }
+        let pins = Pins {
+            cs: pin("cs", &file.pins.cs)?,
+            creset: pin("creset", &file.pins.creset)?,
+            cdone: pin("cdone", &file.pins.cdone)?,
+            ready: match &file.pins.ready {
+                Some(value) => Some(pin("ready", value)?),
+                None => None,
+            },
+        };
+        if let Some(ready) = pins.ready {
+            if ready.bank != GPIOL1.bank || ready.bit != GPIOL1.bit {
+                return Err(format!(
+                    "ready must be {}, the only line the adapter can wait on",
+                    GPIOL1
+                ));
+            }
+        }
+
+        let frequency = match &file.frequency {
+            Some(value) => parse_frequency(value).map_err(|e| format!("frequency: {}", e))?,
+            None => DEFAULT_FREQUENCY,
+        };
+        let flash_size = match &file.flash_size {
+            Some(value) => Some(parse_size(value).map_err(|e| format!("flash_size: {}", e))?),
+            None => None,
+        };
+        let fpga = match &file.fpga {
+            Some(value) => {
+                Some(Fpga::from_name(value).ok_or(format!("unknown FPGA \"{}\"", value))?)
+            }
+            None => None,
+        };
+        let description = file.description;
+        Ok(BoardProfile {
+            name: file.name.unwrap_or_else(|| description.clone()),
+            description,
+            channel: file.channel,
+            pins,
+            frequency,
+            flash_size,
+            fpga,
+        })
+    }
+
+    /// A built-in profile by name, or a TOML file by path.
+    pub fn load(name: &str) -> Result<BoardProfile, Error> {
+        if let Some(board) = BoardProfile::builtin(name) {
+            return Ok(board);
+        }
+        if !name.ends_with(".toml") && !Path::new(name).exists() {
+            return Err(Error::Argument(format!(
+                "unknown board \"{}\", try one of {} or a TOML file",
+                name,
+                BUILTIN.join(", ")
+            )));
+        }
+        let text = std::fs::read_to_string(name).map_err(|e| Error::file(name, e))?;
+        BoardProfile::from_toml(&text).map_err(|e| Error::Argument(format!("{}: {}", name, e)))
+    }
+
+    /// The `-d` selector that opens this board's FTDI interface
+    pub fn device(&self) -> String {
+        match self.channel {
+            Some(channel) => format!("d:{} {}", self.description, channel),
+            None => format!("d:{}", self.description),
+        }
+    }
+}
+
+impl fmt::Display for BoardProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: CS {}, CRESET {}, CDONE {}",
+            self.name, self.pins.cs, self.pins.creset, self.pins.cdone
+        )?;
+        if let Some(ready) = self.pins.ready {
+            write!(f, ", ready {}", ready)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::Flash;
+    use ftdi_vcp_rs::emulator::{Emulator, Event};
+
+    #[test]
+    fn builtin_boards() {
+        for name in BUILTIN {
+            assert!(BoardProfile::builtin(name).is_some(), "{}", name);
+        }
+        let board = BoardProfile::load("iCEBreaker").unwrap();
+        assert_eq!(board.device(), "d:iCEBreaker V1.0e A");
+        assert_eq!(board.fpga, Some(Fpga::Up5k));
+        assert_eq!(
+            BoardProfile::builtin("upduino").unwrap().device(),
+            "d:Single RS232-HS"
+        );
+        assert!(BoardProfile::load("no-such-board").is_err());
+    }
+
+    #[test]
+    fn board_file() {
+        let board = BoardProfile::from_toml(
+            r#"
+            description = "Dual RS232-HS"
+            channel = "B"
+            frequency = "10M"
+            flash_size = "4M"
+            fpga = "hx8k"
+
+            [pins]
+            cs = "ADBUS4"
+            creset = "ACBUS7"
+            cdone = "ac6"
+            inverted = ["creset"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(board.device(), "d:Dual RS232-HS B");
+        assert_eq!(board.frequency, 10_000_000);
+        assert_eq!(board.flash_size, Some(4 * MB));
+        assert_eq!(board.fpga, Some(Fpga::Hx8k));
+        assert_eq!(
+            board.pins,
+            Pins {
+                cs: Pin::low(4),
+                creset: Pin {
+                    inverted: true,
+                    ..Pin::high(7)
+                },
+                cdone: Pin::high(6),
+                ready: None,
+            }
+        );
+
+        let pins = "[pins]\ncs = \"AD4\"\ncreset = \"AD7\"\ncdone = \"AD6\"\n";
+        assert!(BoardProfile::from_toml(&format!("description = \"x\"\n{}", pins)).is_ok());
+        assert!(
+            BoardProfile::from_toml(&format!("description = \"x\"\ncolour = 1\n{}", pins)).is_err()
+        );
+        assert!(BoardProfile::from_toml(&pins.replace("AD4", "AD9")).is_err());
+        assert!(BoardProfile::from_toml(&format!(
+            "description = \"x\"\n{}inverted = [\"sck\"]\n",
+            pins
+        ))
+        .is_err());
+
+        let ready = |pin: &str| {
+            BoardProfile::from_toml(&format!(
+                "description = \"x\"\n{}ready = \"{}\"\ninverted = [\"ready\"]\n",
+                pins, pin
+            ))
+        };
+        assert_eq!(
+            ready("AD5").unwrap().pins.ready,
+            Some(Pin {
+                inverted: true,
+                ..GPIOL1
+            })
+        );
+        assert!(ready("AD3").is_err());
+    }
+
+    #[test]
+    fn pins_follow_the_board() {
+        let board = BoardProfile::builtin("ft2232h").unwrap();
+        let mut flash = Flash::new(Emulator::new(()));
+        flash.set_pins(board.pins);
+        flash.chip_select().unwrap();
+        flash.release_reset().unwrap();
+        assert_eq!(
+            flash.transport.take_events(),
+            vec![
+                Event::SetLow {
+                    value: 0x00,
+                    direction: 0x0B
+                },
+                Event::SetHigh {
+                    value: 0x00,
+                    direction: 0x01
+                },
+                Event::SetLow {
+                    value: 0x08,
+                    direction: 0x0B
+                },
+                Event::SetHigh {
+                    value: 0x01,
+                    direction: 0x01
+                },
+            ]
+        );
+
+        // Nothing drives ACBUS1, so it floats high
+        assert!(flash.cdone().unwrap());
+        flash.set_pins(Pins {
+            cdone: Pin {
+                inverted: true,
+                ..Pin::high(1)
+            },
+            ..board.pins
+        });
+        assert!(!flash.cdone().unwrap());
+    }
+}
@@ -0,0 +1,1082 @@
+//! Command-line parsing.  Each job has its own subcommand with its own
+//! arguments; invoking `swot` without one accepts the original
+//! iceprog-style flags so existing scripts keep working.
+
+use crate::bitstream::Fpga;
+use crate::board::BoardProfile;
+use crate::error::Error;
+use crate::flash::ReadMode;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+pub fn parse_size(input: &str) -> Result<usize, &'static str> {
+    let multiple_index = input
+        .chars()
+        .position(|c| !(c.is_numeric() || c == '.'))
+        .unwrap_or(input.len());
+
+    let (value, multiple) = &input.split_at(multiple_index);
+    let value = value.parse::<usize>().map_err(|_| "unable to parse")?;
+    let multiple = match multiple.trim().to_lowercase().as_str() {
+        "m" | "mib" => 1024 * 1024,
+        "k" | "kib" => 1024,
+        "b" | "" | "bytes" => 1,
+        "g" | "gib" => 1024 * 1024 * 1024,
+        _ => return Err("unrecognized suffix"),
+    };
+    Ok(value * multiple)
+}
+
+/// Parse a frequency such as `6M`, `1.5 MHz` or `50k`.
+pub fn parse_frequency(input: &str) -> Result<u32, &'static str> {
+    let input = input.trim();
+    let multiple_index = input
+        .chars()
+        .position(|c| !(c.is_numeric() || c == '.'))
+        .unwrap_or(input.len());
+
+    let (value, multiple) = input.split_at(multiple_index);
+    let value = value.parse::<f64>().map_err(|_| "unable to parse")?;
+    let multiple = match multiple.trim().to_lowercase().as_str() {
+        "m" | "mhz" => 1_000_000.0,
+        "k" | "khz" => 1_000.0,
+        "" | "hz" => 1.0,
+        _ => return Err("unrecognized suffix"),
+    };
+    let hz = (value * multiple).round();
+    if hz < 1.0 || hz > u32::MAX as f64 {
+        return Err("out of range");
+    }
+    Ok(hz as u32)
+}
+
+/// Which FTDI interface to open, as given to `-d`
+#[derive(Debug, PartialEq)]
+pub enum DeviceSelector {
+    Description(String),
+    Serial(String),
+    Location(u32),
+    Index(usize),
+}
+
+pub fn parse_device(input: &str) -> Result<DeviceSelector, &'static str> {
+    if input.len() < 2 || input.as_bytes()[1] != b':' {
+        return Err(
+            "device must be of the form d:<description>, s:<serial>, l:<location> or i:<index>",
+        );
+    }
+    let (kind, value) = input.split_at(2);
+    let value = value.trim_matches('"');
+    match kind {
+        "d:" => Ok(DeviceSelector::Description(value.to_owned())),
+        "s:" => Ok(DeviceSelector::Serial(value.to_owned())),
+        "l:" => {
+            let location = if value.starts_with("0x") || value.starts_with("0X") {
+                u32::from_str_radix(&value[2..], 16)
+            } else {
+                value.parse::<u32>()
+            };
+            location
+                .map(DeviceSelector::Location)
+                .map_err(|_| "unable to parse location")
+        }
+        "i:" => value
+            .parse::<usize>()
+            .map(DeviceSelector::Index)
+            .map_err(|_| "unable to parse index"),
+        _ => Err("unrecognized device type"),
+    }
+}
+
+/// How the range being written is cleared first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EraseMode {
+    /// Assume the range is already blank
+    Skip,
+
+    /// Erase the sectors covering the range, optionally putting back the
+    /// data around it that shares those sectors
+    Sectors { preserve: bool },
+
+    /// Erase the whole chip
+    Bulk,
+
+    /// Only erase and program the blocks that changed
+    Diff,
+}
+
+/// What to do once the flash has been found
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// List the attached FTDI devices
+    List,
+
+    /// Identify the flash and print what is known about it
+    Info,
+
+    /// Toggle reset and read the ID, without touching the contents
+    Test,
+
+    /// Copy `len` bytes at `offset` into `file`, or up to the end of the
+    /// flash if no length was given
+    Read {
+        file: String,
+        offset: usize,
+        len: Option<usize>,
+    },
+
+    /// Print a hexdump of `len` bytes at `offset`
+    Dump { offset: usize, len: usize },
+
+    /// Program `file` at `offset`, then check it
+    Write {
+        file: String,
+        offset: usize,
+        erase: EraseMode,
+        verify: bool,
+        verify_log: Option<String>,
+    },
+
+    /// Check that the flash at `offset` holds `file`
+    Verify {
+        file: String,
+        offset: usize,
+        verify_log: Option<String>,
+    },
+
+    /// Erase `len` bytes at `offset`, or the whole chip if no range is
+    /// given
+    Erase {
+        range: Option<(usize, usize)>,
+        preserve: bool,
+    },
+
+    /// Configure the FPGA directly from `file`, without touching the flash
+    Sram { file: String },
+
+    /// Put up to four bitstreams behind a multiboot header table, either
+    /// writing the lot or replacing the image in one `slot` of the table
+    /// already in the flash.  Each image may ask for its own address.
+    Multiboot {
+        images: Vec<(String, Option<usize>)>,
+        align: usize,
+        power_on: usize,
+        slot: Option<usize>,
+        output: Option<String>,
+        verify: bool,
+        verify_log: Option<String>,
+    },
+}
+
+impl Command {
+    /// The file whose contents get written or compared
+    pub fn input(&self) -> Option<&str> {
+        match self {
+            Command::Write { file, .. } | Command::Verify { file, .. } | Command::Sram { file } => {
+                Some(file)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Settings shared by every command
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    /// How the FTDI part is wired up, which supplies the defaults below
+    pub board: BoardProfile,
+
+    pub device: String,
+    pub verbose: bool,
+    pub read_mode: ReadMode,
+
+    /// SPI clock to ask the adapter for, in Hz
+    pub frequency: u32,
+
+    /// Clear the status register protection bits before writing or erasing
+    pub unprotect: bool,
+
+    /// The FPGA bitstreams are expected to be built for
+    pub fpga: Option<Fpga>,
+
+    /// Write files that don't look like a bitstream for this FPGA anyway
+    pub force: bool,
+}
+
+/// The SPI clock used unless the board, `--freq` or `--slow` says otherwise
+pub const DEFAULT_FREQUENCY: u32 = 6_000_000;
+
+/// The SPI clock used with `--slow`
+pub const SLOW_FREQUENCY: u32 = 50_000;
+
+fn offset_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("offset")
+        .short("o")
+        .long("offset")
+        .takes_value(true)
+        .help("start address in the flash [default: 0]")
+}
+
+fn verify_log_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("verify_log")
+        .long("verify-log")
+        .takes_value(true)
+        .value_name("FILE")
+        .help("write every byte that fails verification to this file")
+}
+
+fn unprotect_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("unprotect")
+        .short("p")
+        .long("unprotect")
+        .help("clear the flash's write protection first, for parts that seem bricked")
+}
+
+fn force_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("force")
+        .short("f")
+        .long("force")
+        .help("write the file even if it isn't a valid bitstream")
+}
+
+fn preserve_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("preserve")
+        .long("preserve")
+        .help("keep data that shares an erase sector with the range")
+}
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("SWOT: the Spi Write Out Tool")
+        .version(clap::crate_version!())
+        .author("Sean Cross <sean@xobs.io>")
+        .about("Read and write SPI devices using an FTDI cable")
+        .after_help("Running swot without a subcommand accepts iceprog-style flags.")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .multiple(true)
+                .global(true)
+                .help("Sets the level of verbosity"),
+        )
+        .arg(
+            Arg::with_name("device")
+                .short("d")
+                .long("device")
+                .takes_value(true)
+                .global(true)
+                .help("select the FTDI interface to use: d:<description>, s:<serial>, l:<location> or i:<index> (see `swot list`) [default: from the board]"),
+        )
+        .arg(
+            Arg::with_name("board")
+                .short("B")
+                .long("board")
+                .takes_value(true)
+                .global(true)
+                .default_value("icebreaker")
+                .help("board wiring: icebreaker, icestick, upduino, tinyfpga, ft2232h, or a TOML file describing another"),
+        )
+        .arg(
+            Arg::with_name("read_mode")
+                .long("read-mode")
+                .takes_value(true)
+                .global(true)
+                .possible_values(&["standard", "fast", "dual", "quad"])
+                .default_value("standard")
+                .help("command used to read back flash: dual and quad need an adapter with more data lines"),
+        )
+        .arg(
+            Arg::with_name("freq")
+                .long("freq")
+                .takes_value(true)
+                .global(true)
+                .value_name("HZ")
+                .help("SPI clock frequency, e.g. 15M or 500k; above 6M needs an FT2232H or similar [default: 6M]"),
+        )
+        .arg(
+            Arg::with_name("slow")
+                .short("s")
+                .long("slow")
+                .global(true)
+                .conflicts_with("freq")
+                .help("use a 50 kHz SPI clock"),
+        )
+        .arg(
+            Arg::with_name("fpga")
+                .long("fpga")
+                .takes_value(true)
+                .global(true)
+                .value_name("PART")
+                .help("the FPGA bitstreams should be built for, e.g. up5k or hx8k"),
+        )
+        .subcommand(SubCommand::with_name("list").about("List all attached FTDI devices"))
+        .subcommand(SubCommand::with_name("info").about("Identify the flash and show its layout"))
+        .subcommand(
+            SubCommand::with_name("test")
+                .about("Reset the FPGA and read the flash ID without changing anything"),
+        )
+        .subcommand(
+            SubCommand::with_name("read")
+                .about("Copy the contents of the flash into a file")
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .help("file to write the flash contents to"),
+                )
+                .arg(offset_arg())
+                .arg(
+                    Arg::with_name("length")
+                        .short("l")
+                        .long("length")
+                        .takes_value(true)
+                        .help("number of bytes to read [default: up to the end of the flash]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Print the contents of the flash as a hexdump")
+                .arg(offset_arg())
+                .arg(
+                    Arg::with_name("length")
+                        .short("l")
+                        .long("length")
+                        .takes_value(true)
+                        .default_value("256")
+                        .help("number of bytes to show"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("write")
+                .about("Program a file into the flash and verify it")
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .help("file to program"),
+                )
+                .arg(offset_arg())
+                .arg(
+                    Arg::with_name("no_erase")
+                        .short("n")
+                        .long("no-erase")
+                        .help("do not erase before writing"),
+                )
+                .arg(
+                    Arg::with_name("bulk_erase")
+                        .short("b")
+                        .long("bulk-erase")
+                        .conflicts_with("no_erase")
+                        .help("erase the entire flash before writing"),
+                )
+                .arg(preserve_arg().conflicts_with_all(&["no_erase", "bulk_erase"]))
+                .arg(
+                    Arg::with_name("diff")
+                        .long("diff")
+                        .conflicts_with_all(&["no_erase", "bulk_erase", "preserve"])
+                        .help("only erase and program blocks whose contents changed"),
+                )
+                .arg(
+                    Arg::with_name("no_verify")
+                        .short("X")
+                        .long("no-verify")
+                        .conflicts_with("verify_log")
+                        .help("skip reading the flash back afterwards"),
+                )
+                .arg(verify_log_arg())
+                .arg(unprotect_arg())
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check that the flash holds the contents of a file")
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .help("file to compare against"),
+                )
+                .arg(offset_arg())
+                .arg(verify_log_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("erase")
+                .about("Erase part or all of the flash")
+                .arg(
+                    Arg::with_name("all")
+                        .short("a")
+                        .long("all")
+                        .conflicts_with_all(&["offset", "length", "preserve"])
+                        .help("erase the entire flash"),
+                )
+                .arg(offset_arg().requires("length"))
+                .arg(
+                    Arg::with_name("length")
+                        .short("l")
+                        .long("length")
+                        .takes_value(true)
+                        .required_unless("all")
+                        .help("number of bytes to erase, rounded out to whole sectors"),
+                )
+                .arg(preserve_arg())
+                .arg(unprotect_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("multiboot")
+                .about("Write up to four bitstreams behind a warmboot header table")
+                .arg(
+                    Arg::with_name("IMAGES")
+                        .required(true)
+                        .multiple(true)
+                        .max_values(4)
+                        .help("bitstreams for slots 0 to 3, each optionally with an address as FILE@OFFSET"),
+                )
+                .arg(
+                    Arg::with_name("align")
+                        .short("a")
+                        .long("align")
+                        .takes_value(true)
+                        .default_value("64k")
+                        .help("start each image at a multiple of this many bytes"),
+                )
+                .arg(
+                    Arg::with_name("power_on")
+                        .long("power-on")
+                        .takes_value(true)
+                        .value_name("SLOT")
+                        .possible_values(&["0", "1", "2", "3"])
+                        .default_value("0")
+                        .help("the image loaded at power-on"),
+                )
+                .arg(
+                    Arg::with_name("slot")
+                        .long("slot")
+                        .takes_value(true)
+                        .possible_values(&["0", "1", "2", "3"])
+                        .conflicts_with_all(&["align", "power_on", "output"])
+                        .help("only replace the image in this slot, keeping the headers and other images"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("save the combined image to a file instead of writing it to the flash"),
+                )
+                .arg(
+                    Arg::with_name("no_verify")
+                        .short("X")
+                        .long("no-verify")
+                        .conflicts_with("verify_log")
+                        .help("skip reading the flash back afterwards"),
+                )
+                .arg(verify_log_arg())
+                .arg(unprotect_arg())
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("sram")
+                .about("Load a bitstream into the FPGA's SRAM, leaving the flash alone")
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .help("bitstream to load"),
+                )
+                .arg(force_arg()),
+        )
+        // iceprog-compatible flags, used when there's no subcommand
+        .arg(
+            Arg::with_name("FILENAME")
+                .help("iceprog mode: the bitstream file to read or write")
+                .required_unless("test")
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("bulk_erase")
+                .short("b")
+                .help("iceprog mode: bulk erase entire flash before writing"),
+        )
+        .arg(
+            Arg::with_name("check_mode")
+                .short("c")
+                .help("iceprog mode: do not write flash, only verify (`check')"),
+        )
+        .arg(
+            Arg::with_name("no_erase")
+                .short("n")
+                .help("iceprog mode: do not erase flash before writing"),
+        )
+        .arg(
+            Arg::with_name("preserve")
+                .long("preserve")
+                .conflicts_with("bulk_erase")
+                .help("iceprog mode: keep data that shares an erase sector with the written range"),
+        )
+        .arg(
+            Arg::with_name("diff")
+                .long("diff")
+                .conflicts_with_all(&["bulk_erase", "no_erase", "erase_size"])
+                .help("iceprog mode: only erase and program blocks whose contents changed"),
+        )
+        .arg(
+            Arg::with_name("verify_log")
+                .long("verify-log")
+                .takes_value(true)
+                .help("iceprog mode: write every byte that fails verification to this file"),
+        )
+        .arg(
+            Arg::with_name("erase_size")
+                .short("e")
+                .takes_value(true)
+                .help("iceprog mode: only erase flash as if we were writing that number of bytes"),
+        )
+        .arg(
+            Arg::with_name("read_256")
+                .short("r")
+                .help("iceprog mode: read first 256 kB from flash and write to file"),
+        )
+        .arg(
+            Arg::with_name("read_file")
+                .short("R")
+                .takes_value(true)
+                .conflicts_with("read_256")
+                .help("iceprog mode: read the specified number of bytes from flash"),
+        )
+        .arg(
+            Arg::with_name("offset")
+                .short("o")
+                .takes_value(true)
+                .default_value("0")
+                .help("iceprog mode: start address for read/write"),
+        )
+        .arg(
+            Arg::with_name("test")
+                .short("t")
+                .help("iceprog mode: only reset and read the flash ID"),
+        )
+        .arg(
+            Arg::with_name("no_verify")
+                .short("X")
+                .help("iceprog mode: skip verification"),
+        )
+        .arg(
+            Arg::with_name("sram")
+                .short("S")
+                .help("iceprog mode: load the bitstream into the FPGA's SRAM instead of the flash"),
+        )
+        .arg(
+            Arg::with_name("unprotect")
+                .short("p")
+                .long("unprotect")
+                .help("iceprog mode: disable write protection before erasing or writing"),
+        )
+        .arg(
+            Arg::with_name("force")
+                .short("f")
+                .long("force")
+                .help("iceprog mode: write the file even if it isn't a valid bitstream"),
+        )
+}
+
+fn size_arg(matches: &ArgMatches, name: &str) -> Result<Option<usize>, Error> {
+    match matches.value_of(name) {
+        Some(value) => parse_size(value)
+            .map(Some)
+            .map_err(|e| Error::Argument(format!("{} \"{}\": {}", name, value, e))),
+        None => Ok(None),
+    }
+}
+
+fn file_arg(matches: &ArgMatches, name: &str) -> String {
+    matches.value_of(name).unwrap_or_default().to_owned()
+}
+
+fn verify_log(matches: &ArgMatches) -> Option<String> {
+    matches.value_of("verify_log").map(str::to_owned)
+}
+
+/// Multiboot images are given as `FILE` or `FILE@OFFSET`.
+fn multiboot_images(matches: &ArgMatches) -> Result<Vec<(String, Option<usize>)>, Error> {
+    let values = matches.values_of("IMAGES").into_iter().flatten();
+    values
+        .map(|value| match value.rsplit_once('@') {
+            Some((file, offset)) => parse_size(offset)
+                .map(|offset| (file.to_owned(), Some(offset)))
+                .map_err(|e| Error::Argument(format!("image \"{}\": {}", value, e))),
+            None => Ok((value.to_owned(), None)),
+        })
+        .collect()
+}
+
+fn slot_arg(matches: &ArgMatches, name: &str) -> Option<usize> {
+    matches.value_of(name).and_then(|value| value.parse().ok())
+}
+
+fn multiboot_command(sub: &ArgMatches) -> Result<Command, Error> {
+    let images = multiboot_images(sub)?;
+    let power_on = slot_arg(sub, "power_on").unwrap_or(0);
+    let slot = slot_arg(sub, "slot");
+    if slot.is_some() && images.len() != 1 {
+        return Err(Error::Argument(
+            "give exactly one image to put in the slot".to_owned(),
+        ));
+    }
+    if let (Some(slot), [(file, Some(_))]) = (slot, &images[..]) {
+        return Err(Error::Argument(format!(
+            "\"{}\" goes wherever slot {} already is, it can't be given an offset",
+            file, slot
+        )));
+    }
+    if slot.is_none() && power_on >= images.len() {
+        return Err(Error::Argument(format!(
+            "there is no image {} to load at power-on",
+            power_on
+        )));
+    }
+    Ok(Command::Multiboot {
+        images,
+        align: size_arg(sub, "align")?.unwrap_or(0x10000),
+        power_on,
+        slot,
+        output: sub.value_of("output").map(str::to_owned),
+        verify: !sub.is_present("no_verify"),
+        verify_log: verify_log(sub),
+    })
+}
+
+/// Turn the flat iceprog-style flags into the equivalent command.
+fn iceprog_command(matches: &ArgMatches) -> Result<Command, Error> {
+    let file = file_arg(matches, "FILENAME");
+    let offset = size_arg(matches, "offset")?.unwrap_or(0);
+
+    if matches.is_present("test") {
+        return Ok(Command::Test);
+    }
+    if matches.is_present("sram") {
+        return Ok(Command::Sram { file });
+    }
+    if matches.is_present("read_256") {
+        return Ok(Command::Read {
+            file,
+            offset,
+            len: Some(256 * 1024),
+        });
+    }
+    if let Some(len) = size_arg(matches, "read_file")? {
+        return Ok(Command::Read {
+            file,
+            offset,
+            len: Some(len),
+        });
+    }
+    if matches.is_present("check_mode") {
+        return Ok(Command::Verify {
+            file,
+            offset,
+            verify_log: verify_log(matches),
+        });
+    }
+    if let Some(len) = size_arg(matches, "erase_size")? {
+        return Ok(Command::Erase {
+            range: Some((offset, len)),
+            preserve: matches.is_present("preserve"),
+        });
+    }
+
+    let erase = if matches.is_present("no_erase") {
+        EraseMode::Skip
+    } else if matches.is_present("bulk_erase") {
+        EraseMode::Bulk
+    } else if matches.is_present("diff") {
+        EraseMode::Diff
+    } else {
+        EraseMode::Sectors {
+            preserve: matches.is_present("preserve"),
+        }
+    };
+    Ok(Command::Write {
+        file,
+        offset,
+        erase,
+        verify: !matches.is_present("no_verify"),
+        verify_log: verify_log(matches),
+    })
+}
+
+fn command(matches: &ArgMatches) -> Result<Command, Error> {
+    let (name, sub) = match matches.subcommand() {
+        (name, Some(sub)) => (name, sub),
+        _ => return iceprog_command(matches),
+    };
+    let offset = size_arg(sub, "offset")?.unwrap_or(0);
+
+    Ok(match name {
+        "list" => Command::List,
+        "info" => Command::Info,
+        "test" => Command::Test,
+        "read" => Command::Read {
+            file: file_arg(sub, "FILE"),
+            offset,
+            len: size_arg(sub, "length")?,
+        },
+        "dump" => Command::Dump {
+            offset,
+            len: size_arg(sub, "length")?.unwrap_or(256),
+        },
+        "write" => Command::Write {
+            file: file_arg(sub, "FILE"),
+            offset,
+            erase: if sub.is_present("no_erase") {
+                EraseMode::Skip
+            } else if sub.is_present("bulk_erase") {
+                EraseMode::Bulk
+            } else if sub.is_present("diff") {
+                EraseMode::Diff
+            } else {
+                EraseMode::Sectors {
+                    preserve: sub.is_present("preserve"),
+                }
+            },
+            verify: !sub.is_present("no_verify"),
+            verify_log: verify_log(sub),
+        },
+        "verify" => Command::Verify {
+            file: file_arg(sub, "FILE"),
+            offset,
+            verify_log: verify_log(sub),
+        },
+        "erase" => Command::Erase {
+            range: match size_arg(sub, "length")? {
+                Some(len) if !sub.is_present("all") => Some((offset, len)),
+                _ => None,
+            },
+            preserve: sub.is_present("preserve"),
+        },
+        "sram" => Command::Sram {
+            file: file_arg(sub, "FILE"),
+        },
+        "multiboot" => multiboot_command(sub)?,
+        _ => unreachable!("unknown subcommand {}", name),
+    })
+}
+
+/// Work out the shared options and the command to run.
+pub fn parse(matches: &ArgMatches) -> Result<(Options, Command), Error> {
+    // Global arguments are copied into the subcommand's matches
+    let global = match matches.subcommand() {
+        (_, Some(sub)) => sub,
+        _ => matches,
+    };
+    let read_mode = match global.value_of("read_mode").unwrap_or_default() {
+        "fast" => ReadMode::Fast,
+        "dual" => ReadMode::Dual,
+        "quad" => ReadMode::Quad,
+        _ => ReadMode::Standard,
+    };
+    // The MPSSE engine only clocks data in on one line, so catch this
+    // before opening the adapter
+    if read_mode.lanes() > 1 {
+        return Err(Error::Argument(format!(
+            "{:?} reads need {} data lines, but FTDI adapters in MPSSE mode only have 1",
+            read_mode,
+            read_mode.lanes()
+        )));
+    }
+    let board = BoardProfile::load(global.value_of("board").unwrap_or_default())?;
+    let frequency = match global.value_of("freq") {
+        Some(value) => parse_frequency(value)
+            .map_err(|e| Error::Argument(format!("frequency \"{}\": {}", value, e)))?,
+        None if global.is_present("slow") => SLOW_FREQUENCY,
+        None => board.frequency,
+    };
+    let fpga = match global.value_of("fpga") {
+        Some(name) => Some(
+            Fpga::from_name(name)
+                .ok_or_else(|| Error::Argument(format!("unknown FPGA \"{}\"", name)))?,
+        ),
+        None => board.fpga,
+    };
+    let device = match global.value_of("device") {
+        Some(device) => device.to_owned(),
+        None => board.device(),
+    };
+    let options = Options {
+        board,
+        device,
+        verbose: global.is_present("verbose"),
+        read_mode,
+        frequency,
+        // Only some subcommands have these flags
+        unprotect: global.is_present("unprotect"),
+        fpga,
+        force: global.is_present("force"),
+    };
+    Ok((options, command(matches)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_sanity() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("1k").unwrap(), 1024);
+        assert_eq!(parse_size("1K").unwrap(), 1024);
+        assert_eq!(parse_size("1 K").unwrap(), 1024);
+        assert_eq!(parse_size("1 k").unwrap(), 1024);
+        assert_eq!(parse_size("1 kiB").unwrap(), 1024);
+        assert_eq!(parse_size("1 M").unwrap(), 1024 * 1024);
+        assert_eq!(parse_size("2 M").unwrap(), 1024 * 1024 * 2);
+    }
+
+    #[test]
+    fn parse_frequency_sanity() {
+        assert_eq!(parse_frequency("6M").unwrap(), 6_000_000);
+        assert_eq!(parse_frequency("1.5 MHz").unwrap(), 1_500_000);
+        assert_eq!(parse_frequency("50k").unwrap(), 50_000);
+        assert_eq!(parse_frequency("400000").unwrap(), 400_000);
+        assert!(parse_frequency("0").is_err());
+        assert!(parse_frequency("6 GHz").is_err());
+        assert!(parse_frequency("fast").is_err());
+    }
+
+    #[test]
+    fn read_modes() {
+        let read_mode = |args: &[&str]| parse(&app().get_matches_from(args)).map(|p| p.0.read_mode);
+        assert_eq!(read_mode(&["swot", "info"]).unwrap(), ReadMode::Standard);
+        assert_eq!(
+            read_mode(&["swot", "--read-mode", "fast", "info"]).unwrap(),
+            ReadMode::Fast
+        );
+        assert!(matches!(
+            read_mode(&["swot", "--read-mode", "quad", "info"]),
+            Err(Error::Argument(_))
+        ));
+    }
+
+    #[test]
+    fn parse_device_sanity() {
+        assert_eq!(
+            parse_device("d:iCEBreaker V1.0e A").unwrap(),
+            DeviceSelector::Description("iCEBreaker V1.0e A".to_owned())
+        );
+        assert_eq!(
+            parse_device("d:\"iCEBreaker V1.0e A\"").unwrap(),
+            DeviceSelector::Description("iCEBreaker V1.0e A".to_owned())
+        );
+        assert_eq!(
+            parse_device("s:FT4ABC12").unwrap(),
+            DeviceSelector::Serial("FT4ABC12".to_owned())
+        );
+        assert_eq!(parse_device("i:0").unwrap(), DeviceSelector::Index(0));
+        assert_eq!(
+            parse_device("l:0x1011").unwrap(),
+            DeviceSelector::Location(0x1011)
+        );
+        assert_eq!(
+            parse_device("l:4113").unwrap(),
+            DeviceSelector::Location(0x1011)
+        );
+        assert!(parse_device("i:zero").is_err());
+        assert!(parse_device("x:0").is_err());
+        assert!(parse_device("FT4ABC12").is_err());
+    }
+
+    fn parse_args(args: &[&str]) -> Result<(Options, Command), Error> {
+        let matches = app()
+            .get_matches_from_safe(args)
+            .map_err(|e| Error::Argument(e.message))?;
+        parse(&matches)
+    }
+
+    #[test]
+    fn subcommands() {
+        let (options, command) = parse_args(&[
+            "swot", "write", "-d", "i:1", "top.bin", "-o", "1M", "--diff",
+        ])
+        .unwrap();
+        assert_eq!(options.device, "i:1");
+        assert_eq!(
+            command,
+            Command::Write {
+                file: "top.bin".to_owned(),
+                offset: 1024 * 1024,
+                erase: EraseMode::Diff,
+                verify: true,
+                verify_log: None,
+            }
+        );
+
+        let (options, command) = parse_args(&["swot", "read", "out.bin", "-v"]).unwrap();
+        assert!(options.verbose);
+        assert_eq!(options.device, "d:iCEBreaker V1.0e A");
+        assert_eq!(options.fpga, Some(Fpga::Up5k));
+        assert_eq!(
+            command,
+            Command::Read {
+                file: "out.bin".to_owned(),
+                offset: 0,
+                len: None,
+            }
+        );
+
+        assert_eq!(
+            parse_args(&["swot", "erase", "--all"]).unwrap().1,
+            Command::Erase {
+                range: None,
+                preserve: false,
+            }
+        );
+        assert_eq!(
+            parse_args(&["swot", "erase", "-o", "64k", "-l", "4k", "--preserve"])
+                .unwrap()
+                .1,
+            Command::Erase {
+                range: Some((0x10000, 0x1000)),
+                preserve: true,
+            }
+        );
+        assert_eq!(
+            parse_args(&["swot", "sram", "top.bin"]).unwrap().1,
+            Command::Sram {
+                file: "top.bin".to_owned()
+            }
+        );
+        assert_eq!(
+            parse_args(&["swot", "multiboot", "a.bin", "b.bin@1M", "--power-on", "1"])
+                .unwrap()
+                .1,
+            Command::Multiboot {
+                images: vec![
+                    ("a.bin".to_owned(), None),
+                    ("b.bin".to_owned(), Some(1024 * 1024)),
+                ],
+                align: 0x10000,
+                power_on: 1,
+                slot: None,
+                output: None,
+                verify: true,
+                verify_log: None,
+            }
+        );
+        assert!(matches!(
+            parse_args(&["swot", "multiboot", "--slot", "2", "c.bin"])
+                .unwrap()
+                .1,
+            Command::Multiboot { slot: Some(2), .. }
+        ));
+        assert!(parse_args(&["swot", "multiboot", "--slot", "2", "a.bin", "b.bin"]).is_err());
+        assert!(parse_args(&["swot", "multiboot", "--slot", "2", "c.bin@1M"]).is_err());
+        assert!(parse_args(&["swot", "multiboot", "a.bin", "--power-on", "1"]).is_err());
+        assert!(parse_args(&["swot", "multiboot", "a", "b", "c", "d", "e"]).is_err());
+        let (options, command) =
+            parse_args(&["swot", "write", "--freq", "15M", "--no-verify", "top.bin"]).unwrap();
+        assert_eq!(options.frequency, 15_000_000);
+        assert!(!options.unprotect);
+        assert!(matches!(command, Command::Write { verify: false, .. }));
+        assert_eq!(
+            parse_args(&["swot", "info"]).unwrap().0.frequency,
+            DEFAULT_FREQUENCY
+        );
+        assert!(
+            parse_args(&["swot", "erase", "--all", "--unprotect"])
+                .unwrap()
+                .0
+                .unprotect
+        );
+        // -p removes write protection everywhere, as it does in iceprog mode
+        let (options, command) = parse_args(&["swot", "write", "-p", "top.bin"]).unwrap();
+        assert!(options.unprotect);
+        assert!(matches!(command, Command::Write { .. }));
+
+        assert!(parse_args(&["swot", "erase"]).is_err());
+        assert!(parse_args(&["swot", "erase", "-o", "64k"]).is_err());
+        assert!(parse_args(&["swot", "write", "top.bin", "-n", "-b"]).is_err());
+        assert!(parse_args(&["swot", "read", "out.bin", "-o", "12q"]).is_err());
+        assert!(parse_args(&["swot", "info", "--slow", "--freq", "1M"]).is_err());
+        assert!(parse_args(&["swot", "info", "--freq", "lots"]).is_err());
+
+        let (options, _) =
+            parse_args(&["swot", "sram", "--fpga", "hx8k", "-f", "top.bin"]).unwrap();
+        assert_eq!(options.fpga, Some(Fpga::Hx8k));
+        assert!(options.force);
+        assert!(parse_args(&["swot", "info", "--fpga", "ecp5"]).is_err());
+
+        let (options, _) = parse_args(&["swot", "info", "--board", "icestick"]).unwrap();
+        assert_eq!(options.device, "d:Dual RS232-HS A");
+        assert_eq!(options.fpga, Some(Fpga::Hx1k));
+        assert!(parse_args(&["swot", "info", "--board", "nonesuch"]).is_err());
+    }
+
+    #[test]
+    fn iceprog_compatibility() {
+        assert_eq!(
+            parse_args(&["swot", "top.bin"]).unwrap().1,
+            Command::Write {
+                file: "top.bin".to_owned(),
+                offset: 0,
+                erase: EraseMode::Sectors { preserve: false },
+                verify: true,
+                verify_log: None,
+            }
+        );
+        assert_eq!(
+            parse_args(&["swot", "-r", "flash.bin"]).unwrap().1,
+            Command::Read {
+                file: "flash.bin".to_owned(),
+                offset: 0,
+                len: Some(256 * 1024),
+            }
+        );
+        assert_eq!(
+            parse_args(&["swot", "-c", "-o", "128k", "top.bin"])
+                .unwrap()
+                .1,
+            Command::Verify {
+                file: "top.bin".to_owned(),
+                offset: 128 * 1024,
+                verify_log: None,
+            }
+        );
+        assert_eq!(
+            parse_args(&["swot", "-e", "32k", "top.bin"]).unwrap().1,
+            Command::Erase {
+                range: Some((0, 32 * 1024)),
+                preserve: false,
+            }
+        );
+        assert_eq!(parse_args(&["swot", "-t"]).unwrap().1, Command::Test);
+        assert_eq!(
+            parse_args(&["swot", "-S", "top.bin"]).unwrap().1,
+            Command::Sram {
+                file: "top.bin".to_owned()
+            }
+        );
+
+        let (options, command) =
+            parse_args(&["swot", "-s", "-X", "--unprotect", "top.bin"]).unwrap();
+        assert_eq!(options.frequency, SLOW_FREQUENCY);
+        assert!(options.unprotect);
+        assert!(matches!(command, Command::Write { verify: false, .. }));
+        assert!(parse_args(&["swot"]).is_err());
+
+        // As in iceprog, -p removes write protection rather than preserving
+        let (options, command) = parse_args(&["swot", "-p", "top.bin"]).unwrap();
+        assert!(options.unprotect);
+        assert!(matches!(
+            command,
+            Command::Write {
+                erase: EraseMode::Sectors { preserve: false },
+                ..
+            }
+        ));
+        let (options, command) = parse_args(&["swot", "--preserve", "top.bin"]).unwrap();
+        assert!(!options.unprotect);
+        assert!(matches!(
+            command,
+            Command::Write {
+                erase: EraseMode::Sectors { preserve: true },
+                ..
+            }
+        ));
+    }
+}
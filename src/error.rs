@@ -0,0 +1,286 @@
+//! Errors reported by `swot`, each mapped to its own process exit code so
+//! that scripts can tell the failures apart.
+
+use crate::bitstream::ParseError;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A command-line argument couldn't be understood
+    Argument(String),
+
+    /// The FTDI interface couldn't be opened
+    Open {
+        device: String,
+        source: ftdi_vcp_rs::Error,
+    },
+
+    /// The adapter failed part way through talking to the flash.  The
+    /// operation is `None` until `Error::during` says what was going on.
+    Transport {
+        operation: Option<&'static str>,
+        address: Option<usize>,
+        source: ftdi_vcp_rs::Error,
+    },
+
+    /// Nothing answered the JEDEC ID command
+    NoFlash,
+
+    /// An address range doesn't fit in the flash
+    OutOfRange {
+        address: usize,
+        len: usize,
+        size: usize,
+    },
+
+    /// The flash or adapter can't do what was asked
+    Unsupported(String),
+
+    /// The flash stayed busy for longer than the operation should take
+    Timeout {
+        operation: &'static str,
+        elapsed: Duration,
+    },
+
+    /// The flash reported that an erase or program failed, or its write
+    /// protection couldn't be removed
+    Flash { operation: &'static str, status: u8 },
+
+    /// Reading back the flash didn't give the data that was written
+    VerifyFailed { bad_bytes: usize, ranges: usize },
+
+    /// A file couldn't be read or written
+    File { path: String, source: io::Error },
+
+    /// The FPGA didn't raise CDONE after being sent a bitstream
+    NotConfigured,
+
+    /// A file that should be a bitstream isn't one
+    Bitstream { path: String, source: ParseError },
+}
+
+impl Error {
+    /// Attribute a transport error to a flash operation.
+    pub fn transport(
+        operation: &'static str,
+        address: Option<usize>,
+        source: ftdi_vcp_rs::Error,
+    ) -> Error {
+        Error::Transport {
+            operation: Some(operation),
+            address,
+            source,
+        }
+    }
+
+    /// Add the operation and address to a transport error that doesn't
+    /// have them yet.
+    pub fn during(self, operation: &'static str, address: Option<usize>) -> Error {
+        match self {
+            Error::Transport {
+                operation: None,
+                source,
+                ..
+            } => Error::transport(operation, address, source),
+            e => e,
+        }
+    }
+
+    pub fn file(path: &str, source: io::Error) -> Error {
+        Error::File {
+            path: path.to_owned(),
+            source,
+        }
+    }
+
+    /// The process exit code for this error:
+    ///
+    /// | code | meaning                          |
+    /// |------|----------------------------------|
+    /// | 2    | bad command-line argument        |
+    /// | 3    | FTDI device not found or busy    |
+    /// | 4    | USB/adapter failure              |
+    /// | 5    | no flash detected                |
+    /// | 6    | address outside the flash        |
+    /// | 7    | unsupported operation            |
+    /// | 8    | flash reported a failure         |
+    /// | 9    | verify failed                    |
+    /// | 10   | file error                       |
+    /// | 11   | flash operation timed out        |
+    /// | 12   | FPGA didn't configure from SRAM  |
+    /// | 13   | file isn't a valid bitstream     |
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Argument(_) => 2,
+            Error::Open { .. } => 3,
+            Error::Transport { .. } => 4,
+            Error::NoFlash => 5,
+            Error::OutOfRange { .. } => 6,
+            Error::Unsupported(_) => 7,
+            Error::Flash { .. } => 8,
+            Error::VerifyFailed { .. } => 9,
+            Error::File { .. } => 10,
+            Error::Timeout { .. } => 11,
+            Error::NotConfigured => 12,
+            Error::Bitstream { .. } => 13,
+        }
+    }
+}
+
+/// Transport errors that haven't been given any context yet.  Flash
+/// operations replace the operation name using `Error::during`.
+impl From<ftdi_vcp_rs::Error> for Error {
+    fn from(source: ftdi_vcp_rs::Error) -> Error {
+        Error::Transport {
+            operation: None,
+            address: None,
+            source,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Argument(message) => write!(f, "{}", message),
+            Error::Open { device, source } => {
+                write!(f, "unable to open {}: {:?}", device, source)
+            }
+            Error::Transport {
+                operation,
+                address: Some(address),
+                source,
+            } => write!(
+                f,
+                "{} at 0x{:06X} failed: {:?}",
+                operation.unwrap_or("SPI transfer"),
+                address,
+                source
+            ),
+            Error::Transport {
+                operation, source, ..
+            } => write!(
+                f,
+                "{} failed: {:?}",
+                operation.unwrap_or("SPI transfer"),
+                source
+            ),
+            Error::NoFlash => write!(f, "no flash chip detected, check the wiring"),
+            Error::OutOfRange { address, len, size } => write!(
+                f,
+                "0x{:X} bytes at 0x{:X} do not fit in the {} byte flash",
+                len, address, size
+            ),
+            Error::Unsupported(message) => write!(f, "{}", message),
+            Error::Timeout { operation, elapsed } => write!(
+                f,
+                "{} still busy after {} ms",
+                operation,
+                elapsed.as_millis()
+            ),
+            Error::Flash { operation, status } => {
+                write!(f, "{} failed, status 0x{:02X}", operation, status)
+            }
+            Error::VerifyFailed { bad_bytes, ranges } => write!(
+                f,
+                "verify failed: {} bytes differ in {} ranges",
+                bad_bytes, ranges
+            ),
+            Error::File { path, source } => write!(f, "{}: {}", path, source),
+            Error::NotConfigured => {
+                write!(f, "CDONE stayed low, the FPGA didn't accept the bitstream")
+            }
+            Error::Bitstream { path, source } => {
+                write!(f, "{}: {} (use --force to write it anyway)", path, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstream::ParseError;
+
+    #[test]
+    fn exit_codes() {
+        let source = || ftdi_vcp_rs::Error::IoError;
+        let errors = vec![
+            (Error::Argument("bad".to_owned()), 2),
+            (
+                Error::Open {
+                    device: "i:0".to_owned(),
+                    source: source(),
+                },
+                3,
+            ),
+            (Error::from(source()), 4),
+            (Error::NoFlash, 5),
+            (
+                Error::OutOfRange {
+                    address: 0,
+                    len: 2,
+                    size: 1,
+                },
+                6,
+            ),
+            (Error::Unsupported("quad".to_owned()), 7),
+            (
+                Error::Flash {
+                    operation: "erase",
+                    status: 0x20,
+                },
+                8,
+            ),
+            (
+                Error::VerifyFailed {
+                    bad_bytes: 1,
+                    ranges: 1,
+                },
+                9,
+            ),
+            (
+                Error::file("top.bin", io::Error::from(io::ErrorKind::NotFound)),
+                10,
+            ),
+            (
+                Error::Timeout {
+                    operation: "erase",
+                    elapsed: Duration::from_secs(1),
+                },
+                11,
+            ),
+            (Error::NotConfigured, 12),
+            (
+                Error::Bitstream {
+                    path: "top.bin".to_owned(),
+                    source: ParseError::NoPreamble,
+                },
+                13,
+            ),
+        ];
+        for (error, code) in errors {
+            assert_eq!(error.exit_code(), code, "{}", error);
+        }
+    }
+
+    #[test]
+    fn transport_context() {
+        let error = Error::from(ftdi_vcp_rs::Error::IoError);
+        assert_eq!(error.to_string(), "SPI transfer failed: IoError");
+        let error = error.during("read", Some(0x100));
+        assert_eq!(error.to_string(), "read at 0x000100 failed: IoError");
+
+        // Context that is already there is kept
+        let error = error.during("wait", None);
+        assert_eq!(error.to_string(), "read at 0x000100 failed: IoError");
+        assert!(matches!(
+            Error::NoFlash.during("read", None),
+            Error::NoFlash
+        ));
+    }
+}
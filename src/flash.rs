@@ -1,29 +1,252 @@
-use ftdi_vcp_rs::{Error, VCP};
+use crate::board::{Bank, Pins};
+use crate::error::Error;
+use crate::parts::{Addressing, FailureReport, FlashId, FlashPart, Geometry, QuadEnable};
+use crate::sfdp::Sfdp;
+use crate::transport::Transport;
+use crate::verify::VerifyReport;
+use ftdi_vcp_rs::mpsse::Queue;
+use std::fmt;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum EraseType {
+    Kb4,
+    Kb32,
     Kb64,
 }
 
-pub struct Flash {
-    pub vcp: VCP,
+impl EraseType {
+    pub fn size(self) -> usize {
+        match self {
+            EraseType::Kb4 => 4 * 1024,
+            EraseType::Kb32 => 32 * 1024,
+            EraseType::Kb64 => 64 * 1024,
+        }
+    }
+
+    pub fn from_size(size: usize) -> Option<EraseType> {
+        match size {
+            0x1000 => Some(EraseType::Kb4),
+            0x8000 => Some(EraseType::Kb32),
+            0x10000 => Some(EraseType::Kb64),
+            _ => None,
+        }
+    }
+}
+
+/// The command used to read data from the part
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadMode {
+    /// Read Data (0x03), limited to low clock rates on many parts
+    Standard,
+
+    /// Fast Read (0x0B) with a dummy byte
+    Fast,
+
+    /// Dual Output Fast Read (0x3B)
+    Dual,
+
+    /// Quad Output Fast Read (0x6B), which needs the Quad Enable bit
+    Quad,
+}
+
+impl ReadMode {
+    fn opcode(self) -> u8 {
+        match self {
+            ReadMode::Standard => 0x03, /* FC_RD // Read Data */
+            ReadMode::Fast => 0x0B,     /* FC_FR // Fast Read */
+            ReadMode::Dual => 0x3B,     /* FC_FRD // Fast Read Dual Output */
+            ReadMode::Quad => 0x6B,     /* FC_FRQ // Fast Read Quad Output */
+        }
+    }
+
+    pub fn lanes(self) -> usize {
+        match self {
+            ReadMode::Dual => 2,
+            ReadMode::Quad => 4,
+            _ => 1,
+        }
+    }
+}
+
+/// Status reads queued up per round trip to the adapter while waiting
+const STATUS_POLLS: usize = 8;
+
+/// An operation that leaves the part busy, which decides how long `wait`
+/// gives it to finish and which failure flags are checked afterwards.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    PageProgram,
+    Erase(EraseType),
+    ChipErase,
+    WriteStatus,
+}
+
+impl Operation {
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::PageProgram => "page program",
+            Operation::Erase(EraseType::Kb4) => "4 kB erase",
+            Operation::Erase(EraseType::Kb32) => "32 kB erase",
+            Operation::Erase(EraseType::Kb64) => "64 kB erase",
+            Operation::ChipErase => "chip erase",
+            Operation::WriteStatus => "status register write",
+        }
+    }
+
+    /// How long to leave between rounds of status reads.  Programs are
+    /// over in a few round trips, so there is no point sleeping; erases
+    /// take long enough that polling flat out would just burn CPU.
+    pub fn poll_interval(self) -> Duration {
+        match self {
+            Operation::PageProgram | Operation::WriteStatus => Duration::from_micros(0),
+            _ => Duration::from_micros(1_000),
+        }
+    }
+
+    /// How long to wait before deciding the part is stuck.  These are a
+    /// few times the worst case in common datasheets; chip erase scales
+    /// with the size of the part.
+    pub fn timeout(self, flash_size: usize) -> Duration {
+        match self {
+            Operation::PageProgram => Duration::from_millis(50),
+            Operation::Erase(EraseType::Kb4) => Duration::from_secs(2),
+            Operation::Erase(EraseType::Kb32) => Duration::from_secs(3),
+            Operation::Erase(EraseType::Kb64) => Duration::from_secs(4),
+            Operation::ChipErase => Duration::from_secs(30 + flash_size as u64 / (64 * 1024)),
+            Operation::WriteStatus => Duration::from_millis(200),
+        }
+    }
+}
+
+/// Work out which erase operations cover `[offset, offset + len)`, using the
+/// largest aligned erase that fits at each step.  The covered range is
+/// rounded out to the smallest erase size available in `types`.
+pub fn plan_erase(offset: usize, len: usize, types: &[EraseType]) -> Vec<(EraseType, usize)> {
+    let mut types = types.to_vec();
+    types.sort_by_key(|t| t.size());
+    let smallest = match types.first() {
+        Some(t) if len > 0 => t.size(),
+        _ => return vec![],
+    };
+
+    let end = (offset + len + smallest - 1) & !(smallest - 1);
+    let mut addr = offset & !(smallest - 1);
+    let mut plan = vec![];
+    while addr < end {
+        let erase_type = *types
+            .iter()
+            .rev()
+            .find(|t| addr & (t.size() - 1) == 0 && addr + t.size() <= end)
+            .unwrap();
+        plan.push((erase_type, addr));
+        addr += erase_type.size();
+    }
+    plan
+}
+
+pub struct Flash<T: Transport> {
+    pub transport: T,
+    pins: Pins,
     verbose: bool,
+    part: Option<&'static FlashPart>,
+    geometry: Option<Geometry>,
+
+    read_mode: ReadMode,
+
+    /* How the part reports failed programs and erases */
+    failure_report: FailureReport,
+
+    /* Addressing state left in the part, undone by `power_down` */
+    four_byte_mode: bool,
+    extended_address: u8,
 }
 
-impl Flash {
-    pub fn new(vcp: VCP) -> Flash {
-        Flash { vcp, verbose: false }
+impl<T: Transport> Flash<T> {
+    pub fn new(transport: T) -> Flash<T> {
+        Flash {
+            transport,
+            pins: Pins::default(),
+            verbose: false,
+            part: None,
+            geometry: None,
+            read_mode: ReadMode::Standard,
+            failure_report: FailureReport::Unreported,
+            four_byte_mode: false,
+            extended_address: 0,
+        }
+    }
+
+    /// The part identified by the last call to `read_id`, if it was found
+    /// in the part table.
+    pub fn part(&self) -> Option<&'static FlashPart> {
+        self.part
+    }
+
+    /// The layout of the attached part, if it has been identified either
+    /// from the part table or from SFDP.
+    pub fn geometry(&self) -> Option<&Geometry> {
+        self.geometry.as_ref()
+    }
+
+    pub fn set_geometry(&mut self, geometry: Geometry) {
+        self.geometry = Some(geometry);
     }
 
     pub fn set_verbose(&mut self, verbose: bool) {
         self.verbose = verbose;
     }
 
-    fn set_cs_creset(&mut self, cs_asserted: bool, reset_asserted: bool) -> Result<(), Error> {
-        let gpio = if cs_asserted { 0x10 } else { 0 } | if reset_asserted { 0x80 } else { 0 };
-        let direction = 0x93;
-        self.vcp.set_gpio(gpio, direction)
+    /// Use the chip select, CRESET and CDONE lines of another board.
+    pub fn set_pins(&mut self, pins: Pins) {
+        self.pins = pins;
+    }
+
+    /// The values and directions for the low and high GPIO banks that
+    /// drive chip select and CRESET to the given levels, along with the SPI
+    /// clock and MOSI lines which are always outputs.
+    fn cs_creset_levels(&self, cs_high: bool, creset_high: bool) -> ([u8; 2], [u8; 2]) {
+        let mut value = [0u8; 2];
+        let mut direction = [0x03u8, 0];
+        for &(pin, high) in &[(self.pins.cs, cs_high), (self.pins.creset, creset_high)] {
+            let bank = match pin.bank {
+                Bank::Low => 0,
+                Bank::High => 1,
+            };
+            direction[bank] |= pin.mask();
+            if high != pin.inverted {
+                value[bank] |= pin.mask();
+            }
+        }
+        (value, direction)
+    }
+
+    fn set_cs_creset(&mut self, cs_high: bool, creset_high: bool) -> Result<(), Error> {
+        let (value, direction) = self.cs_creset_levels(cs_high, creset_high);
+        self.transport.set_gpio(value[0], direction[0])?;
+        if direction[1] != 0 {
+            self.transport.set_gpio_high(value[1], direction[1])?;
+        }
+        Ok(())
+    }
+
+    /// Like `set_cs_creset`, but added to `queue` to be run later.
+    fn queue_cs_creset(&self, queue: &mut Queue, cs_high: bool, creset_high: bool) {
+        let (value, direction) = self.cs_creset_levels(cs_high, creset_high);
+        queue.set_gpio_low(value[0], direction[0]);
+        if direction[1] != 0 {
+            queue.set_gpio_high(value[1], direction[1]);
+        }
+    }
+
+    /// Queue a read of status register 1, returning where the status will
+    /// be in the reply.
+    fn queue_status_read(&self, queue: &mut Queue) -> usize {
+        self.queue_cs_creset(queue, false, false);
+        let offset = queue.xfer_spi(&[0x05 /* FC_RSR1 // Read Status Register 1 */, 0x00]);
+        self.queue_cs_creset(queue, true, false);
+        offset + 1
     }
 
     // the FPGA reset is released so also FLASH chip select should be deasserted
@@ -42,13 +265,13 @@ impl Flash {
         self.set_cs_creset(true, false)
     }
 
-    pub fn reset(&mut self) -> Result<(), ftdi_vcp_rs::Error> {
+    pub fn reset(&mut self) -> Result<(), Error> {
         self.chip_select()?;
-        self.vcp.xfer_spi_bits(0xFF, 8)?;
+        self.transport.xfer_spi_bits(0xFF, 8)?;
         self.chip_deselect()?;
 
         self.chip_select()?;
-        self.vcp.xfer_spi_bits(0xFF, 2)?;
+        self.transport.xfer_spi_bits(0xFF, 2)?;
         self.chip_deselect()?;
         Ok(())
     }
@@ -56,20 +279,34 @@ impl Flash {
     pub fn power_up(&mut self) -> Result<(), Error> {
         let mut cmd = [0xAB /* FC_RPD */];
         self.chip_select()?;
-        self.vcp.xfer_spi(&mut cmd)?;
+        self.transport.xfer_spi(&mut cmd)?;
         self.chip_deselect()?;
         Ok(())
     }
 
+    /// Put the part to sleep, first returning it to 3-byte addressing so
+    /// that the FPGA can boot from it.
     pub fn power_down(&mut self) -> Result<(), Error> {
+        if self.four_byte_mode {
+            self.write_enable()?;
+            self.chip_select()?;
+            self.transport
+                .send_spi(&[0xE9 /* FC_EX4B // Exit 4-byte mode */])?;
+            self.chip_deselect()?;
+            self.four_byte_mode = false;
+        }
+        if self.extended_address != 0 {
+            self.set_extended_address(0)?;
+        }
+
         let mut cmd = [0xB9 /* FC_PD */];
         self.chip_select()?;
-        self.vcp.xfer_spi(&mut cmd)?;
+        self.transport.xfer_spi(&mut cmd)?;
         self.chip_deselect()?;
         Ok(())
     }
 
-    pub fn read_id(&mut self) -> Result<(), Error> {
+    pub fn read_id(&mut self) -> Result<FlashId, Error> {
         /* JEDEC ID structure:
          * Byte No. | Data Type
          * ---------+----------
@@ -87,41 +324,185 @@ impl Flash {
             println!("read flash ID..");
         }
 
-        self.chip_select()?;
+        self.with_context("read ID", None, |flash| {
+            flash.chip_select()?;
+
+            // Write command and read first 4 bytes
+            flash.transport.xfer_spi(&mut data[0..=4])?;
+
+            if data[4] == 0xFF {
+                println!(
+                    "Extended Device String Length is 0xFF, this is likely a read error. Ignorig..."
+                );
+            } else {
+                // Read extended JEDEC ID bytes
+                if data[4] != 0 {
+                    len += data[4] as usize;
+                    flash.transport.xfer_spi(&mut data[5..len])?;
+                }
+            }
 
-        // Write command and read first 4 bytes
-        self.vcp.xfer_spi(&mut data[0..=4])?;
+            flash.chip_deselect()
+        })?;
 
-        if data[4] == 0xFF {
+        let id = FlashId::from_bytes(&data[1..len]);
+        println!("flash ID: {}", id);
+        self.part = id.part();
+        self.failure_report = id.failure_report();
+        self.geometry = self.part.map(Geometry::from);
+        if let Some(part) = self.part {
+            println!("flash part: {}", part);
+        } else if !id.is_valid() {
+            println!("no flash chip detected, check the wiring");
+        } else {
             println!(
-                "Extended Device String Length is 0xFF, this is likely a read error. Ignorig..."
+                "unknown flash part from {}",
+                id.manufacturer_name().unwrap_or("unknown manufacturer")
             );
-        } else {
-            // Read extended JEDEC ID bytes
-            if data[4] != 0 {
-                len += data[4] as usize;
-                let (_, mut jedec_data) = data.split_at_mut(4);
-                self.vcp.xfer_spi(&mut jedec_data)?;
-            }
         }
+        Ok(id)
+    }
+
+    /// Run `f`, naming `operation` and `address` in any transport error it
+    /// returns.
+    fn with_context<R, F>(
+        &mut self,
+        operation: &'static str,
+        address: Option<usize>,
+        f: F,
+    ) -> Result<R, Error>
+    where
+        F: FnOnce(&mut Self) -> Result<R, Error>,
+    {
+        f(self).map_err(|e| e.during(operation, address))
+    }
+
+    fn addressing(&self) -> Addressing {
+        self.geometry
+            .as_ref()
+            .map_or(Addressing::ThreeByte, |g| g.addressing)
+    }
 
+    /// The part's own 4-byte opcode for erasing `size` bytes, which not
+    /// every erase size has.
+    fn erase_opcode_4b(&self, size: usize) -> Result<u8, Error> {
+        self.geometry
+            .iter()
+            .flat_map(|g| g.erase_opcodes_4b.iter())
+            .find(|(erase_size, _)| *erase_size == size)
+            .map(|(_, opcode)| *opcode)
+            .ok_or_else(|| {
+                Error::Unsupported(format!(
+                    "this part has no 4-byte opcode for {} kB erase",
+                    size / 1024
+                ))
+            })
+    }
+
+    fn set_extended_address(&mut self, value: u8) -> Result<(), Error> {
+        self.write_enable()?;
+        self.chip_select()?;
+        self.transport.send_spi(&[
+            0xC5, /* FC_WEAR // Write Extended Address Register */
+            value,
+        ])?;
         self.chip_deselect()?;
+        self.extended_address = value;
+        Ok(())
+    }
 
-        // TODO: Add full decode of the JEDEC ID.
-        print!("flash ID:");
-        for b in &data[1..len] {
-            print!(" 0x{:02X}", b);
+    /// Build the opcode and address of a command touching `len` bytes at
+    /// `addr`, putting the part into whichever addressing mode it needs.
+    /// Addresses past the end of the part are an error rather than being
+    /// allowed to wrap around to the start.
+    fn address_command(&mut self, opcode: u8, addr: usize, len: usize) -> Result<Vec<u8>, Error> {
+        let size = self.geometry.as_ref().map_or(1 << 24, |g| g.size);
+        if addr + len > size {
+            return Err(Error::OutOfRange {
+                address: addr,
+                len,
+                size,
+            });
         }
-        println!();
-        Ok(())
+
+        let bytes = (addr as u32).to_be_bytes();
+        let mut command = vec![opcode];
+        match self.addressing() {
+            Addressing::ThreeByte => command.extend_from_slice(&bytes[1..]),
+            Addressing::FourByteOpcodes => {
+                let erase = matches!(opcode, 0x20 | 0x52 | 0xD8);
+                if erase && addr + len <= 1 << 24 && self.erase_opcode_4b(len).is_err() {
+                    // Erases without a 4-byte opcode still reach the bottom 16 MiB
+                    command.extend_from_slice(&bytes[1..]);
+                    return Ok(command);
+                }
+                command[0] = match opcode {
+                    0x02 => 0x12, /* FC_PP4B */
+                    0x03 => 0x13, /* FC_RD4B */
+                    0x0B => 0x0C, /* FC_FR4B */
+                    0x3B => 0x3C, /* FC_FRD4B */
+                    0x6B => 0x6C, /* FC_FRQ4B */
+                    0x20 | 0x52 | 0xD8 => self.erase_opcode_4b(len)?,
+                    x => x,
+                };
+                command.extend_from_slice(&bytes);
+            }
+            Addressing::FourByteMode => {
+                if !self.four_byte_mode {
+                    // Some parts (e.g. Micron) want write enable first
+                    self.write_enable()?;
+                    self.chip_select()?;
+                    self.transport
+                        .send_spi(&[0xB7 /* FC_EN4B // Enter 4-byte mode */])?;
+                    self.chip_deselect()?;
+                    self.four_byte_mode = true;
+                }
+                command.extend_from_slice(&bytes);
+            }
+            Addressing::ExtendedAddress => {
+                if bytes[0] != self.extended_address {
+                    self.set_extended_address(bytes[0])?;
+                }
+                command.extend_from_slice(&bytes[1..]);
+            }
+        }
+        Ok(command)
+    }
+
+    /// Read `data.len()` bytes of the SFDP address space starting at `addr`.
+    pub fn read_sfdp_bytes(&mut self, addr: usize, data: &mut [u8]) -> Result<(), Error> {
+        let command = [
+            0x5A, /* FC_RSFDP // Read SFDP */
+            (addr >> 16) as u8,
+            (addr >> 8) as u8,
+            addr as u8,
+            0x00, /* dummy */
+        ];
+
+        self.with_context("read SFDP", Some(addr), |flash| {
+            flash.chip_select()?;
+            flash.transport.send_spi(&command)?;
+            flash.transport.xfer_spi(data)?;
+            flash.chip_deselect()
+        })
+    }
+
+    /// Read and parse the part's SFDP tables, if it has them.
+    pub fn read_sfdp(&mut self) -> Result<Option<Sfdp>, Error> {
+        if self.verbose {
+            println!("read SFDP..");
+        }
+        Sfdp::parse(|addr, data| self.read_sfdp_bytes(addr, data))
     }
 
     pub fn cdone(&mut self) -> Result<bool, Error> {
-        // ADBUS6 (GPIOL2)
-        match self.vcp.readb_low()? & 0x40 {
-            0 => Ok(false),
-            _ => Ok(true),
+        let pin = self.pins.cdone;
+        let gpio = match pin.bank {
+            Bank::Low => self.transport.read_gpio(),
+            Bank::High => self.transport.read_gpio_high(),
         }
+        .map_err(|e| Error::transport("read CDONE", None, e))?;
+        Ok((gpio & pin.mask() != 0) != pin.inverted)
     }
 
     pub fn cdone_str(&mut self) -> Result<&'static str, Error> {
@@ -132,6 +513,39 @@ impl Flash {
         }
     }
 
+    /// Load a bitstream straight into the FPGA's configuration SRAM,
+    /// leaving the flash alone.  Holding SS low while CRESET is released
+    /// puts the iCE40 into SPI slave mode, after which it takes the
+    /// bitstream over the same lines the flash uses.
+    pub fn program_sram(&mut self, bitstream: &[u8]) -> Result<(), Error> {
+        // SS and CRESET low
+        self.set_cs_creset(false, false)?;
+        sleep(Duration::from_micros(100));
+
+        // SS low, CRESET high, then give the FPGA time to clear its SRAM
+        self.set_cs_creset(false, true)?;
+        sleep(Duration::from_micros(2000));
+
+        if self.verbose {
+            println!("cdone: {}", self.cdone_str()?);
+        }
+
+        self.transport
+            .send_spi(bitstream)
+            .map_err(|e| Error::transport("SRAM programming", None, e))?;
+
+        // The FPGA needs at least 49 more clocks to start up
+        self.transport
+            .send_spi(&[0; 6])
+            .and_then(|_| self.transport.xfer_spi_bits(0, 1))
+            .map_err(|e| Error::transport("SRAM programming", None, e))?;
+
+        if !self.cdone()? {
+            return Err(Error::NotConfigured);
+        }
+        Ok(())
+    }
+
     pub fn write_enable(&mut self) -> Result<(), Error> {
         if self.verbose {
             println!("status before enable:");
@@ -141,7 +555,7 @@ impl Flash {
 
         let mut data = [0x06 /* FC_WE // Write Enable */];
         self.chip_select()?;
-        self.vcp.xfer_spi(&mut data)?;
+        self.transport.xfer_spi(&mut data)?;
         self.chip_deselect()?;
 
         if self.verbose {
@@ -154,30 +568,38 @@ impl Flash {
     pub fn bulk_erase(&mut self) -> Result<(), Error> {
         println!("bulk erase..");
         let mut data = [0xC7 /* FC_CE // Chip Erase */];
-        self.chip_select()?;
-        self.vcp.xfer_spi(&mut data)?;
-        self.chip_deselect()?;
-        Ok(())
+        self.with_context("bulk erase", None, |flash| {
+            flash.chip_select()?;
+            flash.transport.xfer_spi(&mut data)?;
+            flash.chip_deselect()
+        })
     }
 
     pub fn sector_erase(&mut self, erase_type: EraseType, addr: usize) -> Result<(), Error> {
         let erase_cmd = match erase_type {
+            EraseType::Kb4 => 0x20,  /* FC_SE // Sector Erase 4kb */
+            EraseType::Kb32 => 0x52, /* FC_BE32 // Block Erase 32kb */
             EraseType::Kb64 => 0xD8, /* FC_BE64 // Block Erase 64kb */
         };
 
-        println!("erase 64kB sector at 0x{:06X}..", addr);
-        self.chip_select()?;
-        self.vcp
-            .send_spi(&[erase_cmd, (addr >> 16) as u8, (addr >> 8) as u8, addr as u8])?;
-        self.chip_deselect()?;
-        Ok(())
+        println!(
+            "erase {}kB sector at 0x{:06X}..",
+            erase_type.size() / 1024,
+            addr
+        );
+        self.with_context("erase", Some(addr), |flash| {
+            let command = flash.address_command(erase_cmd, addr, erase_type.size())?;
+            flash.chip_select()?;
+            flash.transport.send_spi(&command)?;
+            flash.chip_deselect()
+        })
     }
 
     pub fn read_status(&mut self) -> Result<u8, Error> {
         let mut data = [0x05 /* FC_RSR1 // Read Status Register 1 */, 0x00];
 
         self.chip_select()?;
-        self.vcp.xfer_spi(&mut data)?;
+        self.transport.xfer_spi(&mut data)?;
         self.chip_deselect()?;
 
         if self.verbose {
@@ -253,89 +675,182 @@ impl Flash {
         // Write Status Register 1 <- 0x00
         let mut data = [0x01 /* FC_WSR1 // Write Status Register 1 */, 0x00];
         self.chip_select()?;
-        self.vcp.xfer_spi(&mut data)?;
+        self.transport.xfer_spi(&mut data)?;
         self.chip_deselect()?;
 
-        self.wait()?;
+        self.wait(Operation::WriteStatus)?;
 
         // Read Status Register 1
         data[0] = 0x05; // FC_RSR1;
 
         self.chip_select()?;
-        self.vcp.xfer_spi(&mut data)?;
+        self.transport.xfer_spi(&mut data)?;
         self.chip_deselect()?;
 
         if data[1] != 0x00 {
-            println!(
-                "failed to disable protection, SR now equal to 0x{:02x} (expected 0x00)\n",
-                data[1]
-            );
+            return Err(Error::Flash {
+                operation: "disable protection",
+                status: data[1],
+            });
         }
 
         Ok(())
     }
 
-    pub fn wait(&mut self) -> Result<(), Error> {
+    /// Poll until the part finishes `operation`, returning how long that
+    /// took.  Parts that report program and erase failures are checked
+    /// afterwards.
+    pub fn wait(&mut self, operation: Operation) -> Result<Duration, Error> {
+        self.run_and_wait(Queue::new(), operation)
+    }
+
+    /// Whether to have the adapter wait on the board's ready line, and at
+    /// which level, rather than polling the status register.  The adapter
+    /// gives up on a read after 5 s and can't be interrupted while it
+    /// waits, so only operations that are over in milliseconds use it.
+    fn ready_level(&self, operation: Operation) -> Option<bool> {
+        match operation {
+            Operation::PageProgram | Operation::WriteStatus | Operation::Erase(EraseType::Kb4) => {
+                self.pins.ready.map(|pin| !pin.inverted)
+            }
+            _ => None,
+        }
+    }
+
+    /// Run `queue`, which starts `operation`, and then wait for the part
+    /// to finish.  Each round trip to the adapter either waits on the
+    /// ready line or reads the status register several times over, so
+    /// the end of an operation is seen within microseconds of the last
+    /// round trip.  The part counts as done after three ready reads in a
+    /// row.
+    fn run_and_wait(&mut self, mut queue: Queue, operation: Operation) -> Result<Duration, Error> {
         if self.verbose {
             println!("waiting..");
         }
 
+        let size = self.geometry.as_ref().map_or(1 << 24, |g| g.size);
+        let timeout = operation.timeout(size);
+        let ready_level = self.ready_level(operation);
+        let start = Instant::now();
         let mut count = 0;
-        loop {
-            let mut data = [0x05 /* FC_RSR1 // Read Status Register 1 */, 0x00];
+        let (status, security) = 'poll: loop {
+            // RY/BY# is driven whether or not the part is selected
+            if let Some(high) = ready_level {
+                queue.wait_gpiol1(high);
+            }
+            let polls = (0..STATUS_POLLS)
+                .map(|_| {
+                    let status = self.queue_status_read(&mut queue);
+                    (status, self.queue_security_read(&mut queue, operation))
+                })
+                .collect::<Vec<_>>();
 
-            self.chip_select()?;
-            self.vcp.xfer_spi(&mut data)?;
-            self.chip_deselect()?;
+            let reply = self
+                .transport
+                .submit(&queue)
+                .map_err(|e| Error::transport("wait", None, e))?;
+            queue = Queue::new();
 
-            if (data[1] & 0x01) == 0 {
-                if count < 2 {
-                    count += 1;
-                    if self.verbose {
-                        print!("r");
-                        //fflush(stderr);
+            for (status, security) in polls {
+                let (status, security) = (reply[status], security.map(|offset| reply[offset]));
+                if (status & 0x01) == 0 {
+                    if count < 2 {
+                        count += 1;
+                        if self.verbose {
+                            print!("r");
+                        }
+                    } else {
+                        if self.verbose {
+                            print!("R");
+                        }
+                        break 'poll (status, security);
                     }
                 } else {
                     if self.verbose {
-                        print!("R");
-                        // fflush(stderr);
+                        print!(".");
                     }
-                    break;
+                    count = 0;
                 }
-            } else {
+            }
+
+            if start.elapsed() > timeout {
                 if self.verbose {
-                    print!(".");
-                    // fflush(stderr);
+                    println!();
                 }
-                count = 0;
+                return Err(Error::Timeout {
+                    operation: operation.name(),
+                    elapsed: start.elapsed(),
+                });
             }
 
-            sleep(Duration::from_micros(1_000));
-        }
+            sleep(operation.poll_interval());
+        };
 
+        let elapsed = start.elapsed();
         if self.verbose {
             println!();
+            println!("{} took {} ms", operation.name(), elapsed.as_millis());
         }
 
+        self.check_failure(operation, status, security)?;
+        Ok(elapsed)
+    }
+
+    /// Look for the flags some parts raise when a program or erase fails.
+    /// AT25DF parts set EPE in status register 1; Macronix parts set P_FAIL
+    /// or E_FAIL in the security register.
+    fn check_failure(
+        &self,
+        operation: Operation,
+        status: u8,
+        security: Option<u8>,
+    ) -> Result<(), Error> {
+        let flags = match (operation, self.failure_report, security) {
+            (Operation::WriteStatus, _, _) => return Ok(()),
+            (_, FailureReport::Sr1Epe, _) => status & 0x20, /* EPE */
+            // P_FAIL and E_FAIL in the security register
+            (Operation::PageProgram, _, Some(security)) => security & 0x20,
+            (_, _, Some(security)) => security & 0x40,
+            _ => return Ok(()),
+        };
+        if flags != 0 {
+            return Err(Error::Flash {
+                operation: operation.name(),
+                status: flags,
+            });
+        }
         Ok(())
     }
 
+    /// Queue a read of the security register along with a status poll on
+    /// parts that keep their failure flags there, so checking them costs
+    /// no extra round trip.  Returns the offset of the register's value.
+    fn queue_security_read(&self, queue: &mut Queue, operation: Operation) -> Option<usize> {
+        if self.failure_report != FailureReport::SecurityRegister
+            || operation == Operation::WriteStatus
+        {
+            return None;
+        }
+        self.queue_cs_creset(queue, false, false);
+        let offset = queue.xfer_spi(&[0x2B /* FC_RDSCUR // Read Security Register */, 0x00]);
+        self.queue_cs_creset(queue, true, false);
+        Some(offset + 1)
+    }
+
     pub fn prog(&mut self, addr: usize, data: &[u8]) -> Result<(), Error> {
         if self.verbose {
             println!("prog 0x{:06X} +0x{:03X}..", addr, data.len());
         }
 
-        let command = [
-            0x02, /* FC_PP // Page Program */
-            (addr >> 16) as u8,
-            (addr >> 8) as u8,
-            addr as u8,
-        ];
+        self.with_context("page program", Some(addr), |flash| {
+            let command =
+                flash.address_command(0x02 /* FC_PP // Page Program */, addr, data.len())?;
 
-        self.chip_select()?;
-        self.vcp.send_spi(&command)?;
-        self.vcp.send_spi(data)?;
-        self.chip_deselect()?;
+            flash.chip_select()?;
+            flash.transport.send_spi(&command)?;
+            flash.transport.send_spi(data)?;
+            flash.chip_deselect()
+        })?;
 
         // if verbose {
         //     for (int i = 0; i < n; i++)
@@ -349,21 +864,984 @@ impl Flash {
             println!("read 0x{:06X} +0x{:03X}..", addr, data.len());
         }
 
-        let command = [
-            0x03, /*FC_RD // Read Data */
-            (addr >> 16) as u8,
-            (addr >> 8) as u8,
-            addr as u8,
-        ];
+        // With the extended address register, reads can't cross from one
+        // 16 MiB bank into the next.
+        let bank_end = (addr | 0xff_ffff) + 1;
+        if self.addressing() == Addressing::ExtendedAddress && addr + data.len() > bank_end {
+            let (first, rest) = data.split_at_mut(bank_end - addr);
+            self.read(addr, first)?;
+            return self.read(bank_end, rest);
+        }
 
-        self.chip_select()?;
-        self.vcp.send_spi(&command)?;
-        self.vcp.xfer_spi(data)?;
-        self.chip_deselect()?;
+        self.with_context("read", Some(addr), |flash| {
+            let mut command = flash.address_command(flash.read_mode.opcode(), addr, data.len())?;
+            if flash.read_mode != ReadMode::Standard {
+                command.push(0x00); /* dummy */
+            }
+
+            flash.chip_select()?;
+            flash.transport.send_spi(&command)?;
+            flash.transport.read_wide(flash.read_mode.lanes(), data)?;
+            flash.chip_deselect()
+        })?;
 
         // if (verbose)
         //     for (int i = 0; i < n; i++)
         //         fprintf(stderr, "%02x%c", data[i], i == n - 1 || i % 32 == 31 ? '\n' : ' ');
         Ok(())
     }
+
+    pub fn read_mode(&self) -> ReadMode {
+        self.read_mode
+    }
+
+    /// Switch the command `read` uses.  Dual and quad reads need a
+    /// transport with enough data lines, and quad reads need the part
+    /// identified so its Quad Enable bit can be set.
+    pub fn set_read_mode(&mut self, mode: ReadMode) -> Result<(), Error> {
+        if mode.lanes() > self.transport.read_lanes() {
+            return Err(Error::Unsupported(format!(
+                "{:?} reads need {} data lines, but this adapter only has {}",
+                mode,
+                mode.lanes(),
+                self.transport.read_lanes()
+            )));
+        }
+        if mode == ReadMode::Quad {
+            if self.geometry.is_none() {
+                return Err(Error::Unsupported(
+                    "quad reads need a known flash part".to_owned(),
+                ));
+            }
+            self.enable_quad()?;
+        }
+        self.read_mode = mode;
+        Ok(())
+    }
+
+    pub fn read_status2(&mut self) -> Result<u8, Error> {
+        let mut data = [0x35 /* FC_RSR2 // Read Status Register 2 */, 0x00];
+        self.chip_select()?;
+        self.transport.xfer_spi(&mut data)?;
+        self.chip_deselect()?;
+        Ok(data[1])
+    }
+
+    fn write_status_registers(&mut self, command: &[u8]) -> Result<(), Error> {
+        self.write_enable()?;
+        self.chip_select()?;
+        self.transport.send_spi(command)?;
+        self.chip_deselect()?;
+        self.wait(Operation::WriteStatus)?;
+        Ok(())
+    }
+
+    /// Set the part's Quad Enable bit, if it has one, so that IO2 and IO3
+    /// act as data lines.
+    pub fn enable_quad(&mut self) -> Result<(), Error> {
+        let quad_enable = match self.geometry.as_ref().and_then(|g| g.quad_enable) {
+            Some(quad_enable) => quad_enable,
+            None => return Ok(()),
+        };
+
+        let is_set = |flash: &mut Self| -> Result<bool, Error> {
+            Ok(match quad_enable {
+                QuadEnable::Sr1Bit6 => flash.read_status()? & 0x40 != 0,
+                _ => flash.read_status2()? & 0x02 != 0,
+            })
+        };
+        if is_set(self)? {
+            return Ok(());
+        }
+
+        if self.verbose {
+            println!("set quad enable..");
+        }
+        let sr1 = self.read_status()?;
+        match quad_enable {
+            QuadEnable::Sr1Bit6 => self.write_status_registers(&[0x01, sr1 | 0x40])?,
+            QuadEnable::Sr2Bit1 => {
+                let sr2 = self.read_status2()?;
+                self.write_status_registers(&[0x01, sr1, sr2 | 0x02])?
+            }
+            QuadEnable::Sr2Bit1Write31 => {
+                let sr2 = self.read_status2()?;
+                self.write_status_registers(&[0x31 /* FC_WSR2 */, sr2 | 0x02])?
+            }
+        }
+
+        if !is_set(self)? {
+            return Err(Error::Flash {
+                operation: "set quad enable",
+                status: self.read_status()?,
+            });
+        }
+        Ok(())
+    }
+
+    /// The erase operations the attached part supports.  Parts that haven't
+    /// been identified only get 64 kB block erase, which every part has.
+    /// Parts with erase sizes swot has no command for are refused rather
+    /// than left partly unerased.
+    pub fn erase_types(&self) -> Result<Vec<EraseType>, Error> {
+        let geometry = match &self.geometry {
+            Some(geometry) => geometry,
+            None => return Ok(vec![EraseType::Kb64]),
+        };
+        let mut types = vec![];
+        for size in &geometry.erase_sizes {
+            match EraseType::from_size(*size) {
+                Some(erase_type) => types.push(erase_type),
+                None => {
+                    return Err(Error::Unsupported(format!(
+                        "this part erases in {} kB blocks, which swot can't use",
+                        size / 1024
+                    )))
+                }
+            }
+        }
+        if types.is_empty() {
+            return Err(Error::Unsupported(
+                "this part has no erase size swot can use".to_owned(),
+            ));
+        }
+        Ok(types)
+    }
+
+    /// The erase operations that can reach all of `[offset, offset + len)`.
+    /// Above 16 MiB, parts using 4-byte opcodes can only erase the sizes
+    /// they have a 4-byte opcode for.
+    fn erase_types_for(&self, offset: usize, len: usize) -> Result<Vec<EraseType>, Error> {
+        let mut types = self.erase_types()?;
+        if self.addressing() == Addressing::FourByteOpcodes && offset + len > 1 << 24 {
+            types.retain(|t| self.erase_opcode_4b(t.size()).is_ok());
+        }
+        if types.is_empty() {
+            return Err(Error::Unsupported(
+                "this part has no 4-byte erase opcodes".to_owned(),
+            ));
+        }
+        Ok(types)
+    }
+
+    /// Program `data` at `addr`, splitting it at page boundaries.
+    pub fn program(&mut self, addr: usize, data: &[u8]) -> Result<(), Error> {
+        let page_size = self.geometry.as_ref().map_or(256, |g| g.page_size);
+        let mut offset = 0;
+        while offset < data.len() {
+            let page_addr = addr + offset;
+            let len = (page_size - page_addr % page_size).min(data.len() - offset);
+            if self.verbose {
+                self.write_enable()?;
+                self.prog(page_addr, &data[offset..offset + len])?;
+                self.wait(Operation::PageProgram)?;
+            } else {
+                self.program_page(page_addr, &data[offset..offset + len])?;
+            }
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Write enable, program one page and start polling the status, all
+    /// in a single round trip to the adapter.
+    fn program_page(&mut self, addr: usize, data: &[u8]) -> Result<(), Error> {
+        let command = self.with_context("page program", Some(addr), |flash| {
+            flash.address_command(0x02 /* FC_PP // Page Program */, addr, data.len())
+        })?;
+
+        let mut queue = Queue::new();
+        self.queue_cs_creset(&mut queue, false, false);
+        queue.send_spi(&[0x06 /* FC_WE // Write Enable */]);
+        self.queue_cs_creset(&mut queue, true, false);
+        self.queue_cs_creset(&mut queue, false, false);
+        queue.send_spi(&command);
+        queue.send_spi(data);
+        self.queue_cs_creset(&mut queue, true, false);
+
+        self.run_and_wait(queue, Operation::PageProgram)?;
+        Ok(())
+    }
+
+    /// Erase at least `[offset, offset + len)` with as few operations as
+    /// possible.  Erases are rounded out to whole sectors; with `preserve`
+    /// set, the bytes outside the range that this would clobber are read
+    /// first and programmed back afterwards.
+    pub fn erase_range(&mut self, offset: usize, len: usize, preserve: bool) -> Result<(), Error> {
+        let plan = plan_erase(offset, len, &self.erase_types_for(offset, len)?);
+        let (start, end) = match (plan.first(), plan.last()) {
+            (Some(first), Some(last)) => (first.1, last.1 + last.0.size()),
+            _ => return Ok(()),
+        };
+
+        let mut head = vec![0; if preserve { offset - start } else { 0 }];
+        let mut tail = vec![0; if preserve { end - (offset + len) } else { 0 }];
+        if !head.is_empty() {
+            self.read(start, &mut head)?;
+        }
+        if !tail.is_empty() {
+            self.read(offset + len, &mut tail)?;
+        }
+
+        for (erase_type, addr) in plan {
+            self.write_enable()?;
+            self.sector_erase(erase_type, addr)?;
+            if self.verbose {
+                println!("Status after block erase:");
+                self.read_status()?;
+            }
+            self.wait(Operation::Erase(erase_type))?;
+        }
+
+        // Erased flash already reads as 0xFF, so only program what differs.
+        if head.iter().any(|b| *b != 0xff) {
+            self.program(start, &head)?;
+        }
+        if tail.iter().any(|b| *b != 0xff) {
+            self.program(offset + len, &tail)?;
+        }
+        Ok(())
+    }
+
+    /// Bring `[offset, offset + data.len())` up to date with as little work
+    /// as possible.  Each erase block is read back first: blocks that
+    /// already match are skipped, blocks where the new data only clears
+    /// bits are programmed without erasing, and the rest are erased and
+    /// rewritten.  Bytes outside the range that share a block are kept.
+    pub fn program_diff(&mut self, offset: usize, data: &[u8]) -> Result<DiffStats, Error> {
+        let page_size = self.geometry.as_ref().map_or(256, |g| g.page_size);
+        let mut stats = DiffStats::default();
+
+        let types = self.erase_types_for(offset, data.len())?;
+        for (erase_type, addr) in plan_erase(offset, data.len(), &types) {
+            let mut current = vec![0; erase_type.size()];
+            self.read(addr, &mut current)?;
+
+            let mut wanted = current.clone();
+            let start = offset.max(addr);
+            let end = (offset + data.len()).min(addr + current.len());
+            wanted[start - addr..end - addr].copy_from_slice(&data[start - offset..end - offset]);
+
+            if wanted == current {
+                stats.skipped += 1;
+                continue;
+            }
+
+            let needs_erase = current.iter().zip(&wanted).any(|(c, w)| c & w != *w);
+            if needs_erase {
+                self.write_enable()?;
+                self.sector_erase(erase_type, addr)?;
+                self.wait(Operation::Erase(erase_type))?;
+                stats.erased += 1;
+            } else {
+                stats.programmed += 1;
+            }
+
+            for (idx, page) in wanted.chunks(page_size).enumerate() {
+                let old = &current[idx * page_size..idx * page_size + page.len()];
+                let unchanged = if needs_erase {
+                    page.iter().all(|b| *b == 0xff)
+                } else {
+                    page == old
+                };
+                if !unchanged {
+                    self.program(addr + idx * page_size, page)?;
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Read back `[offset, offset + data.len())` and compare it with
+    /// `data`.
+    pub fn verify(&mut self, offset: usize, data: &[u8]) -> Result<VerifyReport, Error> {
+        let mut report = VerifyReport::default();
+        let mut buffer = vec![0; data.len()];
+        self.read(offset, &mut buffer)?;
+        report.compare(offset, data, &buffer);
+        Ok(report)
+    }
+}
+
+/// What `Flash::program_diff` had to do, counted in erase blocks.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiffStats {
+    pub erased: usize,
+    pub programmed: usize,
+    pub skipped: usize,
+}
+
+impl fmt::Display for DiffStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} blocks erased, {} programmed without erase, {} unchanged",
+            self.erased, self.programmed, self.skipped
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstream::PREAMBLE;
+    use crate::parts::QuadEnable;
+    use crate::simulator::{program, sim_flash, start, test_pattern, verify, SimFlash, SimTiming};
+    use ftdi_vcp_rs::emulator::{Emulator, Event};
+
+    #[test]
+    fn erase_plan() {
+        use EraseType::*;
+        let all = [Kb4, Kb32, Kb64];
+
+        assert_eq!(plan_erase(0, 0, &all), vec![]);
+        assert_eq!(plan_erase(0, 1, &all), vec![(Kb4, 0)]);
+        assert_eq!(plan_erase(0x10000, 0x10000, &all), vec![(Kb64, 0x10000)]);
+        assert_eq!(
+            plan_erase(0x7000, 0x1A000, &all),
+            vec![
+                (Kb4, 0x7000),
+                (Kb32, 0x8000),
+                (Kb64, 0x10000),
+                (Kb4, 0x20000)
+            ]
+        );
+        assert_eq!(plan_erase(0x1800, 0x800, &[Kb64, Kb4]), vec![(Kb4, 0x1000)]);
+        assert_eq!(plan_erase(0x1800, 0x800, &[Kb64]), vec![(Kb64, 0)]);
+        assert_eq!(
+            plan_erase(0xF000, 0x2000, &[Kb64]),
+            vec![(Kb64, 0), (Kb64, 0x10000)]
+        );
+    }
+
+    #[test]
+    fn wait_gives_up_on_a_stuck_part() {
+        let mut flash = sim_flash();
+        flash.transport.set_stuck_busy(true);
+        flash.write_enable().unwrap();
+        flash.prog(0, &[0x00]).unwrap();
+        match flash.wait(Operation::PageProgram) {
+            Err(crate::error::Error::Timeout { operation, elapsed }) => {
+                assert_eq!(operation, "page program");
+                assert!(elapsed >= Operation::PageProgram.timeout(0));
+            }
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn write_failures_are_reported() {
+        // AT25DF parts flag failures with EPE, Macronix parts with the
+        // security register, and other parts can't report them at all.
+        for (id, reported) in [
+            ([0x1F, 0x45, 0x01, 0x00], true),
+            ([0x1F, 0x86, 0x01, 0x00], false),
+            ([0xC2, 0x20, 0x18, 0x00], true),
+            ([0xEF, 0x40, 0x18, 0x00], false),
+        ]
+        .iter()
+        {
+            let mut sim = SimFlash::new(16 * 1024 * 1024);
+            sim.set_id(id);
+            sim.set_fail_writes(true);
+            let mut flash = start(Flash::new(sim));
+            flash.read_id().unwrap();
+
+            let result = flash.erase_range(0, 0x1000, false);
+            assert_eq!(
+                matches!(result, Err(crate::error::Error::Flash { .. })),
+                *reported,
+                "{:02X?}: {:?}",
+                id,
+                result
+            );
+            let result = flash.program(0, &[0x00]);
+            assert_eq!(
+                matches!(result, Err(crate::error::Error::Flash { .. })),
+                *reported,
+                "{:02X?}: {:?}",
+                id,
+                result
+            );
+            assert_eq!(flash.transport.memory()[0], 0xff);
+
+            flash.transport.set_fail_writes(false);
+            flash.program(0, &[0x00]).unwrap();
+            assert_eq!(flash.transport.memory()[0], 0x00);
+        }
+    }
+
+    #[test]
+    fn tb_is_not_epe() {
+        // Bit 5 is the TB protection bit on the AT25SF parts, so having it
+        // set mustn't make every write look like a failure
+        let mut sim = SimFlash::new(2 * 1024 * 1024);
+        sim.set_id(&[0x1F, 0x86, 0x01, 0x00]);
+        sim.set_status(0x20 /* TB */);
+        let mut flash = start(Flash::new(sim));
+        flash.read_id().unwrap();
+
+        flash.erase_range(0, 0x1000, false).unwrap();
+        flash.program(0, &[0x00]).unwrap();
+        assert_eq!(flash.transport.memory()[0], 0x00);
+        assert_eq!(flash.transport.status() & 0x20, 0x20);
+    }
+
+    #[test]
+    fn read_id_identifies_part() {
+        let mut flash = sim_flash();
+        let id = flash.read_id().unwrap();
+        assert_eq!(id.manufacturer, 0xEF);
+        assert_eq!(id.device, 0x4018);
+        assert_eq!(flash.part().unwrap().name, "W25Q128");
+
+        flash
+            .transport
+            .set_id(&[0x01, 0x02, 0x19, 0x02, 0x4D, 0x01]);
+        let id = flash.read_id().unwrap();
+        assert_eq!(id.extended, vec![0x4D, 0x01]);
+        assert_eq!(flash.part().unwrap().name, "S25FL256S");
+
+        flash.transport.set_id(&[0xff; 4]);
+        assert!(!flash.read_id().unwrap().is_valid());
+        assert!(flash.part().is_none());
+    }
+
+    #[test]
+    fn read_sfdp() {
+        let mut flash = sim_flash();
+        let sfdp = flash.read_sfdp().unwrap().unwrap();
+        assert_eq!(sfdp.density, 16 * 1024 * 1024);
+        assert_eq!(sfdp.geometry().erase_sizes, vec![4096, 32768, 65536]);
+
+        flash.transport.set_sfdp(&[]);
+        assert_eq!(flash.read_sfdp().unwrap(), None);
+    }
+
+    #[test]
+    fn erase_range_uses_small_sectors() {
+        let mut flash = sim_flash();
+        flash.read_id().unwrap();
+        flash.transport.memory_mut()[0x2FFF] = 0x00;
+        flash.transport.memory_mut()[0x3000] = 0x00;
+        flash.transport.memory_mut()[0x8000] = 0x00;
+
+        // 0x3000..0x7100 is covered by five 4K sectors, so data on either
+        // side of it survives.
+        flash.erase_range(0x3000, 0x4100, false).unwrap();
+        let memory = flash.transport.memory();
+        assert_eq!(memory[0x2FFF], 0x00);
+        assert_eq!(memory[0x3000], 0xff);
+        assert_eq!(memory[0x8000], 0x00);
+    }
+
+    #[test]
+    fn erase_range_preserves_neighbours() {
+        let mut flash = sim_flash();
+        flash.read_id().unwrap();
+        let pattern = test_pattern(0x2000);
+        flash.transport.memory_mut()[0x1000..0x3000].copy_from_slice(&pattern);
+
+        flash.erase_range(0x1800, 0x1000, true).unwrap();
+        let memory = flash.transport.memory();
+        assert_eq!(&memory[0x1000..0x1800], &pattern[..0x800]);
+        assert!(memory[0x1800..0x2800].iter().all(|b| *b == 0xff));
+        assert_eq!(&memory[0x2800..0x3000], &pattern[0x1800..]);
+
+        // Without preserve the whole of both sectors is lost.
+        flash.erase_range(0x1800, 0x1000, false).unwrap();
+        assert!(flash.transport.memory()[0x1000..0x3000]
+            .iter()
+            .all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn differential_programming() {
+        use crate::flash::DiffStats;
+
+        let mut flash = sim_flash();
+        flash.read_id().unwrap();
+        flash.transport.memory_mut()[0x1000] = 0x12;
+        let mut image = test_pattern(0x2E00);
+
+        // A blank part only needs bits cleared.
+        let stats = flash.program_diff(0x1100, &image).unwrap();
+        assert_eq!(
+            stats,
+            DiffStats {
+                erased: 0,
+                programmed: 3,
+                skipped: 0
+            }
+        );
+        assert!(verify(&mut flash, 0x1100, &image));
+
+        let stats = flash.program_diff(0x1100, &image).unwrap();
+        assert_eq!(stats.skipped, 3);
+
+        // Setting bits forces an erase of that block only, and the byte
+        // before the image in the same block survives it.
+        image[0x10] = !image[0x10];
+        let stats = flash.program_diff(0x1100, &image).unwrap();
+        assert_eq!(
+            stats,
+            DiffStats {
+                erased: 1,
+                programmed: 0,
+                skipped: 2
+            }
+        );
+        assert!(verify(&mut flash, 0x1100, &image));
+        assert_eq!(flash.transport.memory()[0x1000], 0x12);
+    }
+
+    #[test]
+    fn read_modes() {
+        let mut flash = sim_flash();
+        flash.read_id().unwrap();
+        let bitstream = test_pattern(0x1000);
+        program(&mut flash, 0x100, &bitstream);
+
+        for mode in [ReadMode::Standard, ReadMode::Fast, ReadMode::Dual].iter() {
+            flash.set_read_mode(*mode).unwrap();
+            assert!(verify(&mut flash, 0x100, &bitstream), "{:?}", mode);
+        }
+
+        assert_eq!(flash.transport.status2() & 0x02, 0);
+        flash.set_read_mode(ReadMode::Quad).unwrap();
+        assert_eq!(flash.transport.status2() & 0x02, 0x02);
+        assert!(verify(&mut flash, 0x100, &bitstream));
+
+        // Reading with QE cleared behind our back gives garbage.
+        flash.transport.set_status2(0);
+        assert!(!verify(&mut flash, 0x100, &bitstream));
+    }
+
+    #[test]
+    fn quad_enable_in_sr1() {
+        let mut flash = sim_flash();
+        flash.read_id().unwrap();
+        let mut geometry = flash.geometry().unwrap().clone();
+        geometry.quad_enable = Some(QuadEnable::Sr1Bit6);
+        flash.set_geometry(geometry);
+
+        flash.set_read_mode(ReadMode::Quad).unwrap();
+        assert_eq!(flash.transport.status() & 0x40, 0x40);
+        assert_eq!(flash.transport.status2(), 0);
+    }
+
+    #[test]
+    fn parts_without_quad_enable_are_left_alone() {
+        let mut sim = SimFlash::new(1024 * 1024);
+        sim.set_id(&[0x1F, 0x45, 0x01, 0x00]);
+        let mut flash = start(Flash::new(sim));
+        flash.read_id().unwrap();
+
+        flash.set_read_mode(ReadMode::Quad).unwrap();
+        assert_eq!(flash.transport.status(), 0);
+        assert_eq!(flash.transport.status2(), 0);
+    }
+
+    #[test]
+    fn mpsse_reads_are_single_lane() {
+        let mut flash = start(Flash::new(Emulator::new(SimFlash::new(1024 * 1024))));
+        flash.read_id().unwrap();
+        assert!(matches!(
+            flash.set_read_mode(ReadMode::Dual),
+            Err(crate::error::Error::Unsupported(_))
+        ));
+        assert_eq!(flash.read_mode(), ReadMode::Standard);
+
+        let bitstream = test_pattern(0x100);
+        program(&mut flash, 0, &bitstream);
+        flash.set_read_mode(ReadMode::Fast).unwrap();
+        assert!(verify(&mut flash, 0, &bitstream));
+    }
+
+    /// A 32 MiB part reached through `addressing`
+    fn large_flash(addressing: Addressing) -> Flash<SimFlash> {
+        let mut sim = SimFlash::new(32 * 1024 * 1024);
+        sim.set_id(&[0xEF, 0x40, 0x19, 0x00]);
+        let mut flash = start(Flash::new(sim));
+        flash.read_id().unwrap();
+        let mut geometry = flash.geometry().unwrap().clone();
+        geometry.addressing = addressing;
+        flash.set_geometry(geometry);
+        flash
+    }
+
+    #[test]
+    fn four_byte_addressing() {
+        let bitstream = test_pattern(0x300);
+        for addressing in [
+            Addressing::FourByteOpcodes,
+            Addressing::FourByteMode,
+            Addressing::ExtendedAddress,
+        ]
+        .iter()
+        {
+            let mut flash = large_flash(*addressing);
+            program(&mut flash, 0x1FF_0100, &bitstream);
+            assert!(
+                verify(&mut flash, 0x1FF_0100, &bitstream),
+                "{:?}",
+                addressing
+            );
+            assert!(flash.transport.memory()[0xFF_0100..0xFF_0400]
+                .iter()
+                .all(|b| *b == 0xff));
+
+            assert_eq!(
+                flash.transport.is_four_byte_mode(),
+                *addressing == Addressing::FourByteMode
+            );
+            flash.power_down().unwrap();
+            assert!(!flash.transport.is_four_byte_mode());
+            assert_eq!(flash.transport.extended_address(), 0);
+        }
+    }
+
+    #[test]
+    fn four_byte_erases_follow_the_part() {
+        // W25Q256 has 32 kB erase, but no 4-byte version of it
+        let mut flash = large_flash(Addressing::FourByteOpcodes);
+        flash.transport.set_missing_opcodes(&[0x5C]);
+        assert_eq!(
+            flash.erase_types().unwrap(),
+            vec![EraseType::Kb4, EraseType::Kb32, EraseType::Kb64]
+        );
+        assert!(matches!(
+            flash.sector_erase(EraseType::Kb32, 0x1FF_8000),
+            Err(crate::error::Error::Unsupported(_))
+        ));
+
+        // The 3-byte version still works in the bottom 16 MiB
+        flash.transport.memory_mut()[0x7FFF] = 0x00;
+        flash.transport.memory_mut()[0x8000] = 0x00;
+        flash.transport.memory_mut()[0x10000] = 0x00;
+        flash.erase_range(0x8000, 0x8000, false).unwrap();
+        assert_eq!(flash.transport.memory()[0x7FFF], 0x00);
+        assert_eq!(flash.transport.memory()[0x8000], 0xff);
+        assert_eq!(flash.transport.memory()[0x10000], 0x00);
+        flash.transport.memory_mut()[0x18000] = 0x00;
+        flash.write_enable().unwrap();
+        flash.sector_erase(EraseType::Kb32, 0x18000).unwrap();
+        flash.wait(Operation::Erase(EraseType::Kb32)).unwrap();
+        assert_eq!(flash.transport.memory()[0x18000], 0xff);
+
+        for byte in &mut flash.transport.memory_mut()[0x1FF_0000..0x1FF_8000] {
+            *byte = 0x00;
+        }
+        flash.erase_range(0x1FF_0000, 0x8000, false).unwrap();
+        assert!(flash.transport.memory()[0x1FF_0000..0x1FF_8000]
+            .iter()
+            .all(|b| *b == 0xff));
+
+        // Parts whose table has it use it
+        let mut geometry = flash.geometry().unwrap().clone();
+        geometry.erase_opcodes_4b.push((32 * 1024, 0x5C));
+        flash.set_geometry(geometry);
+        flash.transport.set_missing_opcodes(&[]);
+        flash.transport.memory_mut()[0x1FF_8000] = 0x00;
+        flash.write_enable().unwrap();
+        flash.sector_erase(EraseType::Kb32, 0x1FF_8000).unwrap();
+        flash.wait(Operation::Erase(EraseType::Kb32)).unwrap();
+        assert_eq!(flash.transport.memory()[0x1FF_8000], 0xff);
+    }
+
+    #[test]
+    fn extended_address_reads_cross_banks() {
+        let mut flash = large_flash(Addressing::ExtendedAddress);
+        let pattern = test_pattern(0x200);
+        flash.transport.memory_mut()[0xFF_FF00..0x100_0100].copy_from_slice(&pattern);
+        assert!(verify(&mut flash, 0xFF_FF00, &pattern));
+    }
+
+    #[test]
+    fn addresses_past_the_end_are_rejected() {
+        let mut flash = sim_flash();
+        flash.read_id().unwrap();
+        let mut buffer = [0; 0x10];
+        assert!(matches!(
+            flash.read(0xFF_FFF8, &mut buffer),
+            Err(crate::error::Error::OutOfRange {
+                address: 0xFF_FFF8,
+                len: 0x10,
+                size: 0x100_0000
+            })
+        ));
+        assert!(matches!(
+            flash.read(0x100_0000, &mut buffer),
+            Err(crate::error::Error::OutOfRange { .. })
+        ));
+
+        let mut flash = large_flash(Addressing::FourByteOpcodes);
+        assert!(matches!(
+            flash.sector_erase(EraseType::Kb64, 0x200_0000),
+            Err(crate::error::Error::OutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn verify_reports_mismatches() {
+        let mut flash = sim_flash();
+        flash.read_id().unwrap();
+        let bitstream = test_pattern(0x400);
+        program(&mut flash, 0x1000, &bitstream);
+
+        // Corrupt a run that straddles a 256 byte read, and one lone byte
+        let memory = flash.transport.memory_mut();
+        for byte in &mut memory[0x10FE..0x1102] {
+            *byte ^= 0xff;
+        }
+        memory[0x1300] = !memory[0x1300];
+
+        let report = flash.verify(0x1000, &bitstream).unwrap();
+        assert_eq!(report.checked, 0x400);
+        assert_eq!(report.bad_bytes(), 5);
+        assert_eq!(report.mismatches.len(), 2);
+        assert_eq!(report.mismatches[0].address, 0x10FE);
+        assert_eq!(report.mismatches[0].expected, &bitstream[0xFE..0x102]);
+        assert_eq!(report.mismatches[1].address, 0x1300);
+        assert_eq!(report.mismatches[1].actual, vec![!bitstream[0x300]]);
+    }
+
+    #[test]
+    fn unknown_parts_only_use_64k_erase() {
+        let mut flash = sim_flash();
+        flash.transport.memory_mut()[0x0000] = 0x00;
+        flash.erase_range(0xF000, 0x100, false).unwrap();
+        assert_eq!(flash.transport.memory()[0x0000], 0xff);
+    }
+
+    #[test]
+    fn unusable_erase_sizes_are_refused() {
+        let mut flash = sim_flash();
+        flash.read_id().unwrap();
+        let mut geometry = flash.geometry().unwrap().clone();
+        geometry.erase_sizes = vec![256 * 1024];
+        flash.set_geometry(geometry);
+        flash.transport.memory_mut()[0] = 0x00;
+
+        for result in [
+            flash.erase_range(0, 0x1000, false),
+            flash.program_diff(0, &[0x12; 0x1000]).map(|_| ()),
+        ]
+        .iter()
+        {
+            assert!(
+                matches!(result, Err(crate::error::Error::Unsupported(_))),
+                "{:?}",
+                result
+            );
+        }
+        assert_eq!(flash.transport.memory()[0], 0x00);
+    }
+
+    #[test]
+    fn sram_programming() {
+        let mut bitstream = vec![0xFF, 0x00, b'h', b'i', 0x00, 0xFF];
+        bitstream.extend_from_slice(&PREAMBLE);
+        bitstream.extend_from_slice(&test_pattern(5000));
+
+        let mut flash = sim_flash();
+        flash.program_sram(&bitstream).unwrap();
+        assert_eq!(&flash.transport.sram()[..bitstream.len()], &bitstream[..]);
+        assert!(flash.cdone().unwrap());
+        // The flash saw 0xFF as an opcode, and so ignored everything else
+        assert_eq!(flash.transport.memory(), &vec![0xff; 1024 * 1024][..]);
+
+        let mut flash = start(Flash::new(Emulator::new(SimFlash::new(1024 * 1024))));
+        flash.program_sram(&bitstream).unwrap();
+        assert!(flash.cdone().unwrap());
+        assert!(matches!(
+            flash.program_sram(&test_pattern(1000)),
+            Err(crate::error::Error::NotConfigured)
+        ));
+    }
+
+    #[test]
+    fn page_program_is_one_round_trip() {
+        let mut sim = SimFlash::new(1024 * 1024);
+        sim.set_timing(SimTiming {
+            page_program: Duration::from_micros(0),
+            ..SimTiming::default()
+        });
+        let mut flash = start(Flash::new(Emulator::new(sim)));
+        flash.transport.take_events();
+
+        let bitstream = test_pattern(0x200);
+        flash.program(0x2000, &bitstream).unwrap();
+        let events = flash.transport.take_events();
+        assert_eq!(events.iter().filter(|e| **e == Event::Flush).count(), 2);
+        assert_eq!(events.last(), Some(&Event::Flush));
+        assert_eq!(
+            &flash.transport.peripheral().memory()[0x2000..0x2200],
+            &bitstream[..]
+        );
+
+        // A part that stays busy is polled until it times out
+        flash.transport.peripheral_mut().set_stuck_busy(true);
+        assert!(matches!(
+            flash.program(0x3000, &[0x00]),
+            Err(crate::error::Error::Timeout { .. })
+        ));
+
+        // Macronix failure flags are read along with the status
+        let mut sim = SimFlash::new(1024 * 1024);
+        sim.set_id(&[0xC2, 0x20, 0x18, 0x00]);
+        sim.set_timing(SimTiming {
+            page_program: Duration::from_micros(0),
+            ..SimTiming::default()
+        });
+        let mut flash = start(Flash::new(Emulator::new(sim)));
+        flash.read_id().unwrap();
+        flash.transport.take_events();
+        flash.program(0x2000, &bitstream).unwrap();
+        let events = flash.transport.take_events();
+        assert_eq!(events.iter().filter(|e| **e == Event::Flush).count(), 2);
+
+        flash.transport.peripheral_mut().set_fail_writes(true);
+        assert!(matches!(
+            flash.program(0x3000, &[0x00]),
+            Err(crate::error::Error::Flash { status: 0x20, .. })
+        ));
+    }
+
+    fn ready_line_flash(sim: SimFlash) -> Flash<Emulator<SimFlash>> {
+        use crate::board::{Pins, GPIOL1};
+
+        let mut flash = start(Flash::new(Emulator::new(sim)));
+        flash.transport.peripheral_mut().set_ready_line(true);
+        flash.set_pins(Pins {
+            ready: Some(GPIOL1),
+            ..Pins::default()
+        });
+        flash.transport.take_events();
+        flash
+    }
+
+    #[test]
+    fn waits_use_the_ready_line() {
+        let mut sim = SimFlash::new(1024 * 1024);
+        sim.set_timing(SimTiming {
+            page_program: Duration::from_millis(20),
+            ..SimTiming::default()
+        });
+        let mut flash = ready_line_flash(sim);
+        let count = |flash: &mut Flash<Emulator<SimFlash>>, event: Event| {
+            flash
+                .transport
+                .take_events()
+                .iter()
+                .filter(|e| **e == event)
+                .count()
+        };
+
+        // The adapter holds the status reads back until the part goes
+        // ready, so the page is done in one round trip
+        let started = Instant::now();
+        flash.program(0x100, &test_pattern(0x10)).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        let events = flash.transport.take_events();
+        assert_eq!(events.iter().filter(|e| **e == Event::Flush).count(), 1);
+        assert!(events.contains(&Event::Wait { high: true }));
+        assert_eq!(
+            &flash.transport.peripheral().memory()[0x100..0x110],
+            &test_pattern(0x10)[..]
+        );
+
+        // Block erases can take longer than the adapter will wait, so they
+        // are polled
+        flash.write_enable().unwrap();
+        flash.sector_erase(EraseType::Kb64, 0).unwrap();
+        flash.transport.take_events();
+        flash.wait(Operation::Erase(EraseType::Kb64)).unwrap();
+        assert_eq!(count(&mut flash, Event::Wait { high: true }), 0);
+    }
+
+    #[test]
+    fn stuck_ready_line_fails() {
+        let mut flash = ready_line_flash(SimFlash::new(1024 * 1024));
+        flash.transport.peripheral_mut().set_stuck_busy(true);
+        flash.transport.set_read_timeout(Duration::from_millis(50));
+        assert!(matches!(
+            flash.program(0x100, &[0x00]),
+            Err(crate::error::Error::Transport {
+                operation: Some("wait"),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn reads_stream_through_mpsse_emulator() {
+        let mut flash = start(Flash::new(Emulator::new(SimFlash::new(1024 * 1024))));
+        let pattern = test_pattern(0x2_8000);
+        flash.transport.peripheral_mut().memory_mut()[0x1000..0x2_9000].copy_from_slice(&pattern);
+        flash.transport.take_events();
+
+        let mut data = vec![0; pattern.len()];
+        flash.read(0x1000, &mut data).unwrap();
+        assert_eq!(data, pattern);
+
+        // One read command, then input-only requests of at most 4 KiB
+        let events = flash.transport.take_events();
+        let reads = events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Shift { command, bits, .. } if *command == 0x20 => Some(*bits / 8),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(reads.len(), 0x28);
+        assert!(reads.iter().all(|len| *len == 0x1000));
+        assert_eq!(
+            events.iter().filter(|e| **e == Event::Flush).count(),
+            reads.len()
+        );
+    }
+
+    #[test]
+    fn program_through_mpsse_emulator() {
+        let mut flash = start(Flash::new(Emulator::new(SimFlash::new(1024 * 1024))));
+        assert!(!flash.cdone().unwrap());
+
+        let bitstream = test_pattern(1000);
+        program(&mut flash, 0x100, &bitstream);
+        assert!(verify(&mut flash, 0x100, &bitstream));
+        assert_eq!(
+            &flash.transport.peripheral().memory()[0x100..0x100 + 1000],
+            &bitstream[..]
+        );
+
+        // Page programs are sent as command and data with chip select held low.
+        flash.transport.take_events();
+        flash.prog(0x1000, &[0x12, 0x34]).unwrap();
+        assert_eq!(
+            flash.transport.take_events(),
+            vec![
+                Event::SetLow {
+                    value: 0x00,
+                    direction: 0x93
+                },
+                Event::Shift {
+                    command: 0x11,
+                    bits: 32,
+                    mosi: vec![0x02, 0x00, 0x10, 0x00],
+                    miso: vec![0xff; 4],
+                },
+                Event::Shift {
+                    command: 0x11,
+                    bits: 16,
+                    mosi: vec![0x12, 0x34],
+                    miso: vec![0xff; 2],
+                },
+                Event::SetLow {
+                    value: 0x10,
+                    direction: 0x93
+                },
+            ]
+        );
+    }
 }
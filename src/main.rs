@@ -5,313 +5,665 @@
 
 #define FT_LIST_MASK (FT_LIST_NUMBER_ONLY|FT_LIST_BY_INDEX|FT_LIST_ALL)
 */
-use clap::{App, Arg};
-use ftdi_vcp_rs::{mpsse::Command::*, BitMode, VCP};
+use bitstream::Bitstream;
+use cli::{Command, DeviceSelector, EraseMode, Options};
+use error::Error;
+use flash::Flash;
+use ftdi_vcp_rs::{BitMode, VCP};
+use parts::{Addressing, Geometry};
 use std::fs::File;
-use std::io::Seek;
-use std::io::{Read, Write};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use transport::Transport;
 
+mod bitstream;
+mod board;
+mod cli;
+mod error;
 mod flash;
-
-fn parse_size(input: &str) -> Result<usize, &'static str> {
-    let multiple_index = input
-        .chars()
-        .position(|c| !(c.is_numeric() || c == '.'))
-        .unwrap_or(input.len());
-
-    let (value, multiple) = &input.split_at(multiple_index);
-    let value = value.parse::<usize>().map_err(|_| "unable to parse")?;
-    let multiple = match multiple.trim().to_lowercase().as_str() {
-        "m" | "mib" => 1024 * 1024,
-        "k" | "kib" => 1024,
-        "b" | "" | "bytes" => 1,
-        "g" | "gib" => 1024 * 1024 * 1024,
-        x => {
-            println!("Unrecognized suffix {}", x);
-            return Err("unrecognized suffix")
-        },
-    };
-    Ok(value * multiple)
+mod multiboot;
+mod parts;
+mod sfdp;
+#[cfg(test)]
+mod simulator;
+mod transport;
+mod verify;
+
+fn open_device(selector: &DeviceSelector) -> Result<VCP, ftdi_vcp_rs::Error> {
+    match selector {
+        DeviceSelector::Description(description) => VCP::new_from_name(description),
+        DeviceSelector::Serial(serial) => VCP::open_by_serial(serial),
+        DeviceSelector::Location(location) => VCP::open_by_location(*location),
+        DeviceSelector::Index(index) => VCP::open_by_index(*index),
+    }
 }
 
-#[test]
-fn parse_size_sanity() {
-    assert_eq!(parse_size("0").unwrap(), 0);
-    assert_eq!(parse_size("1024").unwrap(), 1024);
-    assert_eq!(parse_size("1k").unwrap(), 1024);
-    assert_eq!(parse_size("1K").unwrap(), 1024);
-    assert_eq!(parse_size("1 K").unwrap(), 1024);
-    assert_eq!(parse_size("1 k").unwrap(), 1024);
-    assert_eq!(parse_size("1 kiB").unwrap(), 1024);
-    assert_eq!(parse_size("1 M").unwrap(), 1024*1024);
-    assert_eq!(parse_size("2 M").unwrap(), 1024*1024*2);
-}
+fn list_devices() -> Result<(), Error> {
+    let devices =
+        ftdi_vcp_rs::list_devices().map_err(|e| Error::transport("list devices", None, e))?;
+    if devices.is_empty() {
+        println!("No FTDI devices found");
+        return Ok(());
+    }
 
-fn main() -> Result<(), ftdi_vcp_rs::Error> {
-    let mut vcp = VCP::new_from_name("iCEBreaker V1.0e A").expect("couldn't open vcp");
-    let slow_clock = false;
-    let disable_verify = false;
-    let disable_protect = false;
-
-    let mut bitstream = vec![];
-
-    let matches = App::new("SWOT: the Spi Write Out Tool")
-        .version(clap::crate_version!())
-        .author("Sean Cross <sean@xobs.io>")
-        .about("Read and write SPI devices using an FTDI cable")
-        .arg(
-            Arg::with_name("FILENAME")
-                .help("Sets the bitstream file to read or write")
-                .required_unless("verbose")
-                .required_unless("test")
-                .index(1),
-        )
-        .arg(
-            Arg::with_name("verbose")
-                .short("v")
-                .multiple(true)
-                .help("Sets the level of verbosity"),
-        )
-        .arg(
-            Arg::with_name("bulk_erase")
-                .short("b")
-                .help("bulk erase entire flash before writing"),
-        )
-        .arg(
-            Arg::with_name("check_mode")
-                .short("c")
-                .help("do not write flash, only verify (`check')"),
-        )
-        .arg(
-            Arg::with_name("no_erase")
-                .short("n")
-                .help("do not erase flash before writing"),
-        )
-        .arg(
-            Arg::with_name("erase_size")
-                .short("e")
-                .takes_value(true)
-                .help("erase flash as if we were writing that number of bytes"),
-        )
-        .arg(
-            Arg::with_name("read_256")
-                .short("r")
-                .help("read first 256 kB from flash and write to file"),
-        )
-        .arg(
-            Arg::with_name("read_file")
-                .short("R")
-                .takes_value(true)
-                .conflicts_with("read_256")
-                .help("read the specified number of bytes from flash"),
-        )
-        .arg(
-            Arg::with_name("offset")
-                .short("o")
-                .takes_value(true)
-                .default_value("0")
-                .help("start address for read/write"),
-        )
-        .arg(Arg::with_name("test").short("t").help("Run tests"))
-        .get_matches();
-
-    let verbose = matches.is_present("verbose");
-    let test_mode = matches.is_present("test");
-    let bulk_erase = matches.is_present("bulk_erase");
-    let check_mode = matches.is_present("check_mode");
-    let dont_erase = matches.is_present("no_erase");
-    let rw_offset = matches.value_of("offset").map_or(0, |e: &str| {
-        parse_size(e).expect("unable to parse size")
-    });
-    let erase_size = matches.value_of("erase_size").map(|e| {
-        Some(parse_size(e).unwrap())
-    });
-    let read_size = if matches.is_present("read_256") {
-        Some(256 * 1024)
-    } else if let Some(size) = matches.value_of("read_file") {
-        Some(parse_size(size).unwrap())
-    } else {
-        None
-    };
+    for (index, device) in devices.iter().enumerate() {
+        println!(
+            "{:2}: {:<8} {:04x}:{:04x}  loc 0x{:08x}  serial {:<12} \"{}\"{}{}",
+            index,
+            device.device_type.to_string(),
+            device.vid,
+            device.pid,
+            device.location,
+            if device.serial_number.is_empty() {
+                "-"
+            } else {
+                &device.serial_number
+            },
+            device.description,
+            if device.high_speed {
+                " [high speed]"
+            } else {
+                ""
+            },
+            if device.opened { " [opened]" } else { "" },
+        );
+    }
+    Ok(())
+}
 
-    let mut bitstream_file = if let Some(filename) = matches.value_of("FILENAME") {
-        if read_size.is_none() && !test_mode {
-            let mut file = File::open(filename).expect("Couldn't open path");
-            file.read_to_end(&mut bitstream)
-                .expect("couldn't read to end");
-            Some(file)
-        } else {
-            Some(File::create(filename).expect("Couldn't create output file"))
-        }
-    } else {
-        None
-    };
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
 
+/// Open the adapter and put it into MPSSE mode, returning it along with
+/// the latency timer setting to restore afterwards.
+fn open_adapter(options: &Options) -> Result<(VCP, u8), Error> {
+    let device = cli::parse_device(&options.device).map_err(|e| Error::Argument(e.to_owned()))?;
+    let mut vcp = open_device(&device).map_err(|source| Error::Open {
+        device: options.device.clone(),
+        source,
+    })?;
     println!("Opened VCP: {:?}", vcp);
-    vcp.reset()?;
-    vcp.purge()?;
+    if options.verbose {
+        println!("board {}", options.board);
+    }
+    let setup = |e| Error::transport("configure adapter", None, e);
+    vcp.reset().map_err(setup)?;
+    vcp.purge().map_err(setup)?;
 
-    let previous_latency = vcp.latency_timer()?;
-    vcp.set_latency_timer(1)?;
+    let previous_latency = vcp.latency_timer().map_err(setup)?;
+    vcp.set_latency_timer(1).map_err(setup)?;
 
-    vcp.set_bitmode(0xff, BitMode::MPSSE)?;
+    vcp.set_bitmode(0xff, BitMode::MPSSE).map_err(setup)?;
 
-    // enable clock divide by 5
-    vcp.write(&[MC_TCK_D5.to_u8()])
-        .or_else(|_| Err(ftdi_vcp_rs::Error::IoError))?;
+    let frequency = vcp
+        .set_spi_frequency(options.frequency)
+        .map_err(|e| Error::transport("set SPI clock", None, e))?;
+    if options.verbose || frequency != options.frequency {
+        println!("SPI clock: {} kHz", frequency / 1000);
+    }
 
-    if slow_clock {
-        // set 50 kHz clock
-        vcp.write(&[MC_SET_CLK_DIV.to_u8(), 119, 0x00])
-            .or_else(|_| Err(ftdi_vcp_rs::Error::IoError))?;
-    } else {
-        // set 6 MHz clock
-        vcp.write(&[MC_SET_CLK_DIV.to_u8(), 0x00, 0x00])
-            .or_else(|_| Err(ftdi_vcp_rs::Error::IoError))?;
+    Ok((vcp, previous_latency))
+}
+
+/// Check that a file about to be sent to the FPGA, or written where it
+/// boots from, is a bitstream for the right part.  Anything written
+/// further into the flash is usually firmware, so it is only described if
+/// it happens to be a bitstream.
+fn check_bitstream(
+    path: &str,
+    data: &[u8],
+    required: bool,
+    options: &Options,
+) -> Result<(), Error> {
+    match Bitstream::parse(data) {
+        Ok(bitstream) => {
+            println!("{}", bitstream);
+            if let (Some(expected), Some(actual)) = (options.fpga, bitstream.fpga) {
+                if expected != actual {
+                    println!(
+                        "warning: bitstream is for an {}, not an {}",
+                        actual.name(),
+                        expected.name()
+                    );
+                }
+            }
+            Ok(())
+        }
+        Err(e) if required && options.force => {
+            println!("warning: {}", e);
+            Ok(())
+        }
+        Err(source) if required => Err(Error::Bitstream {
+            path: path.to_owned(),
+            source,
+        }),
+        Err(_) => Ok(()),
     }
+}
 
-    let mut flash = flash::Flash::new(vcp);
-    flash.set_verbose(verbose);
-    flash.release_reset()?;
+/// Read and check the bitstreams for `swot multiboot`, returning either
+/// the one image to put in a slot or the whole multiboot image.
+fn multiboot_image(
+    images: &[(String, Option<usize>)],
+    align: usize,
+    power_on: usize,
+    single_slot: bool,
+    options: &Options,
+) -> Result<Vec<u8>, Error> {
+    let mut bitstreams = vec![];
+    for (path, _) in images {
+        let data = std::fs::read(path).map_err(|e| Error::file(path, e))?;
+        check_bitstream(path, &data, true, options)?;
+        bitstreams.push(data);
+    }
+    if single_slot {
+        return Ok(bitstreams.remove(0));
+    }
 
-    sleep(Duration::from_micros(100_000));
+    let sizes = bitstreams.iter().map(Vec::len).collect::<Vec<_>>();
+    let requested = images.iter().map(|(_, offset)| *offset).collect::<Vec<_>>();
+    let offsets = multiboot::layout(&sizes, &requested, align)?;
+    for (slot, ((path, _), offset)) in images.iter().zip(&offsets).enumerate() {
+        println!(
+            "slot {}: {} at 0x{:06X}{}",
+            slot,
+            path,
+            offset,
+            if slot == power_on { " (power-on)" } else { "" }
+        );
+    }
+    Ok(multiboot::build(&bitstreams, &offsets, power_on))
+}
 
-    if test_mode {
+/// Reset the flash, wake it up and work out its layout.
+fn start<T: Transport>(flash: &mut Flash<T>, options: &Options) -> Result<(), Error> {
+    if options.verbose {
         println!("reset..");
+    }
 
-        flash.chip_deselect()?;
-        sleep(Duration::from_micros(250_000));
+    flash.chip_deselect()?;
+    sleep(Duration::from_micros(250_000));
 
+    if options.verbose {
         println!("cdone: {}", flash.cdone_str()?);
+    }
 
-        flash.reset()?;
-        flash.power_up()?;
+    flash.reset()?;
+    flash.power_up()?;
 
-        flash.read_id()?;
+    if !flash.read_id()?.is_valid() {
+        return Err(Error::NoFlash);
+    }
 
-        flash.power_down()?;
+    if flash.geometry().is_none() {
+        if let Some(sfdp) = flash.read_sfdp()? {
+            println!("{}", sfdp);
+            flash.set_geometry(sfdp.geometry());
+        } else if let Some(size) = options.board.flash_size {
+            // Go by what the board is fitted with, erasing in the block
+            // size every part supports
+            flash.set_geometry(Geometry {
+                size,
+                page_size: 256,
+                erase_sizes: vec![64 * 1024],
+                erase_opcodes_4b: vec![],
+                addressing: Addressing::ThreeByte,
+                quad_enable: None,
+            });
+        }
+    } else if options.verbose {
+        if let Some(sfdp) = flash.read_sfdp()? {
+            println!("{}", sfdp);
+        }
+    }
 
-        flash.release_reset()?;
-        sleep(Duration::from_micros(250_000));
+    flash.set_read_mode(options.read_mode)?;
+    if options.verbose {
+        println!("read mode: {:?}", flash.read_mode());
+    }
+    Ok(())
+}
 
-        println!("cdone: {}", flash.cdone_str()?);
-    } else {
-        // ---------------------------------------------------------
-        // Reset
-        // ---------------------------------------------------------
-        if verbose {
-            println!("reset..");
+/// Reject ranges that run off the end of the part before anything is
+/// erased.  Parts of unknown size are left to fail as they go.
+fn check_range<T: Transport>(flash: &Flash<T>, offset: usize, len: usize) -> Result<(), Error> {
+    match flash.geometry().map(|geometry| geometry.size) {
+        Some(size) if offset + len > size => {
+            println!(
+                "the part is a {}",
+                flash
+                    .part()
+                    .map_or("flash of unknown type", |part| part.name)
+            );
+            Err(Error::OutOfRange {
+                address: offset,
+                len,
+                size,
+            })
         }
+        _ => Ok(()),
+    }
+}
+
+fn verify<T: Transport>(
+    flash: &mut Flash<T>,
+    offset: usize,
+    data: &[u8],
+    verify_log: &Option<String>,
+) -> Result<(), Error> {
+    println!("reading..");
+    let start = Instant::now();
+    let report = flash.verify(offset, data)?;
+    report_throughput(data.len(), start.elapsed());
+
+    if let Some(path) = verify_log {
+        File::create(path)
+            .and_then(|file| report.write_log(file))
+            .map_err(|e| Error::file(path, e))?;
+    }
+
+    if !report.is_ok() {
+        print!("{}", report);
+        return Err(Error::VerifyFailed {
+            bad_bytes: report.bad_bytes(),
+            ranges: report.mismatches.len(),
+        });
+    }
+    println!("VERIFY OK");
+    Ok(())
+}
 
-        flash.chip_deselect()?;
-        sleep(Duration::from_micros(250_000));
+/// Say how long reading `len` bytes took and how fast that was.
+fn report_throughput(len: usize, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64().max(1e-6);
+    println!(
+        "read {} bytes in {:.2} s ({:.0} KiB/s)",
+        len,
+        secs,
+        len as f64 / 1024.0 / secs
+    );
+}
+
+fn bulk_erase<T: Transport>(flash: &mut Flash<T>) -> Result<(), Error> {
+    flash.write_enable()?;
+    flash.bulk_erase()?;
+    let elapsed = flash.wait(flash::Operation::ChipErase)?;
+    println!("bulk erase took {:.1} s", elapsed.as_secs_f32());
+    Ok(())
+}
 
-        if verbose {
-            println!("cdone: {}", flash.cdone_str()?);
+fn info<T: Transport>(flash: &mut Flash<T>) -> Result<(), Error> {
+    match flash.geometry() {
+        Some(geometry) => {
+            println!("size: {} bytes", geometry.size);
+            println!("page size: {} bytes", geometry.page_size);
+            let erase_sizes = geometry
+                .erase_sizes
+                .iter()
+                .map(|size| format!("{}K", size / 1024))
+                .collect::<Vec<_>>();
+            println!("erase sizes: {}", erase_sizes.join(" "));
+            println!("addressing: {:?}", geometry.addressing);
+            if let Some(quad_enable) = geometry.quad_enable {
+                println!("quad enable: {:?}", quad_enable);
+            }
         }
+        None => println!("layout unknown"),
+    }
+    println!("status: SR1 0x{:02X}", flash.read_status()?);
+    match flash.read_sfdp()? {
+        Some(sfdp) => println!("{}", sfdp),
+        None => println!("no SFDP"),
+    }
+    Ok(())
+}
 
-        flash.reset()?;
-        flash.power_up()?;
+fn dump<T: Transport>(flash: &mut Flash<T>, offset: usize, len: usize) -> Result<(), Error> {
+    let mut buffer = vec![0; len];
+    flash.read(offset, &mut buffer)?;
+    for (idx, line) in buffer.chunks(16).enumerate() {
+        let hex = line
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = line
+            .iter()
+            .map(|b| match b {
+                0x20..=0x7e => *b as char,
+                _ => '.',
+            })
+            .collect::<String>();
+        println!("0x{:06X}: {:<47}  |{}|", offset + idx * 16, hex, ascii);
+    }
+    Ok(())
+}
 
-        flash.read_id()?;
+/// Clear the block protection bits so that writes and erases take effect.
+fn unprotect<T: Transport>(flash: &mut Flash<T>) -> Result<(), Error> {
+    flash.write_enable()?;
+    flash.disable_protection()
+}
 
-        // ---------------------------------------------------------
-        // Program
-        // ---------------------------------------------------------
+fn write<T: Transport>(
+    flash: &mut Flash<T>,
+    options: &Options,
+    offset: usize,
+    data: &[u8],
+    erase: EraseMode,
+    read_back: bool,
+    verify_log: &Option<String>,
+) -> Result<(), Error> {
+    check_range(flash, offset, data.len())?;
+    if options.unprotect {
+        unprotect(flash)?;
+    }
 
-        if read_size.is_none() && !check_mode {
-            if disable_protect {
-                flash.write_enable()?;
-                flash.disable_protection()?;
-            }
+    println!("file size: {}", data.len());
+    if erase == EraseMode::Diff {
+        println!("programming changed blocks..");
+        let stats = flash.program_diff(offset, data)?;
+        println!("{}", stats);
+    } else {
+        match erase {
+            EraseMode::Bulk => bulk_erase(flash)?,
+            EraseMode::Sectors { preserve } => flash.erase_range(offset, data.len(), preserve)?,
+            _ => (),
+        }
 
-            if !dont_erase {
-                if bulk_erase {
-                    flash.write_enable()?;
-                    flash.bulk_erase()?;
-                    flash.wait()?;
-                } else {
-                    println!("file size: {}", bitstream.len());
-
-                    let begin_addr = rw_offset & !0xffff;
-                    let end_addr = (rw_offset + bitstream.len() + 0xffff) & !0xffff;
-
-                    for addr in (begin_addr..end_addr).step_by(0x10000) {
-                        flash.write_enable()?;
-                        flash.sector_erase(flash::EraseType::Kb64, addr)?;
-                        if verbose {
-                            println!("Status after block erase:");
-                            flash.read_status()?;
-                        }
-                        flash.wait()?;
-                    }
-                }
-            }
+        println!("programming..");
+        flash.program(offset, data)?;
+    }
 
-            if erase_size.is_none() {
-                println!("programming..");
+    if read_back {
+        verify(flash, offset, data, verify_log)?;
+    }
+    Ok(())
+}
 
-                for (idx, page) in bitstream.chunks(256).enumerate() {
-                    flash.write_enable()?;
-                    flash.prog(rw_offset + idx * 256, page)?;
-                    flash.wait()?;
+fn execute<T: Transport>(
+    flash: &mut Flash<T>,
+    options: &Options,
+    command: &Command,
+    data: &[u8],
+) -> Result<(), Error> {
+    match command {
+        Command::List | Command::Test | Command::Sram { .. } => unreachable!(),
+        Command::Info => info(flash)?,
+        Command::Dump { offset, len } => {
+            check_range(flash, *offset, *len)?;
+            dump(flash, *offset, *len)?;
+        }
+        Command::Read { file, offset, len } => {
+            let len = match (len, flash.geometry()) {
+                (Some(len), _) => *len,
+                (None, Some(geometry)) => geometry.size.saturating_sub(*offset),
+                (None, None) => {
+                    return Err(Error::Argument(
+                        "the size of this flash is unknown, give a length to read".to_owned(),
+                    ))
                 }
-                /* seek to the beginning for second pass */
-                bitstream_file.as_mut()
-                    .expect("no bitstream file was specified")
-                    .seek(std::io::SeekFrom::Start(0))
-                    .expect("Couldn't rewind file");
+            };
+            check_range(flash, *offset, len)?;
+
+            println!("reading {} bytes..", len);
+            let mut buffer = vec![0; len];
+            let start = Instant::now();
+            flash.read(*offset, &mut buffer)?;
+            report_throughput(len, start.elapsed());
+
+            // Only create the file once there is something to put in it, so
+            // a failed read doesn't leave an empty dump behind
+            std::fs::write(file, &buffer).map_err(|e| Error::file(file, e))?;
+        }
+        Command::Write {
+            offset,
+            erase,
+            verify: read_back,
+            verify_log,
+            ..
+        } => write(
+            flash, options, *offset, data, *erase, *read_back, verify_log,
+        )?,
+        Command::Multiboot {
+            slot: None,
+            verify: read_back,
+            verify_log,
+            ..
+        } => {
+            let erase = EraseMode::Sectors { preserve: false };
+            write(flash, options, 0, data, erase, *read_back, verify_log)?
+        }
+        Command::Multiboot {
+            slot: Some(slot),
+            verify: read_back,
+            verify_log,
+            ..
+        } => {
+            if options.unprotect {
+                unprotect(flash)?;
+            }
+            let address = multiboot::update_slot(flash, *slot, data)?;
+            println!("slot {} written at 0x{:06X}", slot, address);
+            if *read_back {
+                verify(flash, address, data, verify_log)?;
             }
         }
-
-        // ---------------------------------------------------------
-        // Read/Verify
-        // ---------------------------------------------------------
-
-        if let Some(read_size) = read_size {
-            println!("reading {} bytes..", read_size);
-            // uint8_t buffer[256];
-            let out_file = bitstream_file.as_mut().expect("no output file found");
-            let mut buffer = [0u8; 256];
-            for addr in (0..read_size).step_by(256) {
-                flash.read(rw_offset + addr, &mut buffer)?;
-                out_file
-                    .write_all(&buffer)
-                    .expect("couldn't write to bitstream file");
+        Command::Verify {
+            offset, verify_log, ..
+        } => {
+            check_range(flash, *offset, data.len())?;
+            verify(flash, *offset, data, verify_log)?;
+        }
+        Command::Erase { range, preserve } => {
+            if let Some((offset, len)) = range {
+                check_range(flash, *offset, *len)?;
             }
-        } else if erase_size.is_none() && !disable_verify {
-            println!("reading..");
-            for (idx, page) in bitstream.chunks(256).enumerate() {
-                let mut buffer_flash = [0; 256];
-                flash.read(rw_offset + idx * 256, &mut buffer_flash)?;
-                if !page.iter().zip(buffer_flash.iter()).all(|(a, b)| a == b) {
-                    println!("Found difference between flash and file!");
-                }
+            if options.unprotect {
+                unprotect(flash)?;
+            }
+            match range {
+                Some((offset, len)) => flash.erase_range(*offset, *len, *preserve)?,
+                None => bulk_erase(flash)?,
             }
-
-            println!("VERIFY OK");
         }
+    }
+    Ok(())
+}
 
-        // ---------------------------------------------------------
-        // Reset
-        // ---------------------------------------------------------
+/// Toggle reset and read the flash ID, without changing anything.
+fn test<T: Transport>(flash: &mut Flash<T>) -> Result<(), Error> {
+    println!("reset..");
 
-        flash.power_down()?;
+    flash.chip_deselect()?;
+    sleep(Duration::from_micros(250_000));
 
-        flash.release_reset()?;
-        sleep(Duration::from_micros(250_000));
+    println!("cdone: {}", flash.cdone_str()?);
 
-        println!("cdone: {}", flash.cdone_str()?);
-    }
+    flash.reset()?;
+    flash.power_up()?;
+
+    flash.read_id()?;
+    Ok(())
+}
+
+/// Configure the FPGA from `bitstream` without going through the flash.
+fn sram<T: Transport>(flash: &mut Flash<T>, bitstream: &[u8]) -> Result<(), Error> {
+    println!("programming SRAM with {} bytes..", bitstream.len());
+    flash.program_sram(bitstream)?;
+    println!("cdone: high");
+    Ok(())
+}
+
+/// Put the flash to sleep and let the FPGA boot from it.
+fn finish(flash: &mut Flash<VCP>, previous_latency: u8) -> Result<(), Error> {
+    flash.power_down()?;
+
+    flash.release_reset()?;
+    sleep(Duration::from_micros(250_000));
 
-    if let Ok(com_port) = flash.vcp.com_port() {
+    println!("cdone: {}", flash.cdone_str()?);
+    close(flash, previous_latency)
+}
+
+/// Hand the adapter back the way it was found.
+fn close(flash: &mut Flash<VCP>, previous_latency: u8) -> Result<(), Error> {
+    if let Ok(com_port) = flash.transport.com_port() {
         println!("VCP COM{}:", com_port);
     } else {
         println!("No COM port assigned");
     }
 
-    flash.vcp.set_latency_timer(previous_latency)?;
+    flash.transport.set_latency_timer(previous_latency)?;
     Ok(())
 }
+
+fn run() -> Result<(), Error> {
+    let matches = cli::app().get_matches();
+    let (options, command) = cli::parse(&matches)?;
+    if command == Command::List {
+        return list_devices();
+    }
+
+    // Sort out the files before touching the hardware
+    let data = match (&command, command.input()) {
+        (
+            Command::Multiboot {
+                images,
+                align,
+                power_on,
+                slot,
+                ..
+            },
+            _,
+        ) => multiboot_image(images, *align, *power_on, slot.is_some(), &options)?,
+        (_, Some(path)) => std::fs::read(path).map_err(|e| Error::file(path, e))?,
+        (_, None) => vec![],
+    };
+    if let Command::Multiboot {
+        output: Some(path), ..
+    } = &command
+    {
+        std::fs::write(path, &data).map_err(|e| Error::file(path, e))?;
+        println!("wrote {} bytes to {}", data.len(), path);
+        return Ok(());
+    }
+    match &command {
+        Command::Write { file, offset, .. } => {
+            check_bitstream(file, &data, *offset == 0, &options)?
+        }
+        Command::Sram { file } => check_bitstream(file, &data, true, &options)?,
+        _ => (),
+    }
+    let (vcp, previous_latency) = open_adapter(&options)?;
+    let mut flash = Flash::new(vcp);
+    flash.set_pins(options.board.pins);
+    flash.set_verbose(options.verbose);
+    flash.release_reset()?;
+
+    sleep(Duration::from_micros(100_000));
+
+    if let Command::Sram { .. } = command {
+        // Letting go of SS leaves the FPGA running the new bitstream,
+        // whereas `finish` would reset it to boot from the flash.
+        let result = sram(&mut flash, &data);
+        let tidied = flash
+            .release_reset()
+            .and_then(|_| close(&mut flash, previous_latency));
+        return result.and(tidied);
+    }
+
+    let result = match command {
+        Command::Test => test(&mut flash),
+        _ => {
+            start(&mut flash, &options).and_then(|_| execute(&mut flash, &options, &command, &data))
+        }
+    };
+
+    // Hand the flash back to the FPGA even if something went wrong, but
+    // report the original failure rather than any from tidying up.
+    let tidied = finish(&mut flash, previous_latency);
+    result.and(tidied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use board::BoardProfile;
+    use ftdi_vcp_rs::emulator::{Emulator, Event};
+    use simulator::SimFlash;
+
+    fn parse_args(args: &[&str]) -> (Options, Command) {
+        cli::parse(&cli::app().get_matches_from(args)).unwrap()
+    }
+
+    #[test]
+    fn commands_run_against_the_emulator() {
+        let mut flash = Flash::new(Emulator::new(SimFlash::new(16 * 1024 * 1024)));
+        flash.release_reset().unwrap();
+        let data: Vec<u8> = (0..0x1234).map(|i| (i * 7) as u8).collect();
+
+        let (options, command) = parse_args(&["swot", "write", "top.bin", "-o", "64k"]);
+        start(&mut flash, &options).unwrap();
+        execute(&mut flash, &options, &command, &data).unwrap();
+        let memory = flash.transport.peripheral().memory();
+        assert_eq!(&memory[0x10000..0x11234], &data[..]);
+        assert!(memory[0x11234..0x20000].iter().all(|b| *b == 0xff));
+
+        let (options, command) = parse_args(&["swot", "verify", "top.bin", "-o", "64k"]);
+        execute(&mut flash, &options, &command, &data).unwrap();
+
+        let (options, command) = parse_args(&["swot", "erase", "-o", "64k", "-l", "4k"]);
+        execute(&mut flash, &options, &command, &[]).unwrap();
+        let (options, command) = parse_args(&["swot", "verify", "top.bin", "-o", "64k"]);
+        assert!(matches!(
+            execute(&mut flash, &options, &command, &data),
+            Err(Error::VerifyFailed { bad_bytes, .. }) if bad_bytes > 0
+        ));
+
+        let path = std::env::temp_dir().join(format!("swot-read-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        let (options, command) = parse_args(&["swot", "read", path, "-o", "68k", "-l", "564"]);
+        execute(&mut flash, &options, &command, &[]).unwrap();
+        let read = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(&read[..], &data[0x1000..]);
+    }
+
+    #[test]
+    fn boards_with_a_ready_line_wait_on_it() {
+        let (mut options, command) = parse_args(&["swot", "write", "top.bin", "-o", "64k"]);
+        options.board = BoardProfile::from_toml(
+            "description = \"RY/BY# on GPIOL1\"\n\
+             [pins]\ncs = \"ADBUS4\"\ncreset = \"ADBUS7\"\ncdone = \"ADBUS6\"\n\
+             ready = \"ADBUS5\"\n",
+        )
+        .unwrap();
+        let mut sim = SimFlash::new(16 * 1024 * 1024);
+        sim.set_ready_line(true);
+        let mut flash = Flash::new(Emulator::new(sim));
+        flash.set_pins(options.board.pins);
+        flash.release_reset().unwrap();
+        let data: Vec<u8> = (0..0x300).map(|i| (i * 3) as u8).collect();
+
+        start(&mut flash, &options).unwrap();
+        flash.transport.take_events();
+        execute(&mut flash, &options, &command, &data).unwrap();
+        assert_eq!(
+            &flash.transport.peripheral().memory()[0x10000..0x10300],
+            &data[..]
+        );
+        let waits = flash
+            .transport
+            .events()
+            .iter()
+            .filter(|e| **e == Event::Wait { high: true })
+            .count();
+        // One for each page, and one for each 4 kB erase
+        assert_eq!(waits, 3 + 1);
+    }
+}
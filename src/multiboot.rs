@@ -0,0 +1,262 @@
+//! Building multiboot images, as icemulti does.  An iCE40 that boots
+//! from flash first reads a table of five small "applet" headers at
+//! address 0: the first says which image to load at power-on, and the
+//! other four are the images SB_WARMBOOT can switch to.
+
+use crate::bitstream::{Bitstream, PREAMBLE};
+use crate::error::Error;
+use crate::flash::Flash;
+use crate::transport::Transport;
+
+/// How many images SB_WARMBOOT can choose between
+pub const SLOTS: usize = 4;
+
+pub const HEADER_SIZE: usize = 0x20;
+
+/// The power-on header followed by one for each slot
+pub const TABLE_SIZE: usize = HEADER_SIZE * (SLOTS + 1);
+
+/// Headers hold a 3-byte address
+const MAX_ADDRESS: usize = 0xFF_FFFF;
+
+/// An applet header telling the FPGA to boot the image at `address`.
+pub fn header(address: usize) -> Vec<u8> {
+    let mut header = PREAMBLE.to_vec();
+    header.extend_from_slice(&[
+        0x92,
+        0x00,
+        0x00, /* boot mode */
+        0x44,
+        0x03, /* boot address, read with opcode 0x03 */
+        (address >> 16) as u8,
+        (address >> 8) as u8,
+        address as u8,
+        0x82,
+        0x00,
+        0x00, /* bank offset */
+        0x01,
+        0x08, /* reboot */
+    ]);
+    header.resize(HEADER_SIZE, 0x00);
+    header
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Work out where each image goes.  Images without a requested address
+/// follow the previous one, starting at the next multiple of `align`.
+pub fn layout(
+    sizes: &[usize],
+    requested: &[Option<usize>],
+    align: usize,
+) -> Result<Vec<usize>, Error> {
+    if sizes.is_empty() || sizes.len() > SLOTS {
+        return Err(Error::Argument(format!(
+            "a multiboot image holds 1 to {} bitstreams",
+            SLOTS
+        )));
+    }
+    if !align.is_power_of_two() {
+        return Err(Error::Argument(format!(
+            "alignment {} is not a power of two",
+            align
+        )));
+    }
+
+    let mut next = align_up(TABLE_SIZE, align);
+    let mut offsets = vec![];
+    for (size, requested) in sizes.iter().zip(requested) {
+        let offset = requested.unwrap_or(next);
+        if offset + size > MAX_ADDRESS + 1 {
+            return Err(Error::Argument(format!(
+                "image at 0x{:X} is past the 16 MiB a header can point to",
+                offset
+            )));
+        }
+        offsets.push(offset);
+        next = align_up(offset + size, align);
+    }
+
+    let mut ranges = offsets
+        .iter()
+        .zip(sizes)
+        .enumerate()
+        .map(|(slot, (offset, size))| (*offset, offset + size, slot))
+        .collect::<Vec<_>>();
+    ranges.sort_unstable();
+    let mut end = TABLE_SIZE;
+    let mut previous = None;
+    for (start, stop, slot) in ranges {
+        if start < end {
+            return Err(Error::Argument(match previous {
+                Some(other) => format!("images {} and {} overlap", other, slot),
+                None => format!("image {} overlaps the header table", slot),
+            }));
+        }
+        end = stop;
+        previous = Some(slot);
+    }
+    Ok(offsets)
+}
+
+/// Put the header table and images together into one flash image, with
+/// the gaps left erased.  Slots without an image boot the power-on one.
+pub fn build(images: &[Vec<u8>], offsets: &[usize], power_on: usize) -> Vec<u8> {
+    let end = images
+        .iter()
+        .zip(offsets)
+        .map(|(image, offset)| offset + image.len())
+        .max()
+        .unwrap_or(TABLE_SIZE);
+    let mut data = vec![0xFF; end];
+
+    data[..HEADER_SIZE].copy_from_slice(&header(offsets[power_on]));
+    for slot in 0..SLOTS {
+        let address = offsets.get(slot).unwrap_or(&offsets[power_on]);
+        let start = HEADER_SIZE * (slot + 1);
+        data[start..start + HEADER_SIZE].copy_from_slice(&header(*address));
+    }
+    for (image, offset) in images.iter().zip(offsets) {
+        data[*offset..offset + image.len()].copy_from_slice(image);
+    }
+    data
+}
+
+/// The address each header in a table points to, power-on first.
+pub fn parse_table(table: &[u8]) -> Option<Vec<usize>> {
+    table
+        .chunks(HEADER_SIZE)
+        .take(SLOTS + 1)
+        .map(|header| {
+            Bitstream::parse(header)
+                .ok()
+                .filter(Bitstream::is_multiboot_header)
+                .and_then(|header| header.boot_address)
+        })
+        .collect()
+}
+
+/// Replace the image in one slot of the multiboot image already in the
+/// flash, leaving the headers and other images alone.  Returns the
+/// address the image was written to.
+pub fn update_slot<T: Transport>(
+    flash: &mut Flash<T>,
+    slot: usize,
+    image: &[u8],
+) -> Result<usize, Error> {
+    let mut table = vec![0; TABLE_SIZE];
+    flash.read(0, &mut table)?;
+    let addresses = parse_table(&table).ok_or_else(|| {
+        Error::Argument("the flash doesn't start with a multiboot header table".to_owned())
+    })?;
+
+    let slots = &addresses[1..];
+    let start = slots[slot];
+    // Slots without an image of their own repeat an earlier slot's address
+    if let Some(other) = (0..slot).find(|other| slots[*other] == start) {
+        return Err(Error::Argument(format!(
+            "slot {} has no image of its own, it boots slot {}'s",
+            slot, other
+        )));
+    }
+
+    // The image may grow up to the start of whatever comes next
+    let end = slots
+        .iter()
+        .filter(|address| **address > start)
+        .min()
+        .copied()
+        .or_else(|| flash.geometry().map(|geometry| geometry.size));
+    if let Some(end) = end {
+        if start + image.len() > end {
+            return Err(Error::Argument(format!(
+                "0x{:X} bytes don't fit in slot {}, which has room for 0x{:X}",
+                image.len(),
+                slot,
+                end - start
+            )));
+        }
+    }
+
+    flash.erase_range(start, image.len(), true)?;
+    flash.program(start, image)?;
+    Ok(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{program, sim_flash, test_pattern, verify};
+
+    #[test]
+    fn header_layout() {
+        assert_eq!(
+            &header(0x021000)[..16],
+            &[
+                0x7E, 0xAA, 0x99, 0x7E, 0x92, 0x00, 0x00, 0x44, 0x03, 0x02, 0x10, 0x00, 0x82, 0x00,
+                0x00, 0x01
+            ]
+        );
+        let parsed = Bitstream::parse(&header(0x021000)).unwrap();
+        assert!(parsed.is_multiboot_header());
+        assert_eq!(parsed.boot_address, Some(0x021000));
+    }
+
+    #[test]
+    fn images_are_placed_and_checked() {
+        assert_eq!(
+            layout(&[0x100, 0x20000, 0x10], &[None, None, None], 0x10000).unwrap(),
+            vec![0x10000, 0x20000, 0x40000]
+        );
+        assert_eq!(
+            layout(&[0x100, 0x100], &[None, Some(0x8000)], 0x100).unwrap(),
+            vec![0x100, 0x8000]
+        );
+        assert!(layout(&[0x100, 0x100], &[Some(0x1000), Some(0x1080)], 0x100).is_err());
+        assert!(layout(&[0x100], &[Some(0x80)], 0x100).is_err());
+        assert!(layout(&[0x100], &[None], 3000).is_err());
+        assert!(layout(&[0x100; 5], &[None; 5], 0x100).is_err());
+    }
+
+    #[test]
+    fn build_and_parse_table() {
+        let images = vec![vec![0x11; 0x2F0], vec![0x22; 0x80]];
+        let offsets = layout(&[0x2F0, 0x80], &[None, None], 0x100).unwrap();
+        let data = build(&images, &offsets, 1);
+        assert_eq!(data.len(), 0x400 + 0x80);
+        assert_eq!(
+            parse_table(&data[..TABLE_SIZE]),
+            Some(vec![0x400, 0x100, 0x400, 0x400, 0x400])
+        );
+        assert!(data[0x3F0..0x400].iter().all(|b| *b == 0xFF));
+        assert_eq!(&data[0x400..], &images[1][..]);
+        assert_eq!(parse_table(&[0xFF; TABLE_SIZE]), None);
+    }
+
+    #[test]
+    fn multiboot_slot_update() {
+        let images = vec![test_pattern(0x1800), test_pattern(0x800), vec![0x5A; 0x900]];
+        let sizes = images.iter().map(Vec::len).collect::<Vec<_>>();
+        let offsets = layout(&sizes, &[None; 3], 0x1000).unwrap();
+        let data = build(&images, &offsets, 0);
+
+        let mut flash = sim_flash();
+        flash.read_id().unwrap();
+        program(&mut flash, 0, &data);
+
+        let replacement = vec![0x33; 0xF00];
+        assert_eq!(update_slot(&mut flash, 1, &replacement).unwrap(), 0x3000);
+        let mut expected = data.clone();
+        expected[0x3000..0x3F00].copy_from_slice(&replacement);
+        assert!(verify(&mut flash, 0, &expected));
+
+        // Slot 1 only has room up to slot 2, and slot 3 has no image
+        assert!(update_slot(&mut flash, 1, &[0; 0x1001]).is_err());
+        assert!(update_slot(&mut flash, 3, &[0; 0x10]).is_err());
+
+        flash.transport.memory_mut()[0] = 0;
+        assert!(update_slot(&mut flash, 0, &[0; 0x10]).is_err());
+    }
+}
@@ -0,0 +1,474 @@
+//! Decoding of JEDEC IDs and a table of common SPI flash parts.
+
+use std::fmt;
+
+const KB: usize = 1024;
+const MB: usize = 1024 * 1024;
+
+/// The response to the JEDEC Read ID (0x9F) command.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlashId {
+    pub manufacturer: u8,
+    pub device: u16,
+
+    /// Extended device information, present when the fourth byte of the
+    /// response is a nonzero length.
+    pub extended: Vec<u8>,
+}
+
+/// How addresses are sent to the part, which matters once it is larger than
+/// the 16 MiB that 24 bits can reach.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Addressing {
+    /// 24-bit addresses reach the whole part
+    ThreeByte,
+
+    /// Dedicated 4-byte opcodes (0x13, 0x12), with the erase opcodes from
+    /// `Geometry::erase_opcodes_4b`
+    FourByteOpcodes,
+
+    /// Switch the part into 4-byte mode with 0xB7, and back with 0xE9
+    FourByteMode,
+
+    /// Keep 3-byte commands and select the top address byte through the
+    /// extended address register (0xC5)
+    ExtendedAddress,
+}
+
+/// Where the Quad Enable bit lives, which must be set before IO2 and IO3
+/// can carry data rather than acting as ~WP and ~HOLD.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuadEnable {
+    /// Bit 6 of status register 1 (Macronix, ISSI)
+    Sr1Bit6,
+
+    /// Bit 1 of status register 2, read with 0x35 and written together
+    /// with status register 1 by a two-byte 0x01 (Winbond, GigaDevice,
+    /// Cypress)
+    Sr2Bit1,
+
+    /// Bit 1 of status register 2, written on its own with 0x31 (Adesto)
+    Sr2Bit1Write31,
+}
+
+/// The usual Quad Enable location for each manufacturer, for parts that
+/// don't say otherwise in the table.  Micron parts don't have one.
+const fn quad_enable(manufacturer: u8) -> Option<QuadEnable> {
+    match manufacturer {
+        0xC2 | 0x9D => Some(QuadEnable::Sr1Bit6),
+        0xEF | 0xC8 | 0x01 => Some(QuadEnable::Sr2Bit1),
+        0x1F => Some(QuadEnable::Sr2Bit1Write31),
+        _ => None,
+    }
+}
+
+/// The 4-byte erase opcodes for 4, 32 and 64 kB, as each manufacturer has
+/// them.  Winbond parts have no 4-byte 32 kB erase, so theirs can only use
+/// the 3-byte 0x52 in the bottom 16 MiB.
+const fn erase_opcodes_4b(manufacturer: u8) -> &'static [(usize, u8)] {
+    match manufacturer {
+        0xEF => &[(4 * KB, 0x21), (64 * KB, 0xDC)],
+        _ => &[(4 * KB, 0x21), (32 * KB, 0x5C), (64 * KB, 0xDC)],
+    }
+}
+
+/// How a part reports that a program or erase failed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureReport {
+    /// The part can't say; only a verify will tell
+    Unreported,
+
+    /// EPE, bit 5 of status register 1 (Adesto AT25DF).  Other Adesto
+    /// parts use that bit for top/bottom write protection.
+    Sr1Epe,
+
+    /// P_FAIL and E_FAIL in the security register, read with 0x2B
+    /// (Macronix)
+    SecurityRegister,
+}
+
+/// How parts from each manufacturer report failures, for parts that don't
+/// say otherwise in the table.
+const fn failure_report(manufacturer: u8) -> FailureReport {
+    match manufacturer {
+        0xC2 => FailureReport::SecurityRegister,
+        _ => FailureReport::Unreported,
+    }
+}
+
+#[derive(Debug)]
+pub struct FlashPart {
+    pub manufacturer: u8,
+    pub device: u16,
+    pub name: &'static str,
+    pub size: usize,
+    pub page_size: usize,
+
+    /// Supported erase sizes in bytes, smallest first
+    pub erase_sizes: &'static [usize],
+
+    /// The dedicated 4-byte opcode for each erase size the part has one for
+    pub erase_opcodes_4b: &'static [(usize, u8)],
+    pub addressing: Addressing,
+    pub quad_enable: Option<QuadEnable>,
+    pub failure_report: FailureReport,
+}
+
+/// The layout of a flash part, from the part table or from SFDP.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Geometry {
+    pub size: usize,
+    pub page_size: usize,
+
+    /// Supported erase sizes in bytes, smallest first
+    pub erase_sizes: Vec<usize>,
+
+    /// The dedicated 4-byte opcode for each erase size, used with
+    /// `Addressing::FourByteOpcodes`
+    pub erase_opcodes_4b: Vec<(usize, u8)>,
+    pub addressing: Addressing,
+    pub quad_enable: Option<QuadEnable>,
+}
+
+const ERASE_4_32_64: &[usize] = &[4 * KB, 32 * KB, 64 * KB];
+const ERASE_4_64: &[usize] = &[4 * KB, 64 * KB];
+const ERASE_64: &[usize] = &[64 * KB];
+
+macro_rules! part {
+    ($manufacturer:expr, $device:expr, $name:expr, $size:expr, $erase:expr) => {
+        part!(
+            $manufacturer,
+            $device,
+            $name,
+            $size,
+            $erase,
+            quad_enable($manufacturer),
+            failure_report($manufacturer)
+        )
+    };
+    (
+        $manufacturer:expr,
+        $device:expr,
+        $name:expr,
+        $size:expr,
+        $erase:expr,
+        $quad_enable:expr,
+        $failure_report:expr
+    ) => {
+        FlashPart {
+            manufacturer: $manufacturer,
+            device: $device,
+            name: $name,
+            size: $size,
+            page_size: 256,
+            erase_sizes: $erase,
+            erase_opcodes_4b: erase_opcodes_4b($manufacturer),
+            addressing: if $size > 16 * MB {
+                Addressing::FourByteOpcodes
+            } else {
+                Addressing::ThreeByte
+            },
+            quad_enable: $quad_enable,
+            failure_report: $failure_report,
+        }
+    };
+}
+
+pub const PARTS: &[FlashPart] = &[
+    /* Winbond */
+    part!(0xEF, 0x4014, "W25Q80", MB, ERASE_4_32_64),
+    part!(0xEF, 0x4015, "W25Q16", 2 * MB, ERASE_4_32_64),
+    part!(0xEF, 0x4016, "W25Q32", 4 * MB, ERASE_4_32_64),
+    part!(0xEF, 0x4017, "W25Q64", 8 * MB, ERASE_4_32_64),
+    part!(0xEF, 0x4018, "W25Q128", 16 * MB, ERASE_4_32_64),
+    part!(0xEF, 0x4019, "W25Q256", 32 * MB, ERASE_4_32_64),
+    part!(0xEF, 0x4020, "W25Q512", 64 * MB, ERASE_4_32_64),
+    part!(0xEF, 0x6015, "W25Q16DW", 2 * MB, ERASE_4_32_64),
+    part!(0xEF, 0x6016, "W25Q32DW", 4 * MB, ERASE_4_32_64),
+    part!(0xEF, 0x6017, "W25Q64DW", 8 * MB, ERASE_4_32_64),
+    part!(0xEF, 0x6018, "W25Q128FW", 16 * MB, ERASE_4_32_64),
+    part!(0xEF, 0x7016, "W25Q32JV-IM", 4 * MB, ERASE_4_32_64),
+    part!(0xEF, 0x7017, "W25Q64JV-IM", 8 * MB, ERASE_4_32_64),
+    part!(0xEF, 0x7018, "W25Q128JV-IM", 16 * MB, ERASE_4_32_64),
+    part!(0xEF, 0x7019, "W25Q256JV-IM", 32 * MB, ERASE_4_32_64),
+    /* Macronix */
+    part!(0xC2, 0x2014, "MX25L8006E", MB, ERASE_4_32_64),
+    part!(0xC2, 0x2015, "MX25L1606E", 2 * MB, ERASE_4_32_64),
+    part!(0xC2, 0x2016, "MX25L3233F", 4 * MB, ERASE_4_32_64),
+    part!(0xC2, 0x2017, "MX25L6433F", 8 * MB, ERASE_4_32_64),
+    part!(0xC2, 0x2018, "MX25L12835F", 16 * MB, ERASE_4_32_64),
+    part!(0xC2, 0x2019, "MX25L25635F", 32 * MB, ERASE_4_32_64),
+    part!(0xC2, 0x201A, "MX25L51245G", 64 * MB, ERASE_4_32_64),
+    part!(0xC2, 0x2815, "MX25R1635F", 2 * MB, ERASE_4_32_64),
+    part!(0xC2, 0x2816, "MX25R3235F", 4 * MB, ERASE_4_32_64),
+    part!(0xC2, 0x2817, "MX25R6435F", 8 * MB, ERASE_4_32_64),
+    part!(0xC2, 0x2535, "MX25U1635F", 2 * MB, ERASE_4_32_64),
+    part!(0xC2, 0x2536, "MX25U3235F", 4 * MB, ERASE_4_32_64),
+    part!(0xC2, 0x2537, "MX25U6435F", 8 * MB, ERASE_4_32_64),
+    part!(0xC2, 0x2538, "MX25U12835F", 16 * MB, ERASE_4_32_64),
+    /* GigaDevice */
+    part!(0xC8, 0x4014, "GD25Q80", MB, ERASE_4_32_64),
+    part!(0xC8, 0x4015, "GD25Q16", 2 * MB, ERASE_4_32_64),
+    part!(0xC8, 0x4016, "GD25Q32", 4 * MB, ERASE_4_32_64),
+    part!(0xC8, 0x4017, "GD25Q64", 8 * MB, ERASE_4_32_64),
+    part!(0xC8, 0x4018, "GD25Q128", 16 * MB, ERASE_4_32_64),
+    part!(0xC8, 0x4019, "GD25Q256", 32 * MB, ERASE_4_32_64),
+    part!(0xC8, 0x6015, "GD25LQ16", 2 * MB, ERASE_4_32_64),
+    part!(0xC8, 0x6016, "GD25LQ32", 4 * MB, ERASE_4_32_64),
+    part!(0xC8, 0x6017, "GD25LQ64", 8 * MB, ERASE_4_32_64),
+    part!(0xC8, 0x6018, "GD25LQ128", 16 * MB, ERASE_4_32_64),
+    /* ISSI */
+    part!(0x9D, 0x6014, "IS25LP080", MB, ERASE_4_32_64),
+    part!(0x9D, 0x6015, "IS25LP016", 2 * MB, ERASE_4_32_64),
+    part!(0x9D, 0x6016, "IS25LP032", 4 * MB, ERASE_4_32_64),
+    part!(0x9D, 0x6017, "IS25LP064", 8 * MB, ERASE_4_32_64),
+    part!(0x9D, 0x6018, "IS25LP128", 16 * MB, ERASE_4_32_64),
+    part!(0x9D, 0x6019, "IS25LP256", 32 * MB, ERASE_4_32_64),
+    part!(0x9D, 0x7016, "IS25WP032", 4 * MB, ERASE_4_32_64),
+    part!(0x9D, 0x7017, "IS25WP064", 8 * MB, ERASE_4_32_64),
+    part!(0x9D, 0x7018, "IS25WP128", 16 * MB, ERASE_4_32_64),
+    /* Micron */
+    part!(0x20, 0xBA16, "N25Q032", 4 * MB, ERASE_4_64),
+    part!(0x20, 0xBA17, "N25Q064", 8 * MB, ERASE_4_64),
+    part!(0x20, 0xBA18, "N25Q128", 16 * MB, ERASE_4_64),
+    part!(0x20, 0xBA19, "N25Q256", 32 * MB, ERASE_4_64),
+    part!(0x20, 0xBA20, "N25Q512", 64 * MB, ERASE_4_64),
+    part!(0x20, 0xBB16, "N25Q032 (1.8V)", 4 * MB, ERASE_4_64),
+    part!(0x20, 0xBB17, "N25Q064 (1.8V)", 8 * MB, ERASE_4_64),
+    part!(0x20, 0xBB18, "N25Q128 (1.8V)", 16 * MB, ERASE_4_64),
+    /* Adesto / Atmel */
+    part!(0x1F, 0x8401, "AT25SF041", 512 * KB, ERASE_4_32_64),
+    part!(0x1F, 0x8501, "AT25SF081", MB, ERASE_4_32_64),
+    part!(0x1F, 0x8601, "AT25SF161", 2 * MB, ERASE_4_32_64),
+    part!(0x1F, 0x8701, "AT25SF321", 4 * MB, ERASE_4_32_64),
+    part!(0x1F, 0x3217, "AT25SF641", 8 * MB, ERASE_4_32_64),
+    // The AT25DF parts only have dual reads and no second status register,
+    // but do flag failures
+    part!(
+        0x1F,
+        0x4501,
+        "AT25DF081A",
+        MB,
+        ERASE_4_32_64,
+        None,
+        FailureReport::Sr1Epe
+    ),
+    part!(
+        0x1F,
+        0x4701,
+        "AT25DF321A",
+        4 * MB,
+        ERASE_4_32_64,
+        None,
+        FailureReport::Sr1Epe
+    ),
+    part!(0x1F, 0x4216, "AT25SL321", 4 * MB, ERASE_4_32_64),
+    /* Cypress / Spansion */
+    part!(0x01, 0x4015, "S25FL116K", 2 * MB, ERASE_4_64),
+    part!(0x01, 0x4016, "S25FL132K", 4 * MB, ERASE_4_64),
+    part!(0x01, 0x4017, "S25FL164K", 8 * MB, ERASE_4_64),
+    part!(0x01, 0x2018, "S25FL128S", 16 * MB, ERASE_64),
+    part!(0x01, 0x0219, "S25FL256S", 32 * MB, ERASE_64),
+];
+
+pub fn manufacturer_name(manufacturer: u8) -> Option<&'static str> {
+    match manufacturer {
+        0x01 => Some("Cypress/Spansion"),
+        0x1F => Some("Adesto/Atmel"),
+        0x20 => Some("Micron"),
+        0x9D => Some("ISSI"),
+        0xC2 => Some("Macronix"),
+        0xC8 => Some("GigaDevice"),
+        0xEF => Some("Winbond"),
+        _ => None,
+    }
+}
+
+impl FlashId {
+    /// Decode the bytes following the 0x9F command byte.
+    pub fn from_bytes(data: &[u8]) -> FlashId {
+        let byte = |n: usize| *data.get(n).unwrap_or(&0xff);
+        let extended_len = byte(3) as usize;
+        let extended = if extended_len != 0 && extended_len != 0xff {
+            data.iter().skip(4).take(extended_len).cloned().collect()
+        } else {
+            vec![]
+        };
+        FlashId {
+            manufacturer: byte(0),
+            device: (byte(1) as u16) << 8 | byte(2) as u16,
+            extended,
+        }
+    }
+
+    /// A missing chip or broken connection reads as all zeroes or all ones.
+    pub fn is_valid(&self) -> bool {
+        !((self.manufacturer == 0x00 && self.device == 0x0000)
+            || (self.manufacturer == 0xff && self.device == 0xffff))
+    }
+
+    pub fn manufacturer_name(&self) -> Option<&'static str> {
+        manufacturer_name(self.manufacturer)
+    }
+
+    pub fn part(&self) -> Option<&'static FlashPart> {
+        PARTS
+            .iter()
+            .find(|p| p.manufacturer == self.manufacturer && p.device == self.device)
+    }
+
+    /// How the part reports failed writes, going by the manufacturer for
+    /// parts that aren't in the table.
+    pub fn failure_report(&self) -> FailureReport {
+        match self.part() {
+            Some(part) => part.failure_report,
+            None if self.is_valid() => failure_report(self.manufacturer),
+            None => FailureReport::Unreported,
+        }
+    }
+}
+
+impl fmt::Display for FlashId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "0x{:02X} 0x{:02X} 0x{:02X}",
+            self.manufacturer,
+            self.device >> 8,
+            self.device & 0xff
+        )?;
+        for b in &self.extended {
+            write!(f, " 0x{:02X}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl FlashPart {
+    pub fn manufacturer_name(&self) -> &'static str {
+        manufacturer_name(self.manufacturer).unwrap_or("Unknown")
+    }
+}
+
+impl From<&FlashPart> for Geometry {
+    fn from(part: &FlashPart) -> Geometry {
+        Geometry {
+            size: part.size,
+            page_size: part.page_size,
+            erase_sizes: part.erase_sizes.to_vec(),
+            erase_opcodes_4b: part
+                .erase_opcodes_4b
+                .iter()
+                .filter(|(size, _)| part.erase_sizes.contains(size))
+                .copied()
+                .collect(),
+            addressing: part.addressing,
+            quad_enable: part.quad_enable,
+        }
+    }
+}
+
+impl fmt::Display for FlashPart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({} KiB, {} byte pages, {}-byte addressing, erase sizes:",
+            self.manufacturer_name(),
+            self.name,
+            self.size / KB,
+            self.page_size,
+            match self.addressing {
+                Addressing::ThreeByte => 3,
+                _ => 4,
+            }
+        )?;
+        for size in self.erase_sizes {
+            write!(f, " {}K", size / KB)?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_id() {
+        let id = FlashId::from_bytes(&[0xEF, 0x40, 0x18, 0x00]);
+        assert!(id.is_valid());
+        assert_eq!(id.manufacturer_name(), Some("Winbond"));
+        let part = id.part().unwrap();
+        assert_eq!(part.name, "W25Q128");
+        assert_eq!(part.size, 16 * MB);
+        assert_eq!(part.addressing, Addressing::ThreeByte);
+        assert_eq!(id.to_string(), "0xEF 0x40 0x18");
+
+        let id = FlashId::from_bytes(&[0x01, 0x02, 0x19, 0x02, 0x4D, 0x00]);
+        assert_eq!(id.extended, vec![0x4D, 0x00]);
+        assert_eq!(id.part().unwrap().addressing, Addressing::FourByteOpcodes);
+        assert_eq!(id.to_string(), "0x01 0x02 0x19 0x4D 0x00");
+
+        // Quad Enable follows the part, not just the manufacturer
+        let part = |id: &[u8]| FlashId::from_bytes(id).part().unwrap();
+        assert_eq!(
+            part(&[0x1F, 0x85, 0x01, 0x00]).quad_enable,
+            Some(QuadEnable::Sr2Bit1Write31)
+        );
+        assert_eq!(part(&[0x1F, 0x45, 0x01, 0x00]).quad_enable, None);
+
+        // Only the AT25DF parts have EPE
+        let report = |id: &[u8]| FlashId::from_bytes(id).failure_report();
+        assert_eq!(report(&[0x1F, 0x47, 0x01, 0x00]), FailureReport::Sr1Epe);
+        assert_eq!(report(&[0x1F, 0x86, 0x01, 0x00]), FailureReport::Unreported);
+        assert_eq!(
+            report(&[0xC2, 0x99, 0x99, 0x00]),
+            FailureReport::SecurityRegister
+        );
+    }
+
+    #[test]
+    fn missing_chip() {
+        assert!(!FlashId::from_bytes(&[0xff, 0xff, 0xff, 0xff]).is_valid());
+        assert!(!FlashId::from_bytes(&[0, 0, 0, 0]).is_valid());
+        assert!(FlashId::from_bytes(&[0x12, 0x34, 0x56, 0x00])
+            .part()
+            .is_none());
+    }
+
+    #[test]
+    fn four_byte_erase_opcodes() {
+        let part = FlashId::from_bytes(&[0xEF, 0x40, 0x19, 0x00])
+            .part()
+            .unwrap();
+        let geometry = Geometry::from(part);
+        assert_eq!(geometry.erase_sizes, vec![4 * KB, 32 * KB, 64 * KB]);
+        assert_eq!(
+            geometry.erase_opcodes_4b,
+            vec![(4 * KB, 0x21), (64 * KB, 0xDC)]
+        );
+
+        let part = FlashId::from_bytes(&[0xC2, 0x20, 0x19, 0x00])
+            .part()
+            .unwrap();
+        assert_eq!(
+            Geometry::from(part).erase_opcodes_4b,
+            vec![(4 * KB, 0x21), (32 * KB, 0x5C), (64 * KB, 0xDC)]
+        );
+    }
+
+    #[test]
+    fn table_is_sane() {
+        for (i, part) in PARTS.iter().enumerate() {
+            assert!(part.size.is_power_of_two(), "{}", part.name);
+            assert!(part.erase_sizes.contains(&(64 * KB)), "{}", part.name);
+            assert!(
+                PARTS[i + 1..]
+                    .iter()
+                    .all(|p| (p.manufacturer, p.device) != (part.manufacturer, part.device)),
+                "duplicate ID for {}",
+                part.name
+            );
+        }
+    }
+}
@@ -0,0 +1,586 @@
+//! Parsing of the Serial Flash Discoverable Parameters (JESD216) tables that
+//! most modern SPI flash parts carry.
+
+use crate::error::Error;
+use crate::parts::{Addressing, Geometry, QuadEnable};
+use std::fmt;
+use std::time::Duration;
+
+const SFDP_SIGNATURE: &[u8; 4] = b"SFDP";
+
+/* Parameter table IDs */
+const BASIC_FLASH_PARAMETERS: u16 = 0xFF00;
+const SECTOR_MAP: u16 = 0xFF81;
+const FOUR_BYTE_INSTRUCTIONS: u16 = 0xFF84;
+
+/// Which address lengths the part accepts, from BFPT DWORD 1 bits 18:17.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressMode {
+    Three,
+    ThreeOrFour,
+    Four,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FastRead {
+    pub opcode: u8,
+    pub dummy_clocks: u8,
+    pub mode_clocks: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SfdpErase {
+    pub size: usize,
+    pub opcode: u8,
+
+    /// The dedicated 4-byte address opcode, if the part has one
+    pub opcode_4b: Option<u8>,
+    pub typical_time: Option<Duration>,
+}
+
+/// Instructions from the 4-byte address instruction table.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FourByteInstructions {
+    /// Read (0x13)
+    pub read: bool,
+
+    /// Fast read (0x0C)
+    pub fast_read: bool,
+
+    /// Page program (0x12)
+    pub page_program: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SectorRegion {
+    pub size: usize,
+
+    /// Bit `n` is set if erase type `n + 1` may be used in this region
+    pub erase_types: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SectorMap {
+    pub configuration: u8,
+    pub regions: Vec<SectorRegion>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sfdp {
+    pub major: u8,
+    pub minor: u8,
+
+    /// Size of the part in bytes
+    pub density: usize,
+    pub page_size: usize,
+
+    /// Erase types 1 to 4, where defined
+    pub erase_types: [Option<SfdpErase>; 4],
+    pub address_mode: AddressMode,
+    pub read_1_1_2: Option<FastRead>,
+    pub read_1_2_2: Option<FastRead>,
+    pub read_1_1_4: Option<FastRead>,
+    pub read_1_4_4: Option<FastRead>,
+    pub four_byte_instructions: Option<FourByteInstructions>,
+
+    /// Ways of entering 4-byte addressing, from BFPT DWORD 16 bits 31:24
+    pub four_byte_entry: u8,
+
+    /// Quad Enable requirements, from BFPT DWORD 15 bits 22:20
+    pub quad_enable: Option<QuadEnable>,
+    pub sector_maps: Vec<SectorMap>,
+}
+
+/// Read a little-endian DWORD, treating missing bytes as erased.
+fn dword(table: &[u8], index: usize) -> u32 {
+    let byte = |n: usize| *table.get(index * 4 + n).unwrap_or(&0xff) as u32;
+    byte(0) | byte(1) << 8 | byte(2) << 16 | byte(3) << 24
+}
+
+fn fast_read(settings: u16, supported: bool) -> Option<FastRead> {
+    let opcode = (settings >> 8) as u8;
+    if !supported || opcode == 0x00 || opcode == 0xff {
+        return None;
+    }
+    Some(FastRead {
+        opcode,
+        dummy_clocks: (settings & 0x1f) as u8,
+        mode_clocks: ((settings >> 5) & 0x7) as u8,
+    })
+}
+
+/// Decode a 7-bit typical erase time field from BFPT DWORD 10.
+fn erase_time(field: u32) -> Duration {
+    let count = (field & 0x1f) as u64 + 1;
+    let unit = match (field >> 5) & 0x3 {
+        0 => 1,
+        1 => 16,
+        2 => 128,
+        _ => 1000,
+    };
+    Duration::from_millis(count * unit)
+}
+
+impl Sfdp {
+    /// Read and parse the SFDP tables, using `read` to fetch bytes from the
+    /// SFDP address space.  Returns `None` if the part has no SFDP.
+    pub fn parse<F>(mut read: F) -> Result<Option<Sfdp>, Error>
+    where
+        F: FnMut(usize, &mut [u8]) -> Result<(), Error>,
+    {
+        let mut header = [0u8; 8];
+        read(0, &mut header)?;
+        if &header[0..4] != SFDP_SIGNATURE {
+            return Ok(None);
+        }
+        let (minor, major) = (header[4], header[5]);
+        let parameter_count = header[6] as usize + 1;
+
+        let mut parameter_headers = vec![0u8; parameter_count * 8];
+        read(8, &mut parameter_headers)?;
+
+        let mut tables = vec![];
+        for p in parameter_headers.chunks(8) {
+            let id = (p[7] as u16) << 8 | p[0] as u16;
+            let len = p[3] as usize * 4;
+            let pointer = p[4] as usize | (p[5] as usize) << 8 | (p[6] as usize) << 16;
+            let mut table = vec![0u8; len];
+            read(pointer, &mut table)?;
+            tables.push((id, table));
+        }
+        let find = |id: u16| tables.iter().find(|(i, _)| *i == id).map(|(_, t)| t);
+
+        let bfpt = match find(BASIC_FLASH_PARAMETERS) {
+            Some(bfpt) if bfpt.len() >= 9 * 4 => bfpt,
+            _ => return Ok(None),
+        };
+        let mut sfdp = Sfdp::parse_basic(major, minor, bfpt);
+        if let Some(table) = find(FOUR_BYTE_INSTRUCTIONS) {
+            sfdp.parse_four_byte(table);
+        }
+        if let Some(table) = find(SECTOR_MAP) {
+            sfdp.parse_sector_map(table);
+        }
+        Ok(Some(sfdp))
+    }
+
+    fn parse_basic(major: u8, minor: u8, bfpt: &[u8]) -> Sfdp {
+        let dw1 = dword(bfpt, 0);
+        let dw2 = dword(bfpt, 1);
+        let density_bits = if dw2 & 0x8000_0000 == 0 {
+            dw2 as u64 + 1
+        } else {
+            1u64 << (dw2 & 0x7fff_ffff).min(63)
+        };
+
+        let address_mode = match (dw1 >> 17) & 0x3 {
+            1 => AddressMode::ThreeOrFour,
+            2 => AddressMode::Four,
+            _ => AddressMode::Three,
+        };
+
+        let dw3 = dword(bfpt, 2);
+        let dw4 = dword(bfpt, 3);
+
+        // JESD216A and later add erase times and the page size in DWORDs
+        // 10 and 11.
+        let has_timing = bfpt.len() >= 11 * 4;
+        let dw10 = dword(bfpt, 9);
+        let page_size = if has_timing {
+            1 << ((dword(bfpt, 10) >> 4) & 0xf)
+        } else {
+            256
+        };
+
+        let mut erase_types = [None, None, None, None];
+        for (n, erase_type) in erase_types.iter_mut().enumerate() {
+            let field = (dword(bfpt, 7 + n / 2) >> (16 * (n % 2))) as u16;
+            let size_shift = field & 0xff;
+            if size_shift == 0 || size_shift >= 32 {
+                continue;
+            }
+            *erase_type = Some(SfdpErase {
+                size: 1 << size_shift,
+                opcode: (field >> 8) as u8,
+                opcode_4b: None,
+                typical_time: if has_timing {
+                    Some(erase_time(dw10 >> (4 + 7 * n)))
+                } else {
+                    None
+                },
+            });
+        }
+
+        Sfdp {
+            major,
+            minor,
+            density: (density_bits / 8) as usize,
+            page_size,
+            erase_types,
+            address_mode,
+            read_1_1_2: fast_read(dw4 as u16, dw1 & (1 << 16) != 0),
+            read_1_2_2: fast_read((dw4 >> 16) as u16, dw1 & (1 << 20) != 0),
+            read_1_1_4: fast_read((dw3 >> 16) as u16, dw1 & (1 << 22) != 0),
+            read_1_4_4: fast_read(dw3 as u16, dw1 & (1 << 21) != 0),
+            four_byte_instructions: None,
+            four_byte_entry: if bfpt.len() >= 16 * 4 {
+                (dword(bfpt, 15) >> 24) as u8
+            } else {
+                0
+            },
+            quad_enable: if bfpt.len() >= 15 * 4 {
+                match (dword(bfpt, 14) >> 20) & 0x7 {
+                    0b010 => Some(QuadEnable::Sr1Bit6),
+                    0b001 | 0b100 | 0b101 => Some(QuadEnable::Sr2Bit1),
+                    0b110 => Some(QuadEnable::Sr2Bit1Write31),
+                    _ => None,
+                }
+            } else {
+                None
+            },
+            sector_maps: vec![],
+        }
+    }
+
+    fn parse_four_byte(&mut self, table: &[u8]) {
+        let supported = dword(table, 0);
+        let opcodes = dword(table, 1);
+        for (n, erase) in self.erase_types.iter_mut().enumerate() {
+            if let (Some(erase), true) = (erase, supported & (1 << (9 + n)) != 0) {
+                erase.opcode_4b = Some((opcodes >> (8 * n)) as u8);
+            }
+        }
+        self.four_byte_instructions = Some(FourByteInstructions {
+            read: supported & (1 << 0) != 0,
+            fast_read: supported & (1 << 1) != 0,
+            page_program: supported & (1 << 6) != 0,
+        });
+    }
+
+    fn parse_sector_map(&mut self, table: &[u8]) {
+        let mut index = 0;
+        while index * 4 < table.len() {
+            let header = dword(table, index);
+            let last = header & 0x1 != 0;
+            if header & 0x2 == 0 {
+                // Configuration detection command, which we don't run
+                index += 2;
+            } else {
+                let region_count = ((header >> 16) & 0xff) as usize + 1;
+                let regions = (0..region_count)
+                    .map(|r| dword(table, index + 1 + r))
+                    .map(|region| SectorRegion {
+                        size: ((region >> 8) as usize + 1) * 256,
+                        erase_types: (region & 0xf) as u8,
+                    })
+                    .collect();
+                self.sector_maps.push(SectorMap {
+                    configuration: (header >> 8) as u8,
+                    regions,
+                });
+                index += 1 + region_count;
+            }
+            if last {
+                break;
+            }
+        }
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        let addressing = self.addressing();
+        let erase_opcodes_4b: Vec<(usize, u8)> = self
+            .erase_types
+            .iter()
+            .flatten()
+            .filter_map(|e| Some((e.size, self.erase_opcode_4b(e)?)))
+            .collect();
+        // Erase types without a 4-byte opcode still reach the bottom 16 MiB
+        let mut erase_sizes: Vec<usize> =
+            self.erase_types.iter().flatten().map(|e| e.size).collect();
+        erase_sizes.sort_unstable();
+        erase_sizes.dedup();
+        Geometry {
+            size: self.density,
+            page_size: self.page_size,
+            erase_sizes,
+            erase_opcodes_4b,
+            addressing,
+            quad_enable: self.quad_enable,
+        }
+    }
+
+    /// The 4-byte opcode for an erase type.  Parts that claim the 4-byte
+    /// instruction set without the table listing it get the usual opcodes.
+    fn erase_opcode_4b(&self, erase: &SfdpErase) -> Option<u8> {
+        if self.four_byte_instructions.is_some() {
+            return erase.opcode_4b;
+        }
+        match erase.opcode {
+            0x20 => Some(0x21), /* FC_SE4B */
+            0x52 => Some(0x5C), /* FC_BE32_4B */
+            0xD8 => Some(0xDC), /* FC_BE64_4B */
+            _ => None,
+        }
+    }
+
+    /// Pick the simplest way to reach the whole part, preferring stateless
+    /// 4-byte opcodes over modes that have to be undone before the FPGA
+    /// boots.
+    fn addressing(&self) -> Addressing {
+        let has_erase = self
+            .erase_types
+            .iter()
+            .flatten()
+            .any(|e| e.opcode_4b.is_some());
+        let has_opcodes = match &self.four_byte_instructions {
+            Some(i) => i.read && i.page_program && has_erase,
+            None => false,
+        };
+        if self.density <= 16 * 1024 * 1024 {
+            Addressing::ThreeByte
+        } else if has_opcodes || self.four_byte_entry & (1 << 5) != 0 {
+            Addressing::FourByteOpcodes
+        } else if self.four_byte_entry & (1 << 2) != 0 && self.four_byte_entry & 0x3 == 0 {
+            Addressing::ExtendedAddress
+        } else {
+            Addressing::FourByteMode
+        }
+    }
+}
+
+impl fmt::Display for Sfdp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "SFDP {}.{}: {} KiB, {} byte pages, {} addressing",
+            self.major,
+            self.minor,
+            self.density / 1024,
+            self.page_size,
+            match self.address_mode {
+                AddressMode::Three => "3-byte",
+                AddressMode::ThreeOrFour => "3- or 4-byte",
+                AddressMode::Four => "4-byte",
+            }
+        )?;
+        write!(f, ", erase:")?;
+        for erase in self.erase_types.iter().flatten() {
+            write!(f, " {}K (0x{:02X}", erase.size / 1024, erase.opcode)?;
+            if let Some(time) = erase.typical_time {
+                write!(f, ", {} ms", time.as_millis())?;
+            }
+            write!(f, ")")?;
+        }
+        let modes = [
+            ("1-1-2", self.read_1_1_2),
+            ("1-2-2", self.read_1_2_2),
+            ("1-1-4", self.read_1_1_4),
+            ("1-4-4", self.read_1_4_4),
+        ];
+        if modes.iter().any(|(_, mode)| mode.is_some()) {
+            write!(f, ", fast read:")?;
+            for (name, mode) in modes.iter() {
+                if let Some(mode) = mode {
+                    write!(f, " {} (0x{:02X})", name, mode.opcode)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The SFDP tables of a Winbond W25Q128JV, which the simulator also serves.
+#[cfg(test)]
+pub fn w25q128jv_sfdp() -> Vec<u8> {
+    let mut image = vec![0xff; 0x100];
+    image[0..16].copy_from_slice(&[
+        /* SFDP header, one parameter header */
+        0x53, 0x46, 0x44, 0x50, 0x06, 0x01, 0x00, 0xFF,
+        /* Basic flash parameter table: 16 DWORDs at 0x80 */
+        0x00, 0x06, 0x01, 0x10, 0x80, 0x00, 0x00, 0xFF,
+    ]);
+    image[0x80..0xC0].copy_from_slice(&[
+        0xE5, 0x20, 0xF9, 0xFF, 0xFF, 0xFF, 0xFF, 0x07, 0x44, 0xEB, 0x08, 0x6B, 0x08, 0x3B, 0x42,
+        0xBB, 0xFE, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xFF, 0xFF, 0x40, 0xEB, 0x0C, 0x20,
+        0x0F, 0x52, 0x10, 0xD8, 0x00, 0x00, 0x36, 0x02, 0xA6, 0x00, 0x82, 0xEA, 0x14, 0xC9, 0xE9,
+        0x63, 0x76, 0x33, 0x7A, 0x75, 0x7A, 0x75, 0xF7, 0xA2, 0xD5, 0x5C, 0x19, 0xF7, 0x4D, 0xFF,
+        0xE9, 0x30, 0xF8, 0x80,
+    ]);
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(image: &[u8]) -> Option<Sfdp> {
+        Sfdp::parse(|address, buffer| {
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = *image.get(address + i).unwrap_or(&0xff);
+            }
+            Ok(())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn basic_parameters() {
+        let sfdp = parse(&w25q128jv_sfdp()).unwrap();
+        assert_eq!((sfdp.major, sfdp.minor), (1, 6));
+        assert_eq!(sfdp.density, 16 * 1024 * 1024);
+        assert_eq!(sfdp.page_size, 256);
+        assert_eq!(sfdp.address_mode, AddressMode::Three);
+        assert_eq!(
+            sfdp.erase_types,
+            [
+                Some(SfdpErase {
+                    size: 4096,
+                    opcode: 0x20,
+                    opcode_4b: None,
+                    typical_time: Some(Duration::from_millis(64)),
+                }),
+                Some(SfdpErase {
+                    size: 32768,
+                    opcode: 0x52,
+                    opcode_4b: None,
+                    typical_time: Some(Duration::from_millis(128)),
+                }),
+                Some(SfdpErase {
+                    size: 65536,
+                    opcode: 0xD8,
+                    opcode_4b: None,
+                    typical_time: Some(Duration::from_millis(160)),
+                }),
+                None,
+            ]
+        );
+        assert_eq!(
+            sfdp.read_1_1_4,
+            Some(FastRead {
+                opcode: 0x6B,
+                dummy_clocks: 8,
+                mode_clocks: 0
+            })
+        );
+        assert_eq!(
+            sfdp.read_1_4_4,
+            Some(FastRead {
+                opcode: 0xEB,
+                dummy_clocks: 4,
+                mode_clocks: 2
+            })
+        );
+        assert_eq!(sfdp.read_1_1_2.unwrap().opcode, 0x3B);
+        assert_eq!(sfdp.read_1_2_2.unwrap().opcode, 0xBB);
+        assert_eq!(sfdp.four_byte_instructions, None);
+        assert!(sfdp.sector_maps.is_empty());
+
+        let geometry = sfdp.geometry();
+        assert_eq!(geometry.size, 16 * 1024 * 1024);
+        assert_eq!(geometry.erase_sizes, vec![4096, 32768, 65536]);
+        assert_eq!(geometry.addressing, Addressing::ThreeByte);
+        assert_eq!(geometry.quad_enable, Some(QuadEnable::Sr2Bit1));
+    }
+
+    #[test]
+    fn optional_tables() {
+        let mut image = w25q128jv_sfdp();
+        image.resize(0x100, 0xff);
+        // 256 Mbit, 3- or 4-byte addressing
+        image[0x82] = 0xFB;
+        image[0x84..0x88].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+        image[6] = 2;
+        image[16..32].copy_from_slice(&[
+            0x84, 0x00, 0x01, 0x02, 0xC0, 0x00, 0x00, 0xFF, /* 4-byte instructions */
+            0x81, 0x00, 0x01, 0x03, 0xD0, 0x00, 0x00, 0xFF, /* sector map */
+        ]);
+        image[0xC0..0xC8].copy_from_slice(&[0x43, 0x0E, 0x00, 0x00, 0x21, 0x5C, 0xDC, 0x00]);
+        image[0xD0..0xDC].copy_from_slice(&[
+            0x03, 0x00, 0x01, 0x00, /* last map descriptor, two regions */
+            0x07, 0xFF, 0x00, 0x00, /* 64 KiB of 4/32/64K sectors */
+            0x04, 0xFF, 0xFE, 0x01, /* the rest in 64K blocks */
+        ]);
+
+        let sfdp = parse(&image).unwrap();
+        assert_eq!(sfdp.density, 32 * 1024 * 1024);
+        assert_eq!(sfdp.address_mode, AddressMode::ThreeOrFour);
+        assert_eq!(sfdp.geometry().addressing, Addressing::FourByteOpcodes);
+        assert_eq!(
+            sfdp.four_byte_instructions,
+            Some(FourByteInstructions {
+                read: true,
+                fast_read: true,
+                page_program: true,
+            })
+        );
+        let opcodes: Vec<_> = sfdp
+            .erase_types
+            .iter()
+            .flatten()
+            .map(|e| e.opcode_4b)
+            .collect();
+        assert_eq!(opcodes, vec![Some(0x21), Some(0x5C), Some(0xDC)]);
+        assert_eq!(
+            sfdp.geometry().erase_opcodes_4b,
+            vec![(4096, 0x21), (32768, 0x5C), (65536, 0xDC)]
+        );
+        assert_eq!(
+            sfdp.sector_maps,
+            vec![SectorMap {
+                configuration: 0,
+                regions: vec![
+                    SectorRegion {
+                        size: 64 * 1024,
+                        erase_types: 0x7,
+                    },
+                    SectorRegion {
+                        size: 32 * 1024 * 1024 - 64 * 1024,
+                        erase_types: 0x4,
+                    },
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn missing_four_byte_erase() {
+        let mut image = w25q128jv_sfdp();
+        image.resize(0x100, 0xff);
+        image[0x84..0x88].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+        image[6] = 1;
+        image[16..24].copy_from_slice(&[0x84, 0x00, 0x01, 0x02, 0xC0, 0x00, 0x00, 0xFF]);
+        // 4-byte 4K and 64K erase, but no 32K
+        image[0xC0..0xC8].copy_from_slice(&[0x43, 0x0A, 0x00, 0x00, 0x21, 0x5C, 0xDC, 0x00]);
+
+        let geometry = parse(&image).unwrap().geometry();
+        assert_eq!(geometry.addressing, Addressing::FourByteOpcodes);
+        assert_eq!(geometry.erase_sizes, vec![4096, 32768, 65536]);
+        assert_eq!(geometry.erase_opcodes_4b, vec![(4096, 0x21), (65536, 0xDC)]);
+
+        // Without any 4-byte erase, fall back to 4-byte mode
+        image[0xC1] = 0x00;
+        let geometry = parse(&image).unwrap().geometry();
+        assert_eq!(geometry.addressing, Addressing::FourByteMode);
+        assert_eq!(geometry.erase_sizes, vec![4096, 32768, 65536]);
+    }
+
+    #[test]
+    fn four_byte_method() {
+        let mut image = w25q128jv_sfdp();
+        image[0x84..0x88].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+        let method = |image: &mut Vec<u8>, entry: u8| {
+            image[0xBF] = entry;
+            parse(image).unwrap().geometry().addressing
+        };
+        assert_eq!(method(&mut image, 0x01), Addressing::FourByteMode);
+        assert_eq!(method(&mut image, 0x02), Addressing::FourByteMode);
+        assert_eq!(method(&mut image, 0x04), Addressing::ExtendedAddress);
+        assert_eq!(method(&mut image, 0x21), Addressing::FourByteOpcodes);
+    }
+
+    #[test]
+    fn missing_sfdp() {
+        assert_eq!(parse(&[0xff; 16]), None);
+        assert_eq!(parse(&[0x00; 16]), None);
+    }
+}
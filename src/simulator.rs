@@ -0,0 +1,685 @@
+//! A simulated SPI NOR flash that can stand in for real hardware.  It speaks
+//! the same command set `Flash` issues and models the write-enable latch,
+//! busy time, erase-to-0xFF and the fact that programming can only clear bits.
+
+use crate::flash::Flash;
+use crate::sfdp::w25q128jv_sfdp;
+use crate::transport::Transport;
+use ftdi_vcp_rs::emulator::Peripheral;
+use ftdi_vcp_rs::Error;
+use std::time::{Duration, Instant};
+
+/* Pins, as wired on the iCEBreaker */
+const CS_PIN: u8 = 0x10;
+const CDONE_PIN: u8 = 0x40;
+const CRESET_PIN: u8 = 0x80;
+const READY_PIN: u8 = 0x20;
+
+const PAGE_SIZE: usize = 256;
+
+const SR1_BUSY: u8 = 1 << 0;
+const SR1_WEL: u8 = 1 << 1;
+const SR1_EPE: u8 = 1 << 5;
+
+const SCUR_P_FAIL: u8 = 1 << 5;
+const SCUR_E_FAIL: u8 = 1 << 6;
+
+/// Every iCE40 bitstream contains this, right after the comment block
+const PREAMBLE: [u8; 4] = [0x7E, 0xAA, 0x99, 0x7E];
+
+/// How long each operation keeps the BUSY bit set.  These are much shorter
+/// than a real part so that tests run quickly.
+#[derive(Clone, Debug)]
+pub struct SimTiming {
+    pub page_program: Duration,
+    pub block_erase: Duration,
+    pub chip_erase: Duration,
+    pub write_status: Duration,
+}
+
+impl Default for SimTiming {
+    fn default() -> Self {
+        SimTiming {
+            page_program: Duration::from_micros(500),
+            block_erase: Duration::from_millis(2),
+            chip_erase: Duration::from_millis(10),
+            write_status: Duration::from_micros(500),
+        }
+    }
+}
+
+pub struct SimFlash {
+    memory: Vec<u8>,
+    id: Vec<u8>,
+    sfdp: Vec<u8>,
+    timing: SimTiming,
+
+    cs_high: bool,
+    creset_high: bool,
+
+    /// Whether the FPGA came out of reset with SS low, and so is
+    /// listening for a bitstream rather than booting from the flash
+    slave_mode: bool,
+    /// Everything clocked into the FPGA while in slave mode
+    sram: Vec<u8>,
+
+    /// Bits 7:2 of status register 1, as set by Write Status Register
+    status: u8,
+    status2: u8,
+    security: u8,
+    write_enabled: bool,
+    powered_down: bool,
+    busy_until: Option<Instant>,
+    four_byte_mode: bool,
+    extended_address: u8,
+    fail_writes: bool,
+    stuck_busy: bool,
+    missing_opcodes: Vec<u8>,
+    ready_line: bool,
+
+    /* State of the transaction currently in progress */
+    opcode: u8,
+    address_len: usize,
+    clocked: usize,
+    ignored: bool,
+    address: usize,
+    page_data: Vec<u8>,
+    new_status: Option<u8>,
+    new_status2: Option<u8>,
+}
+
+impl SimFlash {
+    /// Create a blank (fully erased) flash of `size` bytes that identifies
+    /// itself as a Winbond W25Q128.
+    pub fn new(size: usize) -> SimFlash {
+        SimFlash {
+            memory: vec![0xff; size],
+            id: vec![0xEF, 0x40, 0x18, 0x00],
+            sfdp: w25q128jv_sfdp(),
+            timing: SimTiming::default(),
+            cs_high: true,
+            creset_high: true,
+            slave_mode: false,
+            sram: vec![],
+            status: 0,
+            status2: 0,
+            security: 0,
+            write_enabled: false,
+            powered_down: false,
+            busy_until: None,
+            four_byte_mode: false,
+            extended_address: 0,
+            fail_writes: false,
+            stuck_busy: false,
+            missing_opcodes: vec![],
+            ready_line: false,
+            opcode: 0,
+            address_len: 3,
+            clocked: 0,
+            ignored: false,
+            address: 0,
+            page_data: vec![],
+            new_status: None,
+            new_status2: None,
+        }
+    }
+
+    /// Change the bytes returned by Read JEDEC ID.
+    pub fn set_id(&mut self, id: &[u8]) {
+        self.id = id.to_vec();
+    }
+
+    /// Change the contents of the SFDP address space.  An empty image
+    /// simulates a part without SFDP.
+    pub fn set_sfdp(&mut self, sfdp: &[u8]) {
+        self.sfdp = sfdp.to_vec();
+    }
+
+    pub fn set_timing(&mut self, timing: SimTiming) {
+        self.timing = timing;
+    }
+
+    /// Make program and erase operations leave the memory untouched and
+    /// raise the failure flags, as a worn out or protected part would.
+    pub fn set_fail_writes(&mut self, fail_writes: bool) {
+        self.fail_writes = fail_writes;
+    }
+
+    /// Make the part report BUSY forever, like a missing chip with MISO
+    /// pulled high.
+    pub fn set_stuck_busy(&mut self, stuck_busy: bool) {
+        self.stuck_busy = stuck_busy;
+    }
+
+    /// Drive GPIOL1 (ADBUS5) from RY/BY#, high while the part is idle, as
+    /// on a board with the part's ready output wired to it.
+    pub fn set_ready_line(&mut self, ready_line: bool) {
+        self.ready_line = ready_line;
+    }
+
+    /// Make the part ignore commands it doesn't implement, such as the
+    /// 4-byte 32 kB erase (0x5C) that Winbond parts lack.
+    pub fn set_missing_opcodes(&mut self, opcodes: &[u8]) {
+        self.missing_opcodes = opcodes.to_vec();
+    }
+
+    /// Set the non-volatile bits of status register 1, as a previous
+    /// owner of the part might have left them.
+    pub fn set_status(&mut self, status: u8) {
+        self.status = status & !(SR1_BUSY | SR1_WEL);
+    }
+
+    pub fn set_status2(&mut self, status2: u8) {
+        self.status2 = status2;
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    pub fn is_busy(&self) -> bool {
+        if self.stuck_busy {
+            return true;
+        }
+        match self.busy_until {
+            Some(t) => Instant::now() < t,
+            None => false,
+        }
+    }
+
+    pub fn is_powered_down(&self) -> bool {
+        self.powered_down
+    }
+
+    pub fn is_four_byte_mode(&self) -> bool {
+        self.four_byte_mode
+    }
+
+    pub fn extended_address(&self) -> u8 {
+        self.extended_address
+    }
+
+    pub fn status2(&self) -> u8 {
+        self.status2
+    }
+
+    /// Whether IO2 and IO3 are data lines, which the W25Q controls with
+    /// SR2 bit 1 and Macronix parts with SR1 bit 6.
+    fn quad_enabled(&self) -> bool {
+        self.status2 & 0x02 != 0 || self.status & 0x40 != 0
+    }
+
+    pub fn status(&self) -> u8 {
+        self.status
+            | if self.write_enabled { SR1_WEL } else { 0 }
+            | if self.is_busy() { SR1_BUSY } else { 0 }
+    }
+
+    /// Record whether a program or erase worked, using the flags of the
+    /// part family the part claims to be from.  Returns whether the memory
+    /// should be changed.
+    fn write_result(&mut self, fail_flag: u8) -> bool {
+        self.security &= !(SCUR_P_FAIL | SCUR_E_FAIL);
+        match self.id.get(..2) {
+            // AT25DF; the other Adesto parts use this bit for TB
+            Some([0x1F, 0x45]) | Some([0x1F, 0x47]) => {
+                self.status &= !SR1_EPE;
+                if self.fail_writes {
+                    self.status |= SR1_EPE;
+                }
+            }
+            Some([0xC2, _]) if self.fail_writes => self.security |= fail_flag,
+            _ => (),
+        }
+        !self.fail_writes
+    }
+
+    fn start_busy(&mut self, duration: Duration) {
+        self.busy_until = Some(Instant::now() + duration);
+        self.write_enabled = false;
+    }
+
+    /// The bytes the FPGA was sent while in SPI slave mode.
+    pub fn sram(&self) -> &[u8] {
+        &self.sram
+    }
+
+    /// The FPGA boots from the flash unless it was put into slave mode,
+    /// in which case it only starts if it was sent a bitstream.
+    fn cdone(&self) -> bool {
+        self.creset_high && (!self.slave_mode || self.sram.windows(4).any(|w| w == PREAMBLE))
+    }
+
+    fn set_lines(&mut self, cs_high: bool, creset_high: bool) {
+        if !self.creset_high && creset_high {
+            self.slave_mode = !cs_high;
+            self.sram.clear();
+        }
+        self.creset_high = creset_high;
+        if self.cs_high && !cs_high {
+            self.cs_high = false;
+            self.begin();
+        } else if !self.cs_high && cs_high {
+            self.cs_high = true;
+            self.end();
+        }
+    }
+
+    fn begin(&mut self) {
+        self.opcode = 0;
+        self.clocked = 0;
+        self.ignored = false;
+        self.address = 0;
+        self.page_data.clear();
+        self.new_status = None;
+        self.new_status2 = None;
+    }
+
+    /// Shift one byte in on MOSI and return the byte shifted out on MISO.
+    fn clock_byte(&mut self, mosi: u8) -> u8 {
+        if self.cs_high {
+            return 0xff;
+        }
+        if self.slave_mode && self.creset_high {
+            self.sram.push(mosi);
+        }
+
+        let pos = self.clocked;
+        self.clocked += 1;
+        if pos == 0 {
+            // Treat the dedicated 4-byte opcodes as their 3-byte equivalents
+            // with a longer address.
+            let (opcode, four_byte) = match mosi {
+                0x12 => (0x02, true),
+                0x13 => (0x03, true),
+                0x0C => (0x0B, true),
+                0x3C => (0x3B, true),
+                0x6C => (0x6B, true),
+                0x21 => (0x20, true),
+                0x5C => (0x52, true),
+                0xDC => (0xD8, true),
+                x => (x, self.four_byte_mode),
+            };
+            self.opcode = opcode;
+            self.address_len = if four_byte { 4 } else { 3 };
+            // A powered-down part only listens for Release Power Down, and
+            // a busy part only answers status reads.
+            self.ignored = (self.powered_down && mosi != 0xAB)
+                || (self.is_busy() && mosi != 0x05)
+                || self.missing_opcodes.contains(&mosi);
+            return 0xff;
+        }
+        if self.ignored {
+            return 0xff;
+        }
+
+        match self.opcode {
+            0x9F /* FC_JEDECID */ => *self.id.get(pos - 1).unwrap_or(&0xff),
+            0x05 /* FC_RSR1 */ => self.status(),
+            0x35 /* FC_RSR2 */ => self.status2,
+            0x2B /* FC_RDSCUR */ => self.security,
+            0x01 /* FC_WSR1 */ | 0xC5 /* FC_WEAR */ => {
+                match pos {
+                    1 => self.new_status = Some(mosi),
+                    2 => self.new_status2 = Some(mosi),
+                    _ => (),
+                }
+                0xff
+            }
+            0x31 /* FC_WSR2 */ => {
+                if pos == 1 {
+                    self.new_status2 = Some(mosi);
+                }
+                0xff
+            }
+            0xC8 /* FC_REAR */ => self.extended_address,
+            0x5A /* FC_RSFDP */ if pos <= 3 => {
+                self.address = (self.address << 8) | mosi as usize;
+                0xff
+            }
+            0x5A /* FC_RSFDP */ if pos == 4 => 0xff, // dummy byte
+            0x5A /* FC_RSFDP */ => *self.sfdp.get(self.address + pos - 5).unwrap_or(&0xff),
+            0x02 /* FC_PP */ | 0x03 /* FC_RD */ | 0x0B /* FC_FR */ | 0x3B /* FC_FRD */
+            | 0x6B /* FC_FRQ */ | 0x20 /* FC_SE */ | 0x52 /* FC_BE32 */ | 0xD8 /* FC_BE64 */
+                if pos <= self.address_len =>
+            {
+                self.address = (self.address << 8) | mosi as usize;
+                if pos == self.address_len {
+                    if self.address_len == 3 {
+                        self.address |= (self.extended_address as usize) << 24;
+                    }
+                    self.address %= self.memory.len();
+                }
+                0xff
+            }
+            0x02 /* FC_PP */ => {
+                self.page_data.push(mosi);
+                0xff
+            }
+            0x0B /* FC_FR */ | 0x3B /* FC_FRD */ | 0x6B /* FC_FRQ */
+                if pos == self.address_len + 1 =>
+            {
+                0xff // dummy byte
+            }
+            // Without QE set, IO2 and IO3 are ~WP and ~HOLD and the data
+            // comes out garbled.
+            0x6B /* FC_FRQ */ if !self.quad_enabled() => 0x00,
+            0x03 /* FC_RD */ | 0x0B /* FC_FR */ | 0x3B /* FC_FRD */ | 0x6B /* FC_FRQ */ => {
+                let value = self.memory[self.address];
+                self.address = (self.address + 1) % self.memory.len();
+                value
+            }
+            _ => 0xff,
+        }
+    }
+
+    /// Chip select was deasserted, so commit whatever the command was.
+    fn end(&mut self) {
+        if self.clocked == 0 || self.ignored {
+            return;
+        }
+
+        match self.opcode {
+            0x06 /* FC_WE */ => self.write_enabled = true,
+            0x04 /* FC_WD */ => self.write_enabled = false,
+            0xAB /* FC_RPD */ => self.powered_down = false,
+            0xB9 /* FC_PD */ => self.powered_down = true,
+            0xB7 /* FC_EN4B */ => self.four_byte_mode = true,
+            0xE9 /* FC_EX4B */ => self.four_byte_mode = false,
+            0xC5 /* FC_WEAR */ if self.write_enabled => {
+                if let Some(value) = self.new_status {
+                    self.extended_address = value;
+                    self.write_enabled = false;
+                }
+            }
+            0x01 /* FC_WSR1 */ | 0x31 /* FC_WSR2 */ if self.write_enabled => {
+                if let Some(status) = self.new_status {
+                    self.status = status & !(SR1_WEL | SR1_BUSY);
+                }
+                if let Some(status2) = self.new_status2 {
+                    self.status2 = status2;
+                }
+                let time = self.timing.write_status;
+                self.start_busy(time);
+            }
+            0x02 /* FC_PP */ if self.write_enabled && self.clocked > 1 + self.address_len => {
+                // Addresses wrap around within the page, and only the last
+                // PAGE_SIZE bytes sent are kept.
+                let page = self.address & !(PAGE_SIZE - 1);
+                let skip = self.page_data.len().saturating_sub(PAGE_SIZE);
+                if self.write_result(SCUR_P_FAIL) {
+                    for (i, byte) in self.page_data[skip..].iter().enumerate() {
+                        let offset = (self.address + skip + i) % PAGE_SIZE;
+                        self.memory[page + offset] &= byte;
+                    }
+                }
+                let time = self.timing.page_program;
+                self.start_busy(time);
+            }
+            0x20 /* FC_SE */ | 0x52 /* FC_BE32 */ | 0xD8 /* FC_BE64 */
+                if self.write_enabled && self.clocked > self.address_len =>
+            {
+                let size = match self.opcode {
+                    0x20 => 4 * 1024,
+                    0x52 => 32 * 1024,
+                    _ => 64 * 1024,
+                };
+                let start = self.address & !(size - 1);
+                let end = (start + size).min(self.memory.len());
+                if self.write_result(SCUR_E_FAIL) {
+                    for byte in &mut self.memory[start..end] {
+                        *byte = 0xff;
+                    }
+                }
+                let time = self.timing.block_erase;
+                self.start_busy(time);
+            }
+            0xC7 /* FC_CE */ | 0x60 /* FC_CE */ if self.write_enabled => {
+                if self.write_result(SCUR_E_FAIL) {
+                    for byte in &mut self.memory {
+                        *byte = 0xff;
+                    }
+                }
+                let time = self.timing.chip_erase;
+                self.start_busy(time);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Transport for SimFlash {
+    fn set_gpio(&mut self, value: u8, direction: u8) -> Result<(), Error> {
+        let cs_high = (direction & CS_PIN == 0) || (value & CS_PIN != 0);
+        let creset_high = (direction & CRESET_PIN == 0) || (value & CRESET_PIN != 0);
+        self.set_lines(cs_high, creset_high);
+        Ok(())
+    }
+
+    fn read_gpio(&mut self) -> Result<u8, Error> {
+        let mut value = CS_PIN | CRESET_PIN;
+        if self.cdone() {
+            value |= CDONE_PIN;
+        }
+        Ok(value)
+    }
+
+    fn xfer_spi(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        for byte in data.iter_mut() {
+            *byte = self.clock_byte(*byte);
+        }
+        Ok(())
+    }
+
+    fn send_spi(&mut self, data: &[u8]) -> Result<(), Error> {
+        for byte in data {
+            self.clock_byte(*byte);
+        }
+        Ok(())
+    }
+
+    /// Partial bytes are only ever sent as part of the reset sequence, so
+    /// they abort whatever command was in progress.
+    fn xfer_spi_bits(&mut self, _data: u8, _bits: usize) -> Result<u8, Error> {
+        self.ignored = true;
+        Ok(0xff)
+    }
+
+    /// The simulator doesn't model individual data lines, so a wide read
+    /// is just a read.
+    fn read_lanes(&self) -> usize {
+        4
+    }
+
+    fn read_wide(&mut self, _lanes: usize, data: &mut [u8]) -> Result<(), Error> {
+        for byte in data.iter_mut() {
+            *byte = self.clock_byte(0xff);
+        }
+        Ok(())
+    }
+}
+
+/// Lets the simulated flash sit behind the MPSSE emulator, so that the
+/// whole stack down to the bytes sent over USB can be tested.
+impl Peripheral for SimFlash {
+    fn set_pins(&mut self, pins: u16) {
+        let pins = pins as u8;
+        self.set_lines(pins & CS_PIN != 0, pins & CRESET_PIN != 0);
+    }
+
+    fn drive_pins(&mut self) -> (u16, u16) {
+        let cdone = if self.cdone() { CDONE_PIN } else { 0 };
+        if !self.ready_line {
+            return (CDONE_PIN as u16, cdone as u16);
+        }
+        let ready = if self.is_busy() { 0 } else { READY_PIN };
+        ((CDONE_PIN | READY_PIN) as u16, (cdone | ready) as u16)
+    }
+
+    fn clock(&mut self, mosi: u8, bits: u8) -> u8 {
+        if bits == 8 {
+            self.clock_byte(mosi)
+        } else {
+            self.xfer_spi_bits(mosi, bits as usize).unwrap_or(0xff)
+        }
+    }
+}
+
+/// A simulated 1 MiB part, started up the way `main` does.
+pub fn sim_flash() -> Flash<SimFlash> {
+    start(Flash::new(SimFlash::new(1024 * 1024)))
+}
+
+/// Take the part out of reset and power-down.
+pub fn start<T: Transport>(mut flash: Flash<T>) -> Flash<T> {
+    flash.release_reset().unwrap();
+    flash.chip_deselect().unwrap();
+    flash.reset().unwrap();
+    flash.power_up().unwrap();
+    flash
+}
+
+/// The same erase/program sequence `main` performs.
+pub fn program<T: Transport>(flash: &mut Flash<T>, offset: usize, bitstream: &[u8]) {
+    flash.erase_range(offset, bitstream.len(), false).unwrap();
+    flash.program(offset, bitstream).unwrap();
+}
+
+pub fn verify<T: Transport>(flash: &mut Flash<T>, offset: usize, bitstream: &[u8]) -> bool {
+    flash.verify(offset, bitstream).unwrap().is_ok()
+}
+
+pub fn test_pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::{EraseType, Operation};
+
+    #[test]
+    fn program_and_verify() {
+        let mut flash = sim_flash();
+        flash.read_id().unwrap();
+        flash.transport.memory_mut()[0x20000] = 0x12;
+
+        let bitstream = test_pattern(3000);
+        program(&mut flash, 0x10100, &bitstream);
+        assert!(verify(&mut flash, 0x10100, &bitstream));
+
+        // Everything else in the erased block is blank, and the next block
+        // was left alone.
+        assert!(flash.transport.memory()[0x10000..0x10100]
+            .iter()
+            .all(|b| *b == 0xff));
+        assert_eq!(flash.transport.memory()[0x20000], 0x12);
+    }
+
+    #[test]
+    fn programming_only_clears_bits() {
+        let mut flash = sim_flash();
+        flash.write_enable().unwrap();
+        flash.prog(0, &[0xF0, 0x0F]).unwrap();
+        flash.wait(Operation::PageProgram).unwrap();
+        flash.write_enable().unwrap();
+        flash.prog(0, &[0x3C, 0x3C]).unwrap();
+        flash.wait(Operation::PageProgram).unwrap();
+        assert_eq!(&flash.transport.memory()[0..2], &[0x30, 0x0C]);
+    }
+
+    #[test]
+    fn page_program_wraps_within_page() {
+        let mut flash = sim_flash();
+        flash.write_enable().unwrap();
+        flash.prog(0x1FE, &[1, 2, 3, 4]).unwrap();
+        flash.wait(Operation::PageProgram).unwrap();
+        assert_eq!(&flash.transport.memory()[0x1FE..0x200], &[1, 2]);
+        assert_eq!(&flash.transport.memory()[0x100..0x102], &[3, 4]);
+        assert_eq!(flash.transport.memory()[0x200], 0xff);
+    }
+
+    #[test]
+    fn writes_require_write_enable() {
+        let mut flash = sim_flash();
+        flash.prog(0, &[0x00]).unwrap();
+        flash.wait(Operation::PageProgram).unwrap();
+        assert_eq!(flash.transport.memory()[0], 0xff);
+
+        flash.write_enable().unwrap();
+        assert_eq!(flash.read_status().unwrap() & SR1_WEL, SR1_WEL);
+        flash.prog(0, &[0x00]).unwrap();
+        assert_eq!(flash.read_status().unwrap() & SR1_WEL, 0);
+        flash.wait(Operation::PageProgram).unwrap();
+        assert_eq!(flash.transport.memory()[0], 0x00);
+    }
+
+    #[test]
+    fn erase_sets_busy() {
+        let mut flash = sim_flash();
+        flash.transport.set_timing(SimTiming {
+            block_erase: Duration::from_millis(50),
+            ..SimTiming::default()
+        });
+        flash.transport.memory_mut()[0x100] = 0;
+
+        flash.write_enable().unwrap();
+        flash.sector_erase(EraseType::Kb64, 0).unwrap();
+        assert_eq!(flash.read_status().unwrap() & SR1_BUSY, SR1_BUSY);
+
+        // Commands other than status reads are ignored while busy.
+        flash.write_enable().unwrap();
+        assert_eq!(flash.read_status().unwrap() & SR1_WEL, 0);
+
+        flash.wait(Operation::Erase(EraseType::Kb64)).unwrap();
+        assert_eq!(flash.read_status().unwrap() & SR1_BUSY, 0);
+        assert_eq!(flash.transport.memory()[0x100], 0xff);
+    }
+
+    #[test]
+    fn bulk_erase_clears_everything() {
+        let mut flash = sim_flash();
+        for byte in flash.transport.memory_mut() {
+            *byte = 0;
+        }
+        flash.write_enable().unwrap();
+        flash.bulk_erase().unwrap();
+        flash.wait(Operation::ChipErase).unwrap();
+        assert!(flash.transport.memory().iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn power_down_ignores_commands() {
+        let mut flash = sim_flash();
+        flash.power_down().unwrap();
+        assert!(flash.transport.is_powered_down());
+
+        let mut data = [0xff; 4];
+        flash.read(0, &mut data).unwrap();
+        flash.transport.memory_mut()[0] = 0x55;
+        flash.power_up().unwrap();
+        flash.read(0, &mut data).unwrap();
+        assert_eq!(data[0], 0x55);
+    }
+
+    #[test]
+    fn disable_protection() {
+        let mut flash = sim_flash();
+        flash.write_enable().unwrap();
+        flash.disable_protection().unwrap();
+        assert_eq!(flash.read_status().unwrap(), 0x00);
+    }
+
+    #[test]
+    fn cdone_follows_reset() {
+        let mut flash = sim_flash();
+        flash.chip_select().unwrap();
+        assert!(!flash.cdone().unwrap());
+        flash.release_reset().unwrap();
+        assert!(flash.cdone().unwrap());
+    }
+}
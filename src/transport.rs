@@ -0,0 +1,266 @@
+#[cfg(test)]
+use ftdi_vcp_rs::emulator::{Emulator, Peripheral};
+#[cfg(test)]
+use ftdi_vcp_rs::mpsse;
+use ftdi_vcp_rs::mpsse::{Op, Queue};
+use ftdi_vcp_rs::{Error, VCP};
+
+/// An SPI master capable of driving a flash chip.  `Flash` is written in
+/// terms of this trait so it can run on top of any adapter, not just an
+/// FTDI cable.
+pub trait Transport {
+    /// Set the level and direction of the GPIO lines used for chip select
+    /// and FPGA reset.  A `1` bit in `direction` makes that pin an output.
+    fn set_gpio(&mut self, value: u8, direction: u8) -> Result<(), Error>;
+
+    /// Read back the current level of the GPIO lines.
+    fn read_gpio(&mut self) -> Result<u8, Error>;
+
+    /// Like `set_gpio`, for the second bank of GPIO lines (ACBUS on an
+    /// FTDI part), which some boards use for reset and CDONE.
+    fn set_gpio_high(&mut self, _value: u8, _direction: u8) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+
+    fn read_gpio_high(&mut self) -> Result<u8, Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// Clock `data` out, replacing it with the bytes that were clocked in.
+    fn xfer_spi(&mut self, data: &mut [u8]) -> Result<(), Error>;
+
+    /// Clock `data` out, discarding whatever comes back.
+    fn send_spi(&mut self, data: &[u8]) -> Result<(), Error>;
+
+    /// Clock out the top `bits` bits of `data`, returning the bits clocked in.
+    fn xfer_spi_bits(&mut self, data: u8, bits: usize) -> Result<u8, Error>;
+
+    /// Run everything in `queue`, returning the bytes it read.  Adapters
+    /// that can should send the whole queue at once; by default each
+    /// operation is done in turn, and waiting on GPIOL1 isn't supported.
+    fn submit(&mut self, queue: &Queue) -> Result<Vec<u8>, Error> {
+        let mut reply = Vec::with_capacity(queue.reply_len());
+        for op in queue.ops() {
+            match op {
+                Op::SetGpioLow { value, direction } => self.set_gpio(*value, *direction)?,
+                Op::SetGpioHigh { value, direction } => self.set_gpio_high(*value, *direction)?,
+                Op::ReadGpioLow => reply.push(self.read_gpio()?),
+                Op::ReadGpioHigh => reply.push(self.read_gpio_high()?),
+                Op::Send(data) => self.send_spi(data)?,
+                Op::Xfer(data) => {
+                    let start = reply.len();
+                    reply.extend_from_slice(data);
+                    self.xfer_spi(&mut reply[start..])?;
+                }
+                Op::XferBits { data, bits } => reply.push(self.xfer_spi_bits(*data, *bits)?),
+                Op::WaitGpiol1 { .. } => return Err(Error::NotSupported),
+            }
+        }
+        Ok(reply)
+    }
+
+    /// The number of data lines reads can use: 1, 2 (dual) or 4 (quad).
+    /// An MPSSE engine only has the one.
+    fn read_lanes(&self) -> usize {
+        1
+    }
+
+    /// Clock `data.len()` bytes in over `lanes` data lines.
+    fn read_wide(&mut self, lanes: usize, data: &mut [u8]) -> Result<(), Error> {
+        if lanes != 1 {
+            return Err(Error::NotSupported);
+        }
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+        self.xfer_spi(data)
+    }
+}
+
+impl Transport for VCP {
+    fn set_gpio(&mut self, value: u8, direction: u8) -> Result<(), Error> {
+        VCP::set_gpio(self, value, direction)
+    }
+
+    fn read_gpio(&mut self) -> Result<u8, Error> {
+        self.readb_low()
+    }
+
+    fn set_gpio_high(&mut self, value: u8, direction: u8) -> Result<(), Error> {
+        VCP::set_gpio_high(self, value, direction)
+    }
+
+    fn read_gpio_high(&mut self) -> Result<u8, Error> {
+        self.readb_high()
+    }
+
+    fn xfer_spi(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        VCP::xfer_spi(self, data)
+    }
+
+    fn send_spi(&mut self, data: &[u8]) -> Result<(), Error> {
+        VCP::send_spi(self, data)
+    }
+
+    fn xfer_spi_bits(&mut self, data: u8, bits: usize) -> Result<u8, Error> {
+        VCP::xfer_spi_bits(self, data, bits)
+    }
+
+    fn read_wide(&mut self, lanes: usize, data: &mut [u8]) -> Result<(), Error> {
+        if lanes != 1 {
+            return Err(Error::NotSupported);
+        }
+        VCP::read_spi(self, data)
+    }
+
+    fn submit(&mut self, queue: &Queue) -> Result<Vec<u8>, Error> {
+        VCP::submit(self, queue)
+    }
+}
+
+/// Drive an emulated MPSSE engine with the exact byte stream a `VCP` would
+/// send to the hardware.
+#[cfg(test)]
+impl<P: Peripheral> Transport for Emulator<P> {
+    fn set_gpio(&mut self, value: u8, direction: u8) -> Result<(), Error> {
+        mpsse::set_gpio_low(self, value, direction)
+    }
+
+    fn read_gpio(&mut self) -> Result<u8, Error> {
+        mpsse::read_gpio_low(self)
+    }
+
+    fn set_gpio_high(&mut self, value: u8, direction: u8) -> Result<(), Error> {
+        mpsse::set_gpio_high(self, value, direction)
+    }
+
+    fn read_gpio_high(&mut self) -> Result<u8, Error> {
+        mpsse::read_gpio_high(self)
+    }
+
+    fn xfer_spi(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        mpsse::xfer_spi(self, data)
+    }
+
+    fn send_spi(&mut self, data: &[u8]) -> Result<(), Error> {
+        mpsse::send_spi(self, data)
+    }
+
+    fn xfer_spi_bits(&mut self, data: u8, bits: usize) -> Result<u8, Error> {
+        mpsse::xfer_spi_bits(self, data, bits)
+    }
+
+    fn read_wide(&mut self, lanes: usize, data: &mut [u8]) -> Result<(), Error> {
+        if lanes != 1 {
+            return Err(Error::NotSupported);
+        }
+        mpsse::read_spi(self, data)
+    }
+
+    fn submit(&mut self, queue: &Queue) -> Result<Vec<u8>, Error> {
+        mpsse::submit(self, queue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{test_pattern, SimFlash};
+    use ftdi_vcp_rs::emulator::Event;
+
+    /// An adapter with only the methods every transport must have, to
+    /// exercise the trait's defaults.
+    struct Minimal(Emulator<SimFlash>);
+
+    impl Transport for Minimal {
+        fn set_gpio(&mut self, value: u8, direction: u8) -> Result<(), Error> {
+            self.0.set_gpio(value, direction)
+        }
+
+        fn read_gpio(&mut self) -> Result<u8, Error> {
+            self.0.read_gpio()
+        }
+
+        fn xfer_spi(&mut self, data: &mut [u8]) -> Result<(), Error> {
+            self.0.xfer_spi(data)
+        }
+
+        fn send_spi(&mut self, data: &[u8]) -> Result<(), Error> {
+            self.0.send_spi(data)
+        }
+
+        fn xfer_spi_bits(&mut self, data: u8, bits: usize) -> Result<u8, Error> {
+            self.0.xfer_spi_bits(data, bits)
+        }
+    }
+
+    /* ADBUS4 is CS, and ADBUS7 CRESET which is kept high */
+    const SELECTED: u8 = 0x80;
+    const DESELECTED: u8 = 0x90;
+    const DIRECTION: u8 = 0x93;
+
+    fn flash() -> SimFlash {
+        let mut sim = SimFlash::new(1024 * 1024);
+        sim.memory_mut()[0x100..0x400].copy_from_slice(&test_pattern(0x300));
+        sim
+    }
+
+    /// Everything but the flushes, which the default methods send more of
+    fn shifts_and_gpio(events: Vec<Event>) -> Vec<Event> {
+        events.into_iter().filter(|e| *e != Event::Flush).collect()
+    }
+
+    #[test]
+    fn default_submit_matches_the_batched_one() {
+        let mut queue = Queue::new();
+        queue.set_gpio_low(SELECTED, DIRECTION);
+        let id = queue.xfer_spi(&[0x9F, 0, 0, 0]);
+        queue.set_gpio_low(DESELECTED, DIRECTION);
+        queue.set_gpio_low(SELECTED, DIRECTION);
+        queue.send_spi(&[0x03, 0x00, 0x01, 0x00]);
+        let data = queue.xfer_spi(&[0; 0x10]);
+        let bits = queue.xfer_spi_bits(0x00, 3);
+        queue.set_gpio_low(DESELECTED, DIRECTION);
+        let gpio = queue.read_gpio_low();
+
+        let mut minimal = Minimal(Emulator::new(flash()));
+        let mut batched = Emulator::new(flash());
+        let reply = minimal.submit(&queue).unwrap();
+        assert_eq!(reply, batched.submit(&queue).unwrap());
+        assert_eq!(&reply[id + 1..id + 4], &[0xEF, 0x40, 0x18]);
+        assert_eq!(&reply[data..data + 0x10], &test_pattern(0x300)[..0x10]);
+        assert_eq!(reply[bits], 0x07);
+        assert_eq!(reply[gpio] & DIRECTION, DESELECTED);
+        assert_eq!(
+            shifts_and_gpio(minimal.0.take_events()),
+            shifts_and_gpio(batched.take_events())
+        );
+
+        // Waiting on GPIOL1 needs an adapter that can do it
+        let mut queue = Queue::new();
+        queue.wait_gpiol1(true);
+        assert_eq!(minimal.submit(&queue), Err(Error::NotSupported));
+    }
+
+    #[test]
+    fn defaults_only_cover_one_lane() {
+        let mut minimal = Minimal(Emulator::new(flash()));
+        assert_eq!(minimal.read_lanes(), 1);
+        assert_eq!(minimal.set_gpio_high(0, 0), Err(Error::NotSupported));
+        assert_eq!(minimal.read_gpio_high(), Err(Error::NotSupported));
+
+        let mut data = [0xAA; 0x300];
+        minimal.set_gpio(SELECTED, DIRECTION).unwrap();
+        minimal.send_spi(&[0x03, 0x00, 0x01, 0x00]).unwrap();
+        assert_eq!(minimal.read_wide(2, &mut data), Err(Error::NotSupported));
+        minimal.read_wide(1, &mut data).unwrap();
+        minimal.set_gpio(DESELECTED, DIRECTION).unwrap();
+        assert_eq!(&data[..], &test_pattern(0x300)[..]);
+
+        // Reads clock out zeros, as the MPSSE engine does
+        assert!(minimal.0.events().iter().all(|e| match e {
+            Event::Shift { mosi, bits, .. } if *bits == 0x300 * 8 => mosi.iter().all(|b| *b == 0),
+            _ => true,
+        }));
+    }
+}
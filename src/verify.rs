@@ -0,0 +1,176 @@
+//! Comparing what was read back from the flash against the data that was
+//! meant to be there.
+
+use std::fmt;
+use std::io::{self, Write};
+
+/// How many mismatching ranges the summary shows as a hexdump
+const SHOWN_MISMATCHES: usize = 8;
+
+/// How many bytes of each range the summary shows
+const SHOWN_BYTES: usize = 64;
+
+/// A run of consecutive addresses where the flash doesn't hold the
+/// expected data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mismatch {
+    pub address: usize,
+    pub expected: Vec<u8>,
+    pub actual: Vec<u8>,
+}
+
+impl Mismatch {
+    pub fn bad_bytes(&self) -> usize {
+        self.expected.len()
+    }
+}
+
+/// Every difference found while verifying a range of the flash.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl VerifyReport {
+    /// Compare `actual`, read back from `address`, with `expected`.  Chunks
+    /// are expected in address order; a run of bad bytes that continues
+    /// from the previous chunk extends the same `Mismatch`.
+    pub fn compare(&mut self, address: usize, expected: &[u8], actual: &[u8]) {
+        for (offset, (e, a)) in expected.iter().zip(actual).enumerate() {
+            if e == a {
+                continue;
+            }
+            let addr = address + offset;
+            match self.mismatches.last_mut() {
+                Some(last) if last.address + last.bad_bytes() == addr => {
+                    last.expected.push(*e);
+                    last.actual.push(*a);
+                }
+                _ => self.mismatches.push(Mismatch {
+                    address: addr,
+                    expected: vec![*e],
+                    actual: vec![*a],
+                }),
+            }
+        }
+        self.checked += expected.len().min(actual.len());
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    pub fn bad_bytes(&self) -> usize {
+        self.mismatches.iter().map(Mismatch::bad_bytes).sum()
+    }
+
+    /// Write every differing byte, one per line, as
+    /// `<address> <expected> <actual>`.
+    pub fn write_log<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "# address  expected actual")?;
+        for mismatch in &self.mismatches {
+            for (idx, (e, a)) in mismatch.expected.iter().zip(&mismatch.actual).enumerate() {
+                writeln!(
+                    out,
+                    "0x{:06X}  0x{:02X}     0x{:02X}",
+                    mismatch.address + idx,
+                    e,
+                    a
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn hex_row(f: &mut fmt::Formatter<'_>, label: &str, bytes: &[u8]) -> fmt::Result {
+    write!(f, "  {:<6}", label)?;
+    for byte in bytes {
+        write!(f, " {:02x}", byte)?;
+    }
+    writeln!(f)
+}
+
+/// A summary of the differences, with the first few shown as a hexdump
+/// of the expected bytes above the ones that were read.
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return writeln!(f, "{} bytes match", self.checked);
+        }
+        writeln!(
+            f,
+            "{} of {} bytes differ, in {} ranges",
+            self.bad_bytes(),
+            self.checked,
+            self.mismatches.len()
+        )?;
+
+        for mismatch in self.mismatches.iter().take(SHOWN_MISMATCHES) {
+            writeln!(
+                f,
+                "0x{:06X}: {} bytes differ",
+                mismatch.address,
+                mismatch.bad_bytes()
+            )?;
+            let shown = mismatch.bad_bytes().min(SHOWN_BYTES);
+            for start in (0..shown).step_by(16) {
+                let end = (start + 16).min(shown);
+                writeln!(f, "  0x{:06X}", mismatch.address + start)?;
+                hex_row(f, "file", &mismatch.expected[start..end])?;
+                hex_row(f, "flash", &mismatch.actual[start..end])?;
+            }
+            if shown < mismatch.bad_bytes() {
+                writeln!(f, "  ...")?;
+            }
+        }
+        if self.mismatches.len() > SHOWN_MISMATCHES {
+            writeln!(
+                f,
+                "... and {} more ranges",
+                self.mismatches.len() - SHOWN_MISMATCHES
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_span_chunks() {
+        let expected = [0u8; 8];
+        let mut report = VerifyReport::default();
+        report.compare(0x100, &expected, &[0, 0, 1, 1, 0, 0, 0, 1]);
+        report.compare(0x108, &expected, &[1, 1, 0, 0, 0, 0, 0, 0]);
+        report.compare(0x110, &expected, &expected);
+
+        assert!(!report.is_ok());
+        assert_eq!(report.checked, 24);
+        assert_eq!(report.bad_bytes(), 5);
+        assert_eq!(
+            report.mismatches,
+            vec![
+                Mismatch {
+                    address: 0x102,
+                    expected: vec![0, 0],
+                    actual: vec![1, 1],
+                },
+                Mismatch {
+                    address: 0x107,
+                    expected: vec![0, 0, 0],
+                    actual: vec![1, 1, 1],
+                },
+            ]
+        );
+
+        let mut log = vec![];
+        report.write_log(&mut log).unwrap();
+        let log = String::from_utf8(log).unwrap();
+        assert_eq!(log.lines().count(), 6);
+        assert_eq!(log.lines().nth(1), Some("0x000102  0x00     0x01"));
+    }
+}